@@ -0,0 +1,168 @@
+use crate::error::{Error, Result};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+///
+/// Aborts the clone or fetch it is given to once cancelled, from any thread.
+/// The operation fails with `Error::Cancelled`, and a failed clone
+/// removes what it created.
+///
+/// Cancelling kills the git-upload-pack or ssh process being read from and
+/// shuts the git:// connection down. Over http a blocked read is only
+/// interrupted by the timeout of `HttpOptions`, the cancellation being
+/// noticed between the chunks received.
+///
+/// ```
+/// use rs_git_lib::{CancellationToken, CloneOptions};
+/// let token = CancellationToken::new();
+/// let options = CloneOptions::new().cancellation(token.clone());
+/// // From a timer thread enforcing a time limit
+/// std::thread::spawn(move || token.cancel());
+/// ```
+///
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    /// interrupt the blocking I/O of the connections in use
+    aborts: Mutex<Vec<(usize, Abort)>>,
+    next_abort: AtomicUsize,
+}
+
+type Abort = Box<dyn Fn() + Send>;
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        for (_, abort) in self.inner.aborts.lock().unwrap().iter() {
+            abort();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    ///
+    /// Runs `abort` when the token is cancelled, until the returned guard is
+    /// dropped, to interrupt a read or write blocked on the remote. Runs it
+    /// right away when the token is already cancelled.
+    ///
+    pub(crate) fn on_cancel<F>(&self, abort: F) -> AbortOnCancel
+    where
+        F: Fn() + Send + 'static,
+    {
+        let id = self.inner.next_abort.fetch_add(1, Ordering::SeqCst);
+        self.inner
+            .aborts
+            .lock()
+            .unwrap()
+            .push((id, Box::new(abort)));
+        if self.is_cancelled() {
+            self.abort(id);
+        }
+        AbortOnCancel {
+            token: self.clone(),
+            id,
+        }
+    }
+
+    fn abort(&self, id: usize) {
+        let aborts = self.inner.aborts.lock().unwrap();
+        if let Some((_, abort)) = aborts.iter().find(|(i, _)| *i == id) {
+            abort();
+        }
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+///
+/// Unregisters the abort it was returned for when dropped.
+///
+pub(crate) struct AbortOnCancel {
+    token: CancellationToken,
+    id: usize,
+}
+
+impl Drop for AbortOnCancel {
+    fn drop(&mut self) {
+        let mut aborts = self.token.inner.aborts.lock().unwrap();
+        aborts.retain(|(id, _)| *id != self.id);
+    }
+}
+
+impl fmt::Debug for AbortOnCancel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AbortOnCancel")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+///
+/// Fails once the token, if any, is cancelled.
+///
+pub(crate) fn check(token: &Option<CancellationToken>) -> Result<()> {
+    match token {
+        Some(token) if token.is_cancelled() => Err(Error::Cancelled),
+        _ => Ok(()),
+    }
+}
+
+///
+/// Runs `abort` when the token, if any, is cancelled, as long as the
+/// returned guard is kept.
+///
+pub(crate) fn on_cancel<F>(token: &Option<CancellationToken>, abort: F) -> Option<AbortOnCancel>
+where
+    F: Fn() + Send + 'static,
+{
+    token.as_ref().map(|token| token.on_cancel(abort))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn cancelling_runs_the_registered_aborts() {
+        let token = CancellationToken::new();
+        let aborted = Arc::new(AtomicUsize::new(0));
+        let counter = aborted.clone();
+        let guard = token.on_cancel(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let counter = aborted.clone();
+        drop(token.on_cancel(move || {
+            counter.fetch_add(10, Ordering::SeqCst);
+        }));
+        token.cancel();
+        assert_eq!(aborted.load(Ordering::SeqCst), 1);
+        drop(guard);
+
+        // Registered once cancelled, the abort runs right away
+        let counter = aborted.clone();
+        let _guard = token.on_cancel(move || {
+            counter.fetch_add(100, Ordering::SeqCst);
+        });
+        assert_eq!(aborted.load(Ordering::SeqCst), 101);
+        assert!(check(&Some(token)).is_err());
+    }
+}
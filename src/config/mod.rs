@@ -0,0 +1,195 @@
+use std::fs;
+use std::io::{ErrorKind, Result as IOResult};
+use std::path::PathBuf;
+
+///
+/// A git config file, see https://git-scm.com/docs/git-config#_configuration_file
+///
+/// Sections keep the order in which they were read so that writing the
+/// config back doesn't shuffle the file.
+///
+#[derive(Debug)]
+pub struct Config {
+    path: PathBuf,
+    sections: Vec<Section>,
+}
+
+#[derive(Debug)]
+struct Section {
+    name: String,
+    subsection: Option<String>,
+    entries: Vec<(String, String)>,
+}
+
+impl Section {
+    fn is(&self, name: &str, subsection: Option<&str>) -> bool {
+        self.name.eq_ignore_ascii_case(name) && self.subsection.as_deref() == subsection
+    }
+}
+
+impl Config {
+    ///
+    /// Reads the config of the given git dir, a missing file is an empty config.
+    ///
+    pub fn open(git_dir: &str) -> IOResult<Self> {
+        let mut path = PathBuf::new();
+        path.push(git_dir);
+        path.push("config");
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Config {
+            path,
+            sections: parse_config(&contents),
+        })
+    }
+
+    pub fn get(&self, section: &str, subsection: Option<&str>, key: &str) -> Option<&str> {
+        self.get_all(section, subsection, key).pop()
+    }
+
+    ///
+    /// Every value of a multi-valued key, like the fetch refspecs of a
+    /// remote, in the order they were read.
+    ///
+    pub fn get_all(&self, section: &str, subsection: Option<&str>, key: &str) -> Vec<&str> {
+        self.sections
+            .iter()
+            .filter(|s| s.is(section, subsection))
+            .flat_map(|s| s.entries.iter())
+            .filter(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| &v[..])
+            .collect()
+    }
+
+    pub fn set(&mut self, section: &str, subsection: Option<&str>, key: &str, value: &str) {
+        let index = match self.sections.iter().position(|s| s.is(section, subsection)) {
+            Some(index) => index,
+            None => {
+                self.sections.push(Section {
+                    name: section.to_owned(),
+                    subsection: subsection.map(|s| s.to_owned()),
+                    entries: vec![],
+                });
+                self.sections.len() - 1
+            }
+        };
+        let entries = &mut self.sections[index].entries;
+        match entries
+            .iter_mut()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+        {
+            Some(entry) => entry.1 = value.to_owned(),
+            None => entries.push((key.to_owned(), value.to_owned())),
+        }
+    }
+
+    pub fn write(&self) -> IOResult<()> {
+        fs::write(&self.path, self.encode())
+    }
+
+    fn encode(&self) -> String {
+        let mut encoded = String::new();
+        for section in &self.sections {
+            match section.subsection {
+                Some(ref sub) => encoded.push_str(&format!("[{} \"{}\"]\n", section.name, sub)),
+                None => encoded.push_str(&format!("[{}]\n", section.name)),
+            }
+            for (key, value) in &section.entries {
+                encoded.push_str(&format!("\t{} = {}\n", key, value));
+            }
+        }
+        encoded
+    }
+}
+
+fn parse_config(contents: &str) -> Vec<Section> {
+    let mut sections: Vec<Section> = vec![];
+    for line in contents.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            let header = &line[1..line.len() - 1];
+            let mut split = header.splitn(2, ' ');
+            let name = split.next().unwrap_or("").to_owned();
+            let subsection = split.next().map(|s| s.trim().trim_matches('"').to_owned());
+            sections.push(Section {
+                name,
+                subsection,
+                entries: vec![],
+            });
+        } else if let Some(section) = sections.last_mut() {
+            let mut split = line.splitn(2, '=');
+            let key = split.next().unwrap_or("").trim().to_owned();
+            // A key without value is a boolean set to true
+            let value = split.next().map_or("true", |v| v.trim()).to_owned();
+            section.entries.push((key, value));
+        }
+    }
+    sections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let contents = "[core]\n\
+            \trepositoryformatversion = 0\n\
+            \tbare = false\n\
+            # a comment\n\
+            [remote \"origin\"]\n\
+            \turl = https://github.com/lnds/rs-git-lib.git\n\
+            \tfetch = +refs/heads/*:refs/remotes/origin/*\n\
+            \tfetch = refs/notes/*:refs/notes/*\n\
+            [branch \"master\"]\n\
+            \tremote = origin\n";
+        let config = Config {
+            path: PathBuf::new(),
+            sections: parse_config(contents),
+        };
+        assert_eq!(config.get("core", None, "bare"), Some("false"));
+        assert_eq!(
+            config.get("remote", Some("origin"), "url"),
+            Some("https://github.com/lnds/rs-git-lib.git")
+        );
+        assert_eq!(
+            config.get("branch", Some("master"), "remote"),
+            Some("origin")
+        );
+        assert_eq!(config.get("branch", Some("other"), "remote"), None);
+        assert_eq!(
+            config.get_all("remote", Some("origin"), "fetch"),
+            vec![
+                "+refs/heads/*:refs/remotes/origin/*",
+                "refs/notes/*:refs/notes/*"
+            ]
+        );
+        assert_eq!(
+            config.get("remote", Some("origin"), "fetch"),
+            Some("refs/notes/*:refs/notes/*")
+        );
+    }
+
+    #[test]
+    fn set_and_encode_should_round_trip() {
+        let mut config = Config {
+            path: PathBuf::new(),
+            sections: vec![],
+        };
+        config.set("core", None, "bare", "false");
+        config.set("remote", Some("origin"), "url", "/tmp/repo");
+        config.set("core", None, "bare", "true");
+        let encoded = config.encode();
+        assert_eq!(
+            encoded,
+            "[core]\n\tbare = true\n[remote \"origin\"]\n\turl = /tmp/repo\n"
+        );
+        let sections = parse_config(&encoded);
+        assert_eq!(sections.len(), 2);
+    }
+}
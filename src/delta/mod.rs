@@ -1,7 +1,8 @@
+use crate::error::{Error, Result};
+use crate::packfile::DELTA_BASE_CACHE_SIZE;
 use byteorder::ReadBytesExt;
-use std::io::Result as IOResult;
 
-pub fn patch(source: &[u8], delta: &[u8]) -> IOResult<Vec<u8>> {
+pub fn patch(source: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
     let mut patcher = DeltaPatcher::new(source, delta)?;
     patcher.run_to_end()
 }
@@ -10,28 +11,29 @@ pub fn patch(source: &[u8], delta: &[u8]) -> IOResult<Vec<u8>> {
 struct DeltaHeader {
     source_len: usize,
     target_len: usize,
-    get_offset: usize,
 }
 
 impl DeltaHeader {
-    fn new(delta: &mut &[u8]) -> IOResult<DeltaHeader> {
-        let (source, bytes_s) = DeltaHeader::decode_size(delta)?;
-        let (target, bytes_t) = DeltaHeader::decode_size(delta)?;
+    fn new(delta: &mut &[u8]) -> Result<DeltaHeader> {
+        let (source, _) = DeltaHeader::decode_size(delta)?;
+        let (target, _) = DeltaHeader::decode_size(delta)?;
 
         Ok(DeltaHeader {
             source_len: source,
             target_len: target,
-            get_offset: bytes_s + bytes_t,
         })
     }
 
-    fn decode_size(delta: &mut &[u8]) -> IOResult<(usize, usize)> {
+    fn decode_size(delta: &mut &[u8]) -> Result<(usize, usize)> {
         let mut byte = 0x80;
         let mut size = 0;
         let mut count = 0;
         while (byte & 0x80) > 0 {
-            byte = delta.read_u8()? as usize;
-            size += (byte & 127) << (7 * count);
+            byte = delta.read_u8().map_err(|_| bad_delta("truncated header"))? as usize;
+            let bits = (byte & 127)
+                .checked_shl(7 * count as u32)
+                .ok_or_else(|| bad_delta("size too large"))?;
+            size |= bits;
             count += 1;
         }
         Ok((size, count))
@@ -51,9 +53,11 @@ struct DeltaPatcher<'a> {
 }
 
 impl<'a> DeltaPatcher<'a> {
-    pub fn new(source: &'a [u8], mut delta: &'a [u8]) -> IOResult<Self> {
+    pub fn new(source: &'a [u8], mut delta: &'a [u8]) -> Result<Self> {
         let header = DeltaHeader::new(&mut delta)?;
-        assert_eq!(header.source_len, source.len());
+        if header.source_len != source.len() {
+            return Err(bad_delta("the base size doesn't match"));
+        }
 
         Ok(DeltaPatcher {
             source,
@@ -62,61 +66,126 @@ impl<'a> DeltaPatcher<'a> {
         })
     }
 
-    fn run_to_end(&mut self) -> IOResult<Vec<u8>> {
+    fn run_to_end(&mut self) -> Result<Vec<u8>> {
         let target_len = self.target_len;
-        let mut buf = Vec::with_capacity(target_len);
+        // The size comes from the delta, it isn't trusted to allocate
+        let mut buf = Vec::with_capacity(target_len.min(DELTA_BASE_CACHE_SIZE));
 
-        while let Some(command) = self.read_command() {
-            self.run_command(command, &mut buf);
+        while let Some(command) = self.read_command()? {
+            self.run_command(command, &mut buf)?;
+            if buf.len() > target_len {
+                return Err(bad_delta("the result is larger than its size"));
+            }
+        }
+        if buf.len() != target_len {
+            return Err(bad_delta("the result size doesn't match"));
         }
-        assert_eq!(buf.len(), target_len);
         Ok(buf)
     }
 
-    fn read_command(&mut self) -> Option<DeltaOp> {
-        self.delta.read_u8().ok().map(|cmd| {
-            if cmd & 128 > 0 {
-                let mut offset = 0usize;
-                let mut shift = 0usize;
-                let mut length = 0usize;
-
-                // Read the offset to copy from
-                for mask in &[0x01, 0x02, 0x04, 0x08] {
-                    if cmd & mask > 0 {
-                        let byte = self.delta.read_u8().unwrap() as u64;
-                        offset += (byte as usize) << shift;
-                    }
-                    shift += 8;
-                }
+    fn read_command(&mut self) -> Result<Option<DeltaOp>> {
+        let cmd = match self.delta.read_u8() {
+            Ok(cmd) => cmd,
+            Err(_) => return Ok(None),
+        };
+        let op = if cmd & 128 > 0 {
+            let mut offset = 0usize;
+            let mut shift = 0usize;
+            let mut length = 0usize;
 
-                // Read the length of the copy
-                shift = 0;
-                for mask in &[0x10, 0x20, 0x40] {
-                    if cmd & mask > 0 {
-                        let byte = self.delta.read_u8().unwrap() as u64;
-                        length += ((byte as usize) << shift) as usize;
-                    }
-                    shift += 8;
+            // Read the offset to copy from
+            for mask in &[0x01, 0x02, 0x04, 0x08] {
+                if cmd & mask > 0 {
+                    let byte = self.read_operand()? as u64;
+                    offset += (byte as usize) << shift;
                 }
-                if length == 0 {
-                    length = 0x10000;
+                shift += 8;
+            }
+
+            // Read the length of the copy
+            shift = 0;
+            for mask in &[0x10, 0x20, 0x40] {
+                if cmd & mask > 0 {
+                    let byte = self.read_operand()? as u64;
+                    length += (byte as usize) << shift;
                 }
-                DeltaOp::Copy(offset, length)
-            } else {
-                DeltaOp::Insert(cmd as usize)
+                shift += 8;
             }
-        })
+            if length == 0 {
+                length = 0x10000;
+            }
+            DeltaOp::Copy(offset, length)
+        } else if cmd > 0 {
+            DeltaOp::Insert(cmd as usize)
+        } else {
+            return Err(bad_delta("reserved instruction"));
+        };
+        Ok(Some(op))
+    }
+
+    fn read_operand(&mut self) -> Result<u8> {
+        self.delta
+            .read_u8()
+            .map_err(|_| bad_delta("truncated copy instruction"))
     }
 
-    fn run_command(&mut self, command: DeltaOp, buf: &mut Vec<u8>) {
+    fn run_command(&mut self, command: DeltaOp, buf: &mut Vec<u8>) -> Result<()> {
         match command {
             DeltaOp::Copy(start, length) => {
-                buf.extend_from_slice(&self.source[start..start + length]);
+                let copied = self
+                    .source
+                    .get(start..start + length)
+                    .ok_or_else(|| bad_delta("copy out of the base bounds"))?;
+                buf.extend_from_slice(copied);
             }
             DeltaOp::Insert(length) => {
+                if length > self.delta.len() {
+                    return Err(bad_delta("truncated insert instruction"));
+                }
                 buf.extend_from_slice(&self.delta[..length]);
                 self.delta = &self.delta[length..];
             }
         }
+        Ok(())
+    }
+}
+
+fn bad_delta(reason: &str) -> Error {
+    Error::BadPack(format!("bad delta: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applying_a_delta() {
+        // Copies "hello " from the base and inserts "git"
+        let delta = [11, 9, 0x90, 6, 3, b'g', b'i', b't'];
+        assert_eq!(patch(b"hello world", &delta).unwrap(), b"hello git");
+    }
+
+    #[test]
+    fn malformed_deltas_are_errors() {
+        let is_bad_pack =
+            |base: &[u8], delta: &[u8]| matches!(patch(base, delta), Err(Error::BadPack(_)));
+        // The base size doesn't match
+        assert!(is_bad_pack(
+            b"hello",
+            &[11, 9, 0x90, 6, 3, b'g', b'i', b't']
+        ));
+        // Copies past the end of the base
+        assert!(is_bad_pack(b"hello world", &[11, 20, 0x91, 5, 20]));
+        // Inserts more than the delta holds
+        assert!(is_bad_pack(b"hello world", &[11, 3, 3, b'g']));
+        // The result is shorter, or longer, than announced
+        assert!(is_bad_pack(b"hello world", &[11, 9, 0x90, 6]));
+        assert!(is_bad_pack(b"hello world", &[11, 3, 0x90, 6]));
+        // A huge announced size isn't allocated up front
+        assert!(is_bad_pack(
+            b"hello world",
+            &[11, 0xff, 0xff, 0xff, 0xff, 0x0f, 1, b'g']
+        ));
+        assert!(is_bad_pack(b"hello world", &[0x80]));
     }
 }
@@ -0,0 +1,88 @@
+use crate::transport::url_parser::UrlError;
+use std::fmt::Formatter;
+
+///
+/// The errors returned by this crate. Malformed objects, packs or server
+/// answers are reported as errors, never as panics.
+///
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading or writing a file, or a connection, failed
+    Io(std::io::Error),
+    /// The url of the remote can't be parsed
+    Url(UrlError),
+    /// The directory isn't a git repository, or the server doesn't have it
+    RepositoryNotFound(String),
+    /// An object whose contents can't be parsed, with the reason
+    CorruptObject(String),
+    /// A pack or pack index that can't be read, with the reason
+    BadPack(String),
+    /// The sha of an object the repository doesn't have
+    MissingObject(String),
+    /// A ref, remote or refspec that is missing or invalid
+    Ref(String),
+    /// The server answered something that doesn't follow the git protocol
+    Protocol(String),
+    /// The request to the server failed, like an unexpected http status
+    Transport(String),
+    /// The http client failed, like when the connection is refused or times
+    /// out, holding the error of the client
+    Http(Box<dyn std::error::Error + Send + Sync>),
+    /// The server refused the credentials, or no credentials were given
+    Unauthorized(String),
+    /// The server reported an error through an `ERR` packet line or the
+    /// error channel of the sideband, like "repository not found"
+    Remote(String),
+    /// The operation was cancelled through its `CancellationToken`
+    Cancelled,
+    /// The repository uses something this crate doesn't handle, like
+    /// symbolic links in the tree to check out
+    Unsupported(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Url(e) => Some(e),
+            Error::Http(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => e.fmt(f),
+            Error::Url(e) => write!(f, "invalid url: {}", e),
+            Error::RepositoryNotFound(path) => write!(f, "not a git repository: {}", path),
+            Error::CorruptObject(reason) => write!(f, "corrupt object: {}", reason),
+            Error::BadPack(reason) => write!(f, "bad packfile: {}", reason),
+            Error::MissingObject(sha) => write!(f, "object {} not found", sha),
+            Error::Ref(reason) => f.write_str(reason),
+            Error::Protocol(reason) => write!(f, "protocol error: {}", reason),
+            Error::Transport(reason) => f.write_str(reason),
+            Error::Http(e) => e.fmt(f),
+            Error::Unauthorized(reason) => f.write_str(reason),
+            Error::Remote(message) => write!(f, "remote error: {}", message),
+            Error::Cancelled => f.write_str("operation cancelled"),
+            Error::Unsupported(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<UrlError> for Error {
+    fn from(e: UrlError) -> Self {
+        Error::Url(e)
+    }
+}
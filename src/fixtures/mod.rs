@@ -0,0 +1,123 @@
+use crate::credentials::GitCredentialsFile;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+pub static PACK_FILE: &str = "tests/data/packs/pack-79f006bb5e8d079fdbe07e7ce41f97f4db7d341c";
+/// the tip of `master` in the test pack
+pub static MASTER: &str = "718e7fc194a0fef1b1067b12689e5d343f533497";
+/// an annotated tag of the test pack
+pub static TAG: &str = "7a4219fa5df9550fa54636f2783cd7c3cb63b1f3";
+
+///
+/// An empty directory under the temp dir, named after the test.
+///
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rs-git-lib-{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+///
+/// Writes the test pack and a `master` branch pointing to its tip.
+///
+fn write_objects(git_dir: &Path) {
+    fs::create_dir_all(git_dir.join("objects/pack")).unwrap();
+    fs::create_dir_all(git_dir.join("refs/heads")).unwrap();
+    fs::create_dir_all(git_dir.join("refs/tags")).unwrap();
+    for ext in &["pack", "idx"] {
+        let src = Path::new(PACK_FILE).with_extension(ext);
+        let dst = git_dir.join("objects/pack").join(src.file_name().unwrap());
+        fs::copy(&src, dst).unwrap();
+    }
+    fs::write(git_dir.join("HEAD"), "ref: refs/heads/master\n").unwrap();
+    fs::write(git_dir.join("refs/heads/master"), format!("{}\n", MASTER)).unwrap();
+}
+
+///
+/// A repository with a work tree holding the test pack, with the tag in
+/// `packed-refs`. Nothing is checked out.
+///
+pub fn fixture_repo(name: &str) -> String {
+    let dir = temp_dir(name);
+    let git_dir = dir.join(".git");
+    write_objects(&git_dir);
+    fs::write(
+        git_dir.join("packed-refs"),
+        format!("{} refs/tags/test_tag\n", TAG),
+    )
+    .unwrap();
+    dir.to_str().unwrap().to_owned()
+}
+
+///
+/// A bare repository holding the test pack, to be served by git.
+///
+pub fn fixture_remote(name: &str) -> PathBuf {
+    let dir = temp_dir(&format!("remote-{}.git", name));
+    write_objects(&dir);
+    fs::write(dir.join("config"), "[core]\n\tbare = true\n").unwrap();
+    dir
+}
+
+///
+/// An empty git dir, for the tests writing refs.
+///
+pub fn temp_git_dir(name: &str) -> String {
+    temp_dir(&format!("refs-{}", name))
+        .to_str()
+        .unwrap()
+        .to_owned()
+}
+
+///
+/// A `.git-credentials` file with the given lines.
+///
+pub fn credentials_file(name: &str, contents: &str) -> GitCredentialsFile {
+    let path = std::env::temp_dir().join(format!("rs-git-lib-{}.git-credentials", name));
+    fs::write(&path, contents).unwrap();
+    GitCredentialsFile::new(path)
+}
+
+///
+/// Whether git is missing, in which case the tests running git-upload-pack
+/// or building repositories with git are skipped.
+///
+pub fn skip_without_git() -> bool {
+    let found = Command::new("git")
+        .arg("--version")
+        .output()
+        .is_ok_and(|output| output.status.success());
+    if !found {
+        eprintln!("git is not installed, skipping the test");
+    }
+    !found
+}
+
+///
+/// Runs git in `dir` with `input` on stdin, returning its trimmed stdout.
+///
+pub fn git(dir: &Path, args: &[&str], input: &str) -> String {
+    let mut child = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .env("GIT_AUTHOR_NAME", "a")
+        .env("GIT_AUTHOR_EMAIL", "a@b")
+        .env("GIT_COMMITTER_NAME", "a")
+        .env("GIT_COMMITTER_EMAIL", "a@b")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    String::from_utf8(output.stdout).unwrap().trim().to_owned()
+}
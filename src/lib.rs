@@ -7,35 +7,69 @@ extern crate num_derive;
 #[macro_use]
 extern crate nom;
 
+mod cancel;
+mod config;
+mod credentials;
 mod delta;
+mod error;
+#[cfg(test)]
+mod fixtures;
+mod options;
 mod packfile;
+mod progress;
 mod store;
 mod transport;
 mod utils;
 
-use crate::packfile::refs::{create_refs, resolve_ref, update_head, Refs};
-use crate::packfile::PackFile;
+pub use crate::cancel::CancellationToken;
+pub use crate::credentials::{Credential, CredentialProvider, GitCredentialsFile};
+pub use crate::error::{Error, Result};
+pub use crate::options::{CloneOptions, HttpOptions};
+pub use crate::progress::Progress;
+pub use crate::transport::client::PushStatus;
+pub use crate::transport::url_parser::UrlError;
+
+use crate::cancel::check as check_cancelled;
+use crate::config::Config;
+use crate::credentials::CredentialCallback;
+use crate::packfile::refs::{
+    check_fetch_refspec, check_ref_name, create_mirror_refs, create_ref, create_refs,
+    create_sym_ref, delete_ref, expand_ref_name, find_branch_or_tag, find_head_branch, map_refspec,
+    read_refs, resolve_ref, update_head, update_head_to, write_packed_refs, Ref, Refs,
+};
+use crate::packfile::{open_packs, PackFile, PackWriter};
+use crate::progress::ProgressCallback;
 use crate::store::commit::Commit;
-use crate::store::object::{GitObject, GitObjectType};
+use crate::store::object::{loose_objects, GitObject, GitObjectType};
 use crate::store::tree::{EntryMode, Tree, TreeEntry};
-use crate::utils::sha1_hash;
+use crate::utils::{find_git_dir, is_git_dir, is_sha, sha1_hash};
 use byteorder::{BigEndian, WriteBytesExt};
 use rustc_serialize::hex::FromHex;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs;
 use std::fs::{File, Permissions};
-use std::io::{Error, ErrorKind, Result as IOResult, Write};
+use std::io::{ErrorKind, Write};
 use std::iter::FromIterator;
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use transport::client::haves::Haves;
+use transport::client::{FetchRequest, RefUpdate, ZERO_ID};
 use transport::Transport;
 
 /// A Git Repository
 pub struct Repo {
     dir: String,
+    git_dir: String,
     refs: Refs,
     count_objects: usize,
-    pack: Option<PackFile>,
+    packs: Vec<PackFile>,
+    shallow: HashSet<String>,
+    credentials: Option<CredentialCallback>,
+    http: HttpOptions,
+    progress: Option<ProgressCallback>,
+    cancel: Option<CancellationToken>,
 }
 
 impl Repo {
@@ -44,7 +78,7 @@ impl Repo {
     ///
     /// * `url` - a string that holds de repo url from where we will clone
     /// * `dir` - an optional string with the path where the cloned repo will be out.
-    /// If None the dir wil be created based on url.
+    ///   If None the dir wil be created based on url.
     ///
     /// # Examples
     ///
@@ -54,27 +88,934 @@ impl Repo {
     /// let repo = Repo::clone_from("https://github.com/lnds/rs-git-lib.git", Some("/tmp/rs-git".to_string()));
     /// ```
     ///
-    pub fn clone_from(url: &str, dir: Option<String>) -> IOResult<Self> {
-        let mut transport = Transport::from_url(url, dir)?;
-        let dir = transport.dir();
-        let refs = transport.discover_refs()?;
-        let mut packfile_parser = transport.fetch_packfile(&refs)?;
-        let packfile = packfile_parser.parse(Some(&dir), None)?;
-        packfile.write(&dir)?;
-        create_refs(&dir, &refs)?;
-        update_head(&dir, &refs)?;
-        let repo = Repo {
-            dir,
-            refs,
-            count_objects: packfile_parser.count_objects(),
-            pack: Some(packfile),
+    pub fn clone_from(url: &str, dir: Option<String>) -> Result<Self> {
+        Repo::clone_with(url, dir, &CloneOptions::new())
+    }
+
+    /// clone a git repo without working directory
+    /// # Arguments
+    ///
+    /// * `url` - a string that holds de repo url from where we will clone
+    /// * `dir` - an optional string with the path of the bare repo.
+    ///   If None the dir wil be created based on url, ending with `.git`.
+    ///
+    /// The branches of the remote are copied to `refs/heads`, and no checkout is made.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rs_git_lib::Repo;
+    /// let repo = Repo::clone_bare("https://github.com/lnds/redondeo.git", Some("/tmp/redondeo.git".to_string())).unwrap();
+    /// assert_eq!(repo.commits().unwrap().len(), 5);
+    /// ```
+    ///
+    pub fn clone_bare(url: &str, dir: Option<String>) -> Result<Self> {
+        Repo::clone_with(url, dir, &CloneOptions::new().bare(true))
+    }
+
+    /// mirror a git repo
+    /// # Arguments
+    ///
+    /// * `url` - a string that holds de repo url from where we will clone
+    /// * `dir` - an optional string with the path of the bare repo.
+    ///   If None the dir wil be created based on url, ending with `.git`.
+    ///
+    /// Like `clone_bare`, but every ref of the remote is copied verbatim under `refs/`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rs_git_lib::Repo;
+    /// let repo = Repo::clone_mirror("https://github.com/lnds/redondeo.git", Some("/tmp/redondeo-mirror.git".to_string())).unwrap();
+    /// assert_eq!(repo.refs()[1].name, "refs/heads/master");
+    /// ```
+    ///
+    pub fn clone_mirror(url: &str, dir: Option<String>) -> Result<Self> {
+        Repo::clone_with(url, dir, &CloneOptions::new().mirror(true))
+    }
+
+    /// clone a git repo with the given options
+    /// # Arguments
+    ///
+    /// * `url` - a string that holds de repo url from where we will clone
+    /// * `dir` - an optional string with the path where the cloned repo will be out.
+    ///   If None the dir wil be created based on url.
+    /// * `options` - the `CloneOptions` driving the clone
+    ///
+    /// When a branch is given it is checked out and set to track the remote one,
+    /// a tag is checked out as a detached `HEAD`. The clone fails before fetching
+    /// anything if the remote doesn't advertise it.
+    ///
+    /// In single branch mode only that branch, or the remote `HEAD` branch, is
+    /// fetched along with the tags pointing into its history.
+    ///
+    /// With a depth only the last commits of history are fetched, the commits at
+    /// the boundary are recorded in the `shallow` file.
+    ///
+    /// When the clone fails, or is cancelled, the directory it created is
+    /// removed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rs_git_lib::{CloneOptions, Repo};
+    /// let options = CloneOptions::new().remote_name("upstream").no_checkout(true);
+    /// let repo = Repo::clone_with("https://github.com/lnds/redondeo.git", Some("/tmp/redondeo-upstream".to_string()), &options).unwrap();
+    /// assert_eq!(repo.commits().unwrap().len(), 5);
+    /// ```
+    ///
+    pub fn clone_with(url: &str, dir: Option<String>, options: &CloneOptions) -> Result<Self> {
+        let bare = options.bare || options.mirror;
+        let default_dir = dir.is_none();
+        let mut transport = Transport::from_url(url, dir, &options.http)?;
+        if let Some(ref credentials) = options.credentials {
+            transport.set_credentials(credentials.clone());
+        }
+        if let Some(ref cancel) = options.cancel {
+            transport.set_cancellation(cancel.clone());
+        }
+        let dir = if bare && default_dir {
+            format!("{}.git", transport.dir())
+        } else {
+            transport.dir()
+        };
+        let git_dir = if bare { dir.clone() } else { git_dir_of(&dir) };
+        // A failed clone removes what it created, but not what was there
+        let mut created = Created::default();
+        created.record(Path::new(&dir));
+        created.record(Path::new(&git_dir));
+        for entry in GIT_DIR_ENTRIES.iter() {
+            created.record(&Path::new(&git_dir).join(entry));
+        }
+        let repo = Repo::clone_to(url, transport, &dir, &git_dir, options, &mut created);
+        if repo.is_err() {
+            created.remove();
+        }
+        repo
+    }
+
+    fn clone_to(
+        url: &str,
+        mut transport: Transport,
+        dir: &str,
+        git_dir: &str,
+        options: &CloneOptions,
+        created: &mut Created,
+    ) -> Result<Self> {
+        let bare = options.bare || options.mirror;
+        let remote = &options.remote_name;
+        let (dir, git_dir) = (dir.to_owned(), git_dir.to_owned());
+        // Servers speaking protocol v2 only list the refs asked for
+        let (refs, capabilities) = if options.mirror {
+            transport.discover_refs()?
+        } else {
+            transport.discover_refs_with_prefixes(&["HEAD", "refs/heads/", "refs/tags/"])?
+        };
+        let checkout_ref = match options.branch {
+            Some(ref branch) => Some(find_branch_or_tag(&refs, branch)?),
+            None => None,
+        };
+        let head_target = capabilities.symref("HEAD");
+        let is_tag = |r: &Ref| r.name.starts_with("refs/tags/");
+        // In single branch mode only the checked out ref, or the remote HEAD, is wanted
+        let single_ref = checkout_ref
+            .or_else(|| find_head_branch(&refs, head_target))
+            .or_else(|| refs.iter().find(|r| r.name == "HEAD"))
+            .filter(|_| options.single_branch);
+        // Peeled tags aren't refs of the server, the tags are wanted instead
+        let wants: Refs = match single_ref {
+            Some(the_ref) => refs
+                .iter()
+                .filter(|r| r.name == the_ref.name.trim_end_matches("^{}"))
+                .cloned()
+                .collect(),
+            None => refs
+                .iter()
+                .filter(|r| !r.name.ends_with("^{}"))
+                .cloned()
+                .collect(),
         };
-        repo.checkout_head()?;
+        // What a copy can't honour goes through upload-pack, like a file:// url
+        let copy = options.depth.is_none()
+            && !options.single_branch
+            && options.progress.is_none()
+            && options.cancel.is_none();
+        let mut repo = if copy && transport.copies_objects() {
+            init_git_dir(&git_dir, bare, "master")?;
+            transport.copy_objects(&git_dir)?;
+            Repo::open(&dir)?
+        } else {
+            init_git_dir(&git_dir, bare, "master")?;
+            let request = FetchRequest {
+                wants: &wants,
+                haves: &Haves::none(),
+                shallow: &[],
+                include_tag: options.single_branch,
+                depth: options.depth,
+                git_dir: Some(&git_dir),
+                progress: options.progress.clone(),
+                cancel: options.cancel.clone(),
+            };
+            let mut packfile_parser = transport.fetch_packfile(&request)?;
+            let packfile = packfile_parser.parse(Some(&git_dir))?;
+            let shallow: HashSet<String> = packfile_parser.shallow().iter().cloned().collect();
+            write_shallow(&git_dir, &shallow)?;
+            Repo {
+                dir: dir.clone(),
+                git_dir: git_dir.clone(),
+                refs: vec![],
+                count_objects: packfile_parser.count_objects(),
+                packs: vec![packfile],
+                shallow,
+                credentials: None,
+                http: HttpOptions::new(),
+                progress: None,
+                cancel: None,
+            }
+        };
+
+        // Only record the tags whose objects were fetched
+        let mut fetched_refs = Vec::new();
+        for r in refs.iter() {
+            let wanted = if is_tag(r) {
+                repo.read_object(&r.id).is_ok()
+            } else {
+                match single_ref {
+                    Some(single) => single.name == r.name,
+                    None => true,
+                }
+            };
+            if wanted {
+                fetched_refs.push(r.clone());
+            }
+        }
+
+        let mut config = Config::open(&git_dir)?;
+        config.set("remote", Some(remote), "url", url);
+        if options.mirror {
+            config.set("remote", Some(remote), "fetch", "+refs/*:refs/*");
+            config.set("remote", Some(remote), "mirror", "true");
+            create_mirror_refs(&git_dir, &fetched_refs)?;
+        } else if bare {
+            create_refs(&git_dir, "refs/heads", &fetched_refs)?;
+        } else {
+            let tracking = format!("refs/remotes/{}", remote);
+            let refspec = match single_ref {
+                Some(r) if r.name.starts_with("refs/heads/") => {
+                    let branch = r.name.trim_start_matches("refs/heads/");
+                    format!("+{}:{}/{}", r.name, tracking, branch)
+                }
+                _ => format!("+refs/heads/*:{}/*", tracking),
+            };
+            config.set("remote", Some(remote), "fetch", &refspec);
+            create_refs(&git_dir, &tracking, &fetched_refs)?;
+            if let Some(branch) = find_head_branch(&fetched_refs, head_target) {
+                let branch = branch.name.trim_start_matches("refs/heads/");
+                let remote_head = format!("{}/HEAD", tracking);
+                create_sym_ref(&git_dir, &remote_head, &format!("{}/{}", tracking, branch))?;
+            }
+        }
+        let head = match checkout_ref {
+            Some(the_ref) => update_head_to(&git_dir, the_ref)?,
+            None => update_head(&git_dir, &refs, head_target)?,
+        };
+        if let (Some(branch), false) = (head, bare) {
+            let name = branch.trim_start_matches("refs/heads/");
+            config.set("branch", Some(name), "remote", remote);
+            config.set("branch", Some(name), "merge", &branch);
+        }
+        config.write()?;
+
+        repo.dir = dir;
+        // The refs written, as `open` reads them
+        repo.refs = read_refs(&git_dir)?;
+        repo.credentials = options.credentials.clone();
+        repo.http = options.http.clone();
+        repo.progress = options.progress.clone();
+        repo.cancel = options.cancel.clone();
+        if !bare && !options.no_checkout {
+            repo.checkout_head(created)?;
+        }
         Ok(repo)
     }
 
+    /// open an existing git repo
+    /// # Arguments
     ///
-    /// return references of cloned repo
+    /// * `path` - the path of the working directory holding the `.git` dir,
+    ///   or the path of a bare repository
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rs_git_lib::Repo;
+    /// let cloned = Repo::clone_from("https://github.com/lnds/redondeo.git", Some("/tmp/redondeo".to_string())).unwrap();
+    /// let repo = Repo::open("/tmp/redondeo").unwrap();
+    /// assert_eq!(repo.commits().unwrap().len(), 5);
+    /// ```
+    ///
+    pub fn open(path: &str) -> Result<Self> {
+        let git_dir = find_git_dir(Path::new(path))?
+            .ok_or_else(|| Error::RepositoryNotFound(path.to_owned()))?;
+        let git_dir = git_dir.to_string_lossy().into_owned();
+        let refs = read_refs(&git_dir)?;
+        let packs = open_packs(&git_dir)?;
+        let mut shas: HashSet<String> = loose_objects(&git_dir)?.into_iter().collect();
+        for pack in packs.iter() {
+            shas.extend(pack.shas());
+        }
+        let shallow = read_shallow(&git_dir)?;
+        Ok(Repo {
+            dir: path.to_string(),
+            git_dir,
+            refs,
+            count_objects: shas.len(),
+            packs,
+            shallow,
+            credentials: None,
+            http: HttpOptions::new(),
+            progress: None,
+            cancel: None,
+        })
+    }
+
+    /// create a new empty git repo
+    /// # Arguments
+    ///
+    /// * `path` - the directory where the repo will be created
+    /// * `bare` - if true `path` is the git dir itself, otherwise the git dir
+    ///   is `path/.git`
+    /// * `initial_branch` - an optional name for the branch `HEAD` points to.
+    ///   If None `master` is used.
+    ///
+    /// If `path` already holds a repo it is opened and left untouched.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rs_git_lib::Repo;
+    /// let repo = Repo::init("/tmp/rs-git-init", false, Some("main")).unwrap();
+    /// assert_eq!(repo.dir(), "/tmp/rs-git-init");
+    /// ```
+    ///
+    pub fn init(path: &str, bare: bool, initial_branch: Option<&str>) -> Result<Self> {
+        let git_dir = if bare {
+            path.to_string()
+        } else {
+            git_dir_of(path)
+        };
+        init_git_dir(&git_dir, bare, initial_branch.unwrap_or("master"))?;
+        Repo::open(path)
+    }
+
+    /// find and open the git repo containing a directory
+    /// # Arguments
+    ///
+    /// * `start_dir` - the directory where the search starts, usually somewhere
+    ///   inside a working directory
+    /// * `ceiling_dirs` - directories the search must not walk up into
+    ///
+    /// Returns the opened repo and the path of `start_dir` relative to the root
+    /// of the working directory.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rs_git_lib::Repo;
+    /// use std::path::Path;
+    /// let cloned = Repo::clone_from("https://github.com/lnds/rs-git-lib.git", Some("/tmp/rs-git".to_string())).unwrap();
+    /// let (repo, relative) = Repo::discover("/tmp/rs-git/src/store", &["/tmp"]).unwrap();
+    /// assert_eq!(repo.dir(), "/tmp/rs-git");
+    /// assert_eq!(relative, Path::new("src/store"));
+    /// ```
+    ///
+    pub fn discover(start_dir: &str, ceiling_dirs: &[&str]) -> Result<(Self, PathBuf)> {
+        let start = fs::canonicalize(start_dir)?;
+        let ceilings: Vec<PathBuf> = ceiling_dirs
+            .iter()
+            .filter_map(|d| fs::canonicalize(d).ok())
+            .collect();
+        let mut current = Some(start.as_path());
+        while let Some(dir) = current {
+            if let Some(git_dir) = find_git_dir(dir)? {
+                // Inside `.git` itself the repo is rooted at the parent work tree
+                let root = match dir.parent() {
+                    Some(parent) if git_dir == dir && dir.ends_with(".git") => parent,
+                    _ => dir,
+                };
+                let relative = start.strip_prefix(root).unwrap_or(&start).to_path_buf();
+                let repo = Repo::open(&root.to_string_lossy())?;
+                return Ok((repo, relative));
+            }
+            current = dir.parent().filter(|p| !ceilings.iter().any(|c| c == p));
+        }
+        Err(Error::RepositoryNotFound(start_dir.to_owned()))
+    }
+
+    /// fetch the changes of a remote
+    /// # Arguments
+    ///
+    /// * `remote` - the name of a remote configured in the repo, like `origin`
+    ///
+    /// The refs advertised by the remote are mapped with its fetch refspecs, the
+    /// commits already in the repo are negotiated so only the missing objects are
+    /// downloaded, and the received pack is stored alongside the existing ones.
+    /// A ref mapped by a refspec without a leading `+` is only updated when it
+    /// fast-forwards. Returns the refs that were created or updated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rs_git_lib::Repo;
+    /// let cloned = Repo::clone_from("https://github.com/lnds/redondeo.git", Some("/tmp/redondeo-fetch".to_string())).unwrap();
+    /// let mut repo = Repo::open("/tmp/redondeo-fetch").unwrap();
+    /// let updated = repo.fetch("origin").unwrap();
+    /// assert!(updated.iter().any(|r| r.name == "refs/remotes/origin/master"));
+    /// ```
+    ///
+    pub fn fetch(&mut self, remote: &str) -> Result<Refs> {
+        let (url, refspecs) = self.remote_config(remote)?;
+
+        let mut transport = self.remote_transport(&url)?;
+        let mut prefixes: Vec<&str> = refspecs
+            .iter()
+            .map(|r| {
+                let source = r.trim_start_matches('+').split(':').next().unwrap_or("");
+                source.trim_end_matches('*')
+            })
+            .collect();
+        prefixes.push("refs/tags/");
+        let (remote_refs, _) = transport.discover_refs_with_prefixes(&prefixes)?;
+        // Peeled tags aren't refs of the server. A ref mapped by several
+        // refspecs takes the first mapping it, with its force flag.
+        let mut updates: Refs = Vec::new();
+        let mut forced: HashSet<String> = HashSet::new();
+        for r in remote_refs.iter().filter(|r| !r.name.ends_with("^{}")) {
+            for refspec in refspecs.iter() {
+                if let Some(name) = map_refspec(refspec, &r.name) {
+                    if updates.iter().any(|u| u.name == name) {
+                        continue;
+                    }
+                    if refspec.starts_with('+') {
+                        forced.insert(name.clone());
+                    }
+                    updates.push(Ref {
+                        id: r.id.clone(),
+                        name,
+                    });
+                }
+            }
+        }
+        let wants: Refs = updates
+            .iter()
+            .filter(|r| self.read_object(&r.id).is_err())
+            .cloned()
+            .collect();
+
+        if !wants.is_empty() {
+            let mut shallow: Vec<String> = self.shallow.iter().cloned().collect();
+            shallow.sort();
+            let mut packfile_parser = transport.fetch_packfile(&FetchRequest {
+                wants: &wants,
+                haves: &self.haves()?,
+                shallow: &shallow,
+                include_tag: true,
+                depth: None,
+                git_dir: Some(&self.git_dir),
+                progress: self.progress.clone(),
+                cancel: self.cancel.clone(),
+            })?;
+            let packfile = packfile_parser
+                .parse_thin(Some(&self.git_dir), |sha| self.read_object(sha).ok())?;
+            self.count_objects += packfile_parser.count_objects();
+            self.packs.push(packfile);
+        }
+
+        // Follow the tags pointing to objects we have, keeping the existing ones
+        let local_refs = read_refs(&self.git_dir)?;
+        for r in remote_refs.iter() {
+            if r.name.starts_with("refs/tags/")
+                && !r.name.ends_with("^{}")
+                && !local_refs.iter().any(|l| l.name == r.name)
+                && !updates.iter().any(|u| u.name == r.name)
+                && self.read_object(&r.id).is_ok()
+            {
+                updates.push(r.clone());
+            }
+        }
+        updates.retain(|u| match local_refs.iter().find(|l| l.name == u.name) {
+            Some(l) => l.id != u.id && (forced.contains(&u.name) || self.is_ancestor(&l.id, &u.id)),
+            None => true,
+        });
+        // The names come from the remote, none is written if any is invalid
+        for update in updates.iter() {
+            check_ref_name(&update.name)?;
+        }
+        // Tags are packed with their peeled ref, as a clone writes them
+        let mut tags: Refs = Vec::new();
+        for update in updates.iter() {
+            if !update.name.starts_with("refs/tags/") {
+                create_ref(&self.git_dir, &update.name, &update.id)?;
+                continue;
+            }
+            tags.push(update.clone());
+            let peeled = self.peel(&update.id);
+            if peeled != update.id {
+                tags.push(Ref {
+                    id: peeled,
+                    name: format!("{}^{{}}", update.name),
+                });
+            }
+        }
+        write_packed_refs(&self.git_dir, &tags.iter().collect::<Vec<_>>())?;
+        self.refs = read_refs(&self.git_dir)?;
+        Ok(updates)
+    }
+
+    /// push refs to a configured remote
+    /// # Arguments
+    ///
+    /// * `remote` - the name of the remote, like `origin`
+    /// * `refspecs` - the refs to push, like `master`, `+feature:refs/heads/other`
+    ///   to force an update or `:refs/tags/v1` to delete a remote ref
+    ///
+    /// Returns the status of each ref, non fast-forward updates that aren't
+    /// forced are rejected without being sent. The remote-tracking refs of the
+    /// accepted updates are updated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rs_git_lib::Repo;
+    /// let mut repo = Repo::open("/tmp/rs-git").unwrap();
+    /// for status in repo.push("origin", &["master"]).unwrap() {
+    ///     assert!(status.is_ok(), "{} was rejected", status.name);
+    /// }
+    /// ```
+    ///
+    pub fn push(&mut self, remote: &str, refspecs: &[&str]) -> Result<Vec<PushStatus>> {
+        let (url, fetch_refspecs) = self.remote_config(remote)?;
+        let tracking_name = |name: &str| {
+            fetch_refspecs
+                .iter()
+                .find_map(|refspec| map_refspec(refspec, name))
+        };
+        let mut transport = self.remote_transport(&url)?;
+        let (remote_refs, _) = transport.discover_push_refs()?;
+
+        let mut statuses = Vec::new();
+        let mut updates = Vec::new();
+        for refspec in refspecs {
+            let (force, new_id, name) = self.parse_push_refspec(refspec)?;
+            let old_id = remote_refs
+                .iter()
+                .find(|r| r.name == name)
+                .map_or(ZERO_ID, |r| &r.id[..])
+                .to_owned();
+            if old_id == new_id {
+                statuses.push(PushStatus { name, error: None });
+            } else if !force
+                && old_id != ZERO_ID
+                && new_id != ZERO_ID
+                && !self.is_ancestor(&old_id, &new_id)
+            {
+                let error = Some("non-fast-forward".to_owned());
+                statuses.push(PushStatus { name, error });
+            } else {
+                updates.push(RefUpdate {
+                    name,
+                    old_id,
+                    new_id,
+                });
+            }
+        }
+        if updates.is_empty() {
+            return Ok(statuses);
+        }
+        // Checked before pushing, not to fail once the remote is updated
+        for update in updates.iter() {
+            if let Some(tracking) = tracking_name(&update.name) {
+                check_ref_name(&tracking)?;
+            }
+        }
+
+        let tips: Vec<String> = updates
+            .iter()
+            .filter(|u| u.new_id != ZERO_ID)
+            .map(|u| u.new_id.clone())
+            .collect();
+        // Only deletions are sent without a pack, which is written to a
+        // temporary file one object at a time and streamed from there
+        let pack = if tips.is_empty() {
+            None
+        } else {
+            let remote_tips: Vec<String> = remote_refs
+                .iter()
+                .filter(|r| self.read_object(&r.id).is_ok())
+                .map(|r| r.id.clone())
+                .collect();
+            let mut writer = PackWriter::create(None)?;
+            for sha in self.objects_to_push(&tips, &remote_tips)? {
+                writer.add(&self.read_object(&sha)?)?;
+            }
+            Some(writer.finish()?)
+        };
+        let sent = transport.send_pack(&updates, pack.as_deref());
+        if let Some(ref pack) = pack {
+            let _ = fs::remove_file(pack);
+        }
+
+        for status in sent? {
+            if status.is_ok() {
+                let update = updates.iter().find(|u| u.name == status.name);
+                if let (Some(update), Some(tracking)) = (update, tracking_name(&status.name)) {
+                    if update.new_id == ZERO_ID {
+                        delete_ref(&self.git_dir, &tracking)?;
+                    } else {
+                        create_ref(&self.git_dir, &tracking, &update.new_id)?;
+                    }
+                }
+            }
+            statuses.push(status);
+        }
+        self.refs = read_refs(&self.git_dir)?;
+        Ok(statuses)
+    }
+
+    ///
+    /// Sets the provider asked for credentials when a remote requires
+    /// authentication on fetch or push.
+    ///
+    pub fn set_credentials<P>(&mut self, provider: P)
+    where
+        P: CredentialProvider + Send + Sync + 'static,
+    {
+        self.credentials = Some(Arc::new(provider));
+    }
+
+    ///
+    /// Sets the settings of the http client used on fetch and push.
+    ///
+    pub fn set_http_options(&mut self, http: HttpOptions) {
+        self.http = http;
+    }
+
+    ///
+    /// Sets the callback receiving the progress of fetches, and of the
+    /// checkouts following them.
+    ///
+    pub fn set_progress<F>(&mut self, callback: F)
+    where
+        F: Fn(&Progress) + Send + Sync + 'static,
+    {
+        self.progress = Some(Arc::new(callback));
+    }
+
+    ///
+    /// Sets the token aborting fetches when cancelled.
+    ///
+    pub fn set_cancellation(&mut self, token: CancellationToken) {
+        self.cancel = Some(token);
+    }
+
+    fn remote_transport(&self, url: &str) -> Result<Transport> {
+        let mut transport = Transport::from_url(url, Some(self.dir.clone()), &self.http)?;
+        if let Some(ref credentials) = self.credentials {
+            transport.set_credentials(credentials.clone());
+        }
+        if let Some(ref cancel) = self.cancel {
+            transport.set_cancellation(cancel.clone());
+        }
+        Ok(transport)
+    }
+
+    ///
+    /// Reads the url and every fetch refspec of a remote from the config.
+    ///
+    fn remote_config(&self, remote: &str) -> Result<(String, Vec<String>)> {
+        let config = Config::open(&self.git_dir)?;
+        let url = config
+            .get("remote", Some(remote), "url")
+            .ok_or_else(|| Error::Ref(format!("no remote {}", remote)))?
+            .to_owned();
+        let mut refspecs: Vec<String> = config
+            .get_all("remote", Some(remote), "fetch")
+            .into_iter()
+            .map(|r| r.to_owned())
+            .collect();
+        if refspecs.is_empty() {
+            refspecs.push(format!("+refs/heads/*:refs/remotes/{}/*", remote));
+        }
+        for refspec in refspecs.iter() {
+            check_fetch_refspec(refspec)?;
+        }
+        Ok((url, refspecs))
+    }
+
+    ///
+    /// Parses a push refspec into whether it is forced, the id to push
+    /// (the zero id to delete) and the full name of the remote ref.
+    ///
+    fn parse_push_refspec(&self, refspec: &str) -> Result<(bool, String, String)> {
+        let force = refspec.starts_with('+');
+        let mut split = refspec.trim_start_matches('+').splitn(2, ':');
+        let (src, dst) = (split.next().unwrap_or(""), split.next());
+        if src.is_empty() {
+            let dst = dst.unwrap_or("");
+            let name = if dst.starts_with("refs/") {
+                dst.to_owned()
+            } else {
+                expand_ref_name(&self.git_dir, dst)
+                    .unwrap_or_else(|_| format!("refs/heads/{}", dst))
+            };
+            return Ok((force, ZERO_ID.to_owned(), name));
+        }
+        let src_name = expand_ref_name(&self.git_dir, src)?;
+        let id = resolve_ref(&self.git_dir, &src_name)?;
+        let name = match dst {
+            Some(dst) if dst.starts_with("refs/") => dst.to_owned(),
+            Some(dst) if src_name.starts_with("refs/tags/") => format!("refs/tags/{}", dst),
+            Some(dst) => format!("refs/heads/{}", dst),
+            None if src_name == "HEAD" => {
+                let err = "a detached HEAD can't be pushed without a destination";
+                return Err(Error::Ref(err.to_owned()));
+            }
+            None => src_name,
+        };
+        Ok((force, id, name))
+    }
+
+    ///
+    /// Whether the commit `ancestor` can be reached from `sha`, which may be
+    /// an annotated tag.
+    ///
+    fn is_ancestor(&self, ancestor: &str, sha: &str) -> bool {
+        let ancestor = self.peel(ancestor);
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut queue = vec![self.peel(sha)];
+        while let Some(sha) = queue.pop() {
+            if sha == ancestor {
+                return true;
+            }
+            if !seen.insert(sha.clone()) || self.shallow.contains(&sha) {
+                continue;
+            }
+            if let Some(commit) = self
+                .read_object(&sha)
+                .ok()
+                .as_ref()
+                .and_then(|o| o.as_commit())
+            {
+                queue.extend(commit.parents.iter().map(|p| p.to_string()));
+            }
+        }
+        false
+    }
+
+    ///
+    /// Follows annotated tags to the object they point to.
+    ///
+    fn peel(&self, sha: &str) -> String {
+        let mut sha = sha.to_owned();
+        while let Some(target) = self
+            .read_object(&sha)
+            .ok()
+            .and_then(|o| Some(o.as_tag()?.object.to_owned()))
+        {
+            sha = target;
+        }
+        sha
+    }
+
+    ///
+    /// Returns the tree and the parents of a commit, none for the parents of
+    /// a shallow commit. `None` when the object is missing or not a commit.
+    ///
+    fn commit_links(&self, sha: &str) -> Option<(String, Vec<String>)> {
+        let object = self.read_object(sha).ok()?;
+        let commit = object.as_commit()?;
+        let parents = if self.shallow.contains(sha) {
+            Vec::new()
+        } else {
+            commit.parents.iter().map(|p| p.to_string()).collect()
+        };
+        Some((commit.tree.to_owned(), parents))
+    }
+
+    ///
+    /// Returns the shas of the objects reachable from the tips but not from
+    /// the remote tips, like `git rev-list --objects tips ^remote_tips`. The
+    /// history is walked only until every commit left is known to the remote.
+    ///
+    fn objects_to_push(&self, tips: &[String], remote_tips: &[String]) -> Result<HashSet<String>> {
+        let mut objects: HashSet<String> = HashSet::new();
+        // Trees and blobs the tips point to through annotated tags
+        let mut roots: Vec<String> = Vec::new();
+        let mut remote_roots: Vec<String> = Vec::new();
+        let mut hidden: HashMap<String, bool> = HashMap::new();
+        let mut queue: VecDeque<String> = VecDeque::new();
+        for tip in remote_tips {
+            let sha = self.peel(tip);
+            if self.commit_links(&sha).is_none() {
+                remote_roots.push(sha);
+            } else if hidden.insert(sha.clone(), true).is_none() {
+                queue.push_back(sha);
+            }
+        }
+        for tip in tips {
+            let mut sha = tip.clone();
+            while let Some(target) = self
+                .read_object(&sha)
+                .ok()
+                .and_then(|o| Some(o.as_tag()?.object.to_owned()))
+            {
+                if !remote_tips.contains(&sha) {
+                    objects.insert(sha);
+                }
+                sha = target;
+            }
+            if self.commit_links(&sha).is_none() {
+                roots.push(sha);
+            } else if !hidden.contains_key(&sha) {
+                hidden.insert(sha.clone(), false);
+                queue.push_back(sha);
+            }
+        }
+
+        // Commits reached from the remote tips hide their ancestors, already
+        // visited or not, the walk ends once only hidden commits are left,
+        // `visible` counting the queued commits not hidden
+        let mut links: HashMap<String, (String, Vec<String>)> = HashMap::new();
+        let mut visible = queue.iter().filter(|sha| !hidden[*sha]).count();
+        while visible > 0 {
+            let sha = queue.pop_front().unwrap();
+            let is_hidden = hidden[&sha];
+            if !is_hidden {
+                visible -= 1;
+            }
+            let (tree, parents) = match self.commit_links(&sha) {
+                Some(links) => links,
+                // The history of the remote may be missing past a boundary
+                None if is_hidden => continue,
+                None => return Err(Error::MissingObject(sha)),
+            };
+            for parent in parents.iter() {
+                if !hidden.contains_key(parent) {
+                    hidden.insert(parent.clone(), is_hidden);
+                    queue.push_back(parent.clone());
+                    if !is_hidden {
+                        visible += 1;
+                    }
+                } else if is_hidden {
+                    let mut stack = vec![parent.clone()];
+                    while let Some(sha) = stack.pop() {
+                        let was_hidden = hidden.insert(sha.clone(), true);
+                        if was_hidden == Some(true) {
+                            continue;
+                        }
+                        // The commits not visited yet are the queued ones
+                        match links.get(&sha) {
+                            Some(l) => stack.extend(l.1.clone()),
+                            None if was_hidden == Some(false) => visible -= 1,
+                            None => {}
+                        }
+                    }
+                }
+            }
+            links.insert(sha, (tree, parents));
+        }
+
+        // The remote has the trees of the commits at the boundary
+        for (sha, (tree, parents)) in links.iter() {
+            if hidden[sha] {
+                continue;
+            }
+            objects.insert(sha.clone());
+            roots.push(tree.clone());
+            for parent in parents.iter().filter(|p| hidden[*p]) {
+                if let Some((tree, _)) = self.commit_links(parent) {
+                    remote_roots.push(tree);
+                }
+            }
+        }
+        for tip in remote_tips {
+            if let Some((tree, _)) = self.commit_links(&self.peel(tip)) {
+                remote_roots.push(tree);
+            }
+        }
+        let common = self.reachable_objects(&remote_roots, &HashSet::new())?;
+        objects.extend(self.reachable_objects(&roots, &common)?);
+        Ok(objects)
+    }
+
+    ///
+    /// Returns the shas of every object reachable from the given tips, not
+    /// going through the excluded ones nor the parents of shallow commits.
+    ///
+    fn reachable_objects(
+        &self,
+        tips: &[String],
+        exclude: &HashSet<String>,
+    ) -> Result<HashSet<String>> {
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut stack: Vec<String> = tips.to_vec();
+        while let Some(sha) = stack.pop() {
+            if exclude.contains(&sha) || !seen.insert(sha.clone()) {
+                continue;
+            }
+            let object = self.read_object(&sha)?;
+            match object.object_type {
+                GitObjectType::Commit => {
+                    let commit = object
+                        .as_commit()
+                        .ok_or_else(|| Error::CorruptObject(format!("invalid commit {}", sha)))?;
+                    stack.push(commit.tree.to_owned());
+                    if !self.shallow.contains(&sha) {
+                        stack.extend(commit.parents.iter().map(|p| p.to_string()));
+                    }
+                }
+                GitObjectType::Tree => {
+                    let tree = object
+                        .as_tree()
+                        .ok_or_else(|| Error::CorruptObject(format!("invalid tree {}", sha)))?;
+                    // Submodule commits live in another repository
+                    stack.extend(
+                        tree.entries
+                            .into_iter()
+                            .filter(|e| !matches!(e.mode, EntryMode::Gitlink))
+                            .map(|e| e.sha),
+                    );
+                }
+                GitObjectType::Tag => stack.extend(object.as_tag().map(|t| t.object.to_owned())),
+                GitObjectType::Blob => (),
+            }
+        }
+        Ok(seen)
+    }
+
+    ///
+    /// Returns the commits reachable from the local refs, walked from their
+    /// tips as the negotiation of a fetch goes.
+    ///
+    fn haves(&self) -> Result<Haves<'_>> {
+        let tips = read_refs(&self.git_dir)?
+            .iter()
+            .map(|r| self.peel(&r.id))
+            .collect();
+        Ok(Haves::new(tips, move |sha| {
+            let object = self.read_object(sha).ok()?;
+            let commit = object.as_commit()?;
+            // The parents of shallow commits are not in the repo
+            if self.shallow.contains(sha) {
+                return Some(Vec::new());
+            }
+            Some(commit.parents.iter().map(|p| p.to_string()).collect())
+        }))
+    }
+
+    ///
+    /// return the references of the repo, `HEAD` first and then the
+    /// branches, remote-tracking branches and tags stored in it
     ///
     /// ```
     /// use rs_git_lib::Repo;
@@ -122,40 +1063,53 @@ impl Repo {
     /// let commits = repo.commits().unwrap();
     /// assert_eq!(commits.len(), 5);
     /// assert_eq!(commits[4].as_commit().unwrap().get_message(), "Initial commit".to_string())
-    pub fn commits(&self) -> IOResult<Vec<GitObject>> {
-        let tip = resolve_ref(&self.dir, "HEAD")?;
+    pub fn commits(&self) -> Result<Vec<GitObject>> {
+        let tip = resolve_ref(&self.git_dir, "HEAD")?;
         let mut result = Vec::new();
         let head = self.read_object(&tip)?;
         if let Some(commit) = head.as_commit() {
             result.push(head.clone());
-            self.search_parents(&mut result, &commit)?;
+            if !self.shallow.contains(&tip) {
+                self.search_parents(&mut result, &commit)?;
+            }
         }
         Ok(result)
     }
 
-    fn search_parents(
-        &self,
-        mut vec_of_commits: &mut Vec<GitObject>,
-        commit: &Commit,
-    ) -> IOResult<()> {
+    fn search_parents(&self, vec_of_commits: &mut Vec<GitObject>, commit: &Commit) -> Result<()> {
         if commit.has_parents() {
             for parent in commit.parents.iter() {
                 let obj = self.read_object(parent)?;
                 if obj.object_type == GitObjectType::Commit {
                     vec_of_commits.push(obj.clone());
-                    self.search_parents(&mut vec_of_commits, &obj.as_commit().unwrap())?;
+                    // The parents of a shallow commit are not in the repo
+                    if !self.shallow.contains(*parent) {
+                        let commit = obj.as_commit().ok_or_else(|| {
+                            Error::CorruptObject(format!("invalid commit {}", parent))
+                        })?;
+                        self.search_parents(vec_of_commits, &commit)?;
+                    }
                 }
             }
         }
         Ok(())
     }
 
-    fn checkout_head(&self) -> IOResult<()> {
-        let tip = resolve_ref(&self.dir, "HEAD")?;
-        let mut idx = Vec::new();
-        self.walk(&tip)
-            .and_then(|t| self.walk_tree(&self.dir, &t, &mut idx).ok());
-        write_index(&self.dir, &mut idx[..])?;
+    fn checkout_head(&self, created: &mut Created) -> Result<()> {
+        let tip = resolve_ref(&self.git_dir, "HEAD")?;
+        let mut checkout = Checkout {
+            idx: Vec::new(),
+            total: 0,
+            created,
+        };
+        if let Some(tree) = self.walk(&tip) {
+            // The trees are only read twice when the progress is reported
+            if self.progress.is_some() {
+                checkout.total = self.count_files(&tree);
+            }
+            self.walk_tree(&self.dir, &tree, &mut checkout)?;
+        }
+        write_index(&self.git_dir, &mut checkout.idx[..])?;
         Ok(())
     }
 
@@ -169,22 +1123,35 @@ impl Repo {
             })
     }
 
-    fn walk_tree(&self, parent: &str, tree: &Tree, idx: &mut Vec<IndexEntry>) -> IOResult<()> {
+    ///
+    /// Counts the index entries written when checking out the tree.
+    ///
+    fn count_files(&self, tree: &Tree) -> usize {
+        tree.entries
+            .iter()
+            .map(|entry| match entry.mode {
+                EntryMode::SubDirectory => self
+                    .walk(&entry.sha)
+                    .map_or(0, |subtree| self.count_files(&subtree)),
+                _ => 1,
+            })
+            .sum()
+    }
+
+    fn walk_tree(&self, parent: &str, tree: &Tree, checkout: &mut Checkout) -> Result<()> {
         for entry in &tree.entries {
-            let &TreeEntry {
-                ref path,
-                ref mode,
-                ref sha,
-            } = entry;
+            check_cancelled(&self.cancel)?;
+            let TreeEntry { path, mode, sha } = entry;
             let mut full_path = PathBuf::new();
             full_path.push(parent);
             full_path.push(path);
+            checkout.created.record(&full_path);
             match *mode {
                 EntryMode::SubDirectory => {
                     fs::create_dir_all(&full_path)?;
-                    let path_str = full_path.to_str().unwrap();
-                    self.walk(sha)
-                        .and_then(|t| self.walk_tree(path_str, &t, idx).ok());
+                    if let Some(subtree) = self.walk(sha) {
+                        self.walk_tree(&full_path.to_string_lossy(), &subtree, checkout)?;
+                    }
                 }
                 EntryMode::Normal | EntryMode::Executable => {
                     let object = self.read_object(sha)?;
@@ -200,30 +1167,56 @@ impl Repo {
                     perms.set_mode(raw_mode);
                     fs::set_permissions(&full_path, perms)?;
 
-                    let idx_entry = get_index_entry(
-                        &self.dir,
-                        full_path.to_str().unwrap(),
-                        mode.clone(),
-                        sha.clone(),
-                    )?;
-                    idx.push(idx_entry);
+                    self.add_to_index(&full_path, mode, sha, checkout)?;
+                }
+                EntryMode::Symlink => {
+                    let err = format!("checking out symbolic links is not supported: {}", path);
+                    return Err(Error::Unsupported(err));
+                }
+                EntryMode::Gitlink => {
+                    let err = format!("checking out submodules is not supported: {}", path);
+                    return Err(Error::Unsupported(err));
                 }
-                ref e => panic!("Unsupported Entry Mode {:?}", e),
             }
         }
         Ok(())
     }
 
-    pub fn read_object(&self, sha: &str) -> IOResult<GitObject> {
+    fn add_to_index(
+        &self,
+        path: &Path,
+        mode: &EntryMode,
+        sha: &str,
+        checkout: &mut Checkout,
+    ) -> Result<()> {
+        let idx_entry = get_index_entry(&self.dir, &path.to_string_lossy(), mode.clone(), sha)?;
+        if let Some(ref progress) = self.progress {
+            progress(&Progress::CheckedOut {
+                path: idx_entry.path.clone(),
+                files: checkout.idx.len() + 1,
+                total: checkout.total,
+            });
+        }
+        checkout.idx.push(idx_entry);
+        Ok(())
+    }
+
+    pub fn read_object(&self, sha: &str) -> Result<GitObject> {
+        if !is_sha(sha) {
+            return Err(Error::MissingObject(sha.to_owned()));
+        }
         // Attempt to read from disk first
-        GitObject::open(&self.dir, sha).or_else(|_| {
-            // If this isn't there, read from the packfile
-            let pack = self
-                .pack
-                .as_ref()
-                .ok_or_else(|| Error::new(ErrorKind::Other, "can't read pack object"))?;
-            pack.find_by_sha(sha).map(|o| o.unwrap())
-        })
+        match GitObject::open(&self.git_dir, sha) {
+            Err(Error::Io(ref e)) if e.kind() == ErrorKind::NotFound => (),
+            result => return result,
+        }
+        // If this isn't there, read from the packfiles
+        for pack in self.packs.iter() {
+            if let Some(object) = pack.find_by_sha(sha)? {
+                return Ok(object);
+            }
+        }
+        Err(Error::MissingObject(sha.to_owned()))
     }
 
     fn extract_tree(&self, commit: &Commit) -> Option<Tree> {
@@ -251,10 +1244,114 @@ struct IndexEntry {
     path: String,
 }
 
-fn write_index(repo: &str, entries: &mut [IndexEntry]) -> IOResult<()> {
+/// The entries of a git dir a clone writes
+const GIT_DIR_ENTRIES: [&str; 7] = [
+    "HEAD",
+    "config",
+    "index",
+    "objects",
+    "packed-refs",
+    "refs",
+    "shallow",
+];
+
+///
+/// The paths a clone created, removed when it fails. Only the topmost of
+/// the new paths are kept, removing them removes what they hold.
+///
+#[derive(Default)]
+struct Created {
+    paths: HashSet<PathBuf>,
+}
+
+impl Created {
+    ///
+    /// Records `path` before it is written, when it doesn't exist yet.
+    ///
+    fn record(&mut self, path: &Path) {
+        let in_created = path.ancestors().any(|p| self.paths.contains(p));
+        if !in_created && !path.exists() {
+            self.paths.insert(path.to_path_buf());
+        }
+    }
+
+    fn remove(&self) {
+        for path in self.paths.iter() {
+            let _ = match fs::symlink_metadata(path) {
+                Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+                _ => fs::remove_file(path),
+            };
+        }
+    }
+}
+
+///
+/// The state of a checkout as the trees are walked.
+///
+struct Checkout<'a> {
+    idx: Vec<IndexEntry>,
+    /// the number of entries to write, when reporting the progress
+    total: usize,
+    created: &'a mut Created,
+}
+
+///
+/// Lays out an empty git dir, unless there is already one at `git_dir`.
+///
+fn init_git_dir(git_dir: &str, bare: bool, branch: &str) -> Result<()> {
+    if is_git_dir(Path::new(git_dir)) {
+        return Ok(());
+    }
+    for dir in &["objects/info", "objects/pack", "refs/heads", "refs/tags"] {
+        fs::create_dir_all(Path::new(git_dir).join(dir))?;
+    }
+    create_sym_ref(git_dir, "HEAD", &format!("refs/heads/{}", branch))?;
+
+    let mut config = Config::open(git_dir)?;
+    config.set("core", None, "repositoryformatversion", "0");
+    config.set("core", None, "filemode", "true");
+    config.set("core", None, "bare", &bare.to_string());
+    if !bare {
+        config.set("core", None, "logallrefupdates", "true");
+    }
+    Ok(config.write()?)
+}
+
+///
+/// Reads the commits listed in the `shallow` file of a shallow clone.
+///
+fn read_shallow(git_dir: &str) -> Result<HashSet<String>> {
+    match fs::read_to_string(Path::new(git_dir).join("shallow")) {
+        Ok(contents) => Ok(contents.lines().map(|l| l.trim().to_owned()).collect()),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_shallow(git_dir: &str, shallow: &HashSet<String>) -> Result<()> {
+    let path = Path::new(git_dir).join("shallow");
+    if shallow.is_empty() {
+        return match fs::remove_file(path) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        };
+    }
+    let mut shas: Vec<&String> = shallow.iter().collect();
+    shas.sort();
+    let contents: String = shas.iter().map(|s| format!("{}\n", s)).collect();
+    Ok(fs::write(path, contents)?)
+}
+
+fn git_dir_of(dir: &str) -> String {
     let mut path = PathBuf::new();
-    path.push(repo);
+    path.push(dir);
     path.push(".git");
+    path.to_string_lossy().into_owned()
+}
+
+fn write_index(git_dir: &str, entries: &mut [IndexEntry]) -> Result<()> {
+    let mut path = PathBuf::new();
+    path.push(git_dir);
     path.push("index");
     let mut idx_file = File::create(path)?;
     let encoded = encode_index(entries)?;
@@ -262,10 +1359,10 @@ fn write_index(repo: &str, entries: &mut [IndexEntry]) -> IOResult<()> {
     Ok(())
 }
 
-fn encode_index(idx: &mut [IndexEntry]) -> IOResult<Vec<u8>> {
+fn encode_index(idx: &mut [IndexEntry]) -> Result<Vec<u8>> {
     let mut encoded = index_header(idx.len())?;
     idx.sort_by(|a, b| a.path.cmp(&b.path));
-    let entries: Result<Vec<_>, _> = idx.iter().map(|e| encode_entry(e)).collect();
+    let entries: Result<Vec<_>> = idx.iter().map(encode_entry).collect();
     let mut encoded_entries = entries?.concat();
     encoded.append(&mut encoded_entries);
     let mut hash = sha1_hash(&encoded);
@@ -273,7 +1370,7 @@ fn encode_index(idx: &mut [IndexEntry]) -> IOResult<Vec<u8>> {
     Ok(encoded)
 }
 
-fn index_header(num_entries: usize) -> IOResult<Vec<u8>> {
+fn index_header(num_entries: usize) -> Result<Vec<u8>> {
     let mut header = Vec::with_capacity(12);
     let magic = 1_145_655_875; // "DIRC"
     let version: u32 = 2;
@@ -283,7 +1380,7 @@ fn index_header(num_entries: usize) -> IOResult<Vec<u8>> {
     Ok(header)
 }
 
-fn encode_entry(entry: &IndexEntry) -> IOResult<Vec<u8>> {
+fn encode_entry(entry: &IndexEntry) -> Result<Vec<u8>> {
     let mut buf: Vec<u8> = Vec::with_capacity(62);
     let &IndexEntry {
         ctime,
@@ -296,10 +1393,10 @@ fn encode_entry(entry: &IndexEntry) -> IOResult<Vec<u8>> {
         size,
         ..
     } = entry;
-    let &IndexEntry {
-        ref sha,
-        ref file_mode,
-        ref path,
+    let IndexEntry {
+        sha,
+        file_mode,
+        path,
         ..
     } = entry;
     let flags = (path.len() & 0xFFF) as u16;
@@ -335,30 +1432,25 @@ fn encode_entry(entry: &IndexEntry) -> IOResult<Vec<u8>> {
     buf.write_u32::<BigEndian>(device as u32)?;
     buf.write_u32::<BigEndian>(inode as u32)?;
     buf.write_u32::<BigEndian>(encoded_mode)?;
-    buf.write_u32::<BigEndian>(uid as u32)?;
-    buf.write_u32::<BigEndian>(gid as u32)?;
+    buf.write_u32::<BigEndian>(uid)?;
+    buf.write_u32::<BigEndian>(gid)?;
     buf.write_u32::<BigEndian>(size as u32)?;
-    buf.extend_from_slice(&sha);
+    buf.extend_from_slice(sha);
     buf.write_u16::<BigEndian>(flags)?;
     buf.extend(path_and_padding);
     Ok(buf)
 }
 
-fn get_index_entry(
-    root: &str,
-    path: &str,
-    file_mode: EntryMode,
-    sha: String,
-) -> IOResult<IndexEntry> {
-    let file = File::open(path)?;
-    let meta = file.metadata()?;
+fn get_index_entry(root: &str, path: &str, file_mode: EntryMode, sha: &str) -> Result<IndexEntry> {
+    // Symlinks are indexed themselves, not their targets
+    let meta = fs::symlink_metadata(path)?;
 
     // We need to remove the repo path from the path we save on the index entry
     // FIXME: This doesn't need to be a path since we just discard it again
     let relative_path = PathBuf::from(path.trim_start_matches(root).trim_start_matches('/'));
     let decoded_sha = sha
         .from_hex()
-        .map_err(|_| Error::new(ErrorKind::Other, "can't decode sha"))?;
+        .map_err(|_| Error::CorruptObject(format!("bad sha {}", sha)))?;
 
     Ok(IndexEntry {
         ctime: meta.ctime(),
@@ -371,6 +1463,521 @@ fn get_index_entry(
         size: meta.size() as i64,
         sha: decoded_sha,
         file_mode,
-        path: relative_path.to_str().unwrap().to_owned(),
+        path: relative_path.to_string_lossy().into_owned(),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{fixture_repo, git, skip_without_git, temp_dir, MASTER, PACK_FILE, TAG};
+
+    #[test]
+    fn opening_an_existing_repo() {
+        let dir = fixture_repo("open");
+        let repo = Repo::open(&dir).unwrap();
+        let commits = repo.commits().unwrap();
+        assert_eq!(commits[0].sha(), MASTER);
+        assert_eq!(
+            commits[1].as_commit().unwrap().get_message(),
+            "initial commit\n"
+        );
+        assert!(repo.read_object(TAG).is_ok());
+        assert_eq!(repo.count_objects, 30);
+
+        let refs = repo.refs();
+        assert_eq!(refs[0].name, "HEAD");
+        assert_eq!(refs[0].id, MASTER);
+        assert_eq!(refs[1].name, "refs/heads/master");
+        assert_eq!(refs[2].name, "refs/tags/test_tag");
+        assert_eq!(refs[2].id, TAG);
+    }
+
+    #[test]
+    fn cloning_a_local_repo() {
+        let source = fixture_repo("local-source");
+        // A branch sharing the sha of HEAD, listed before master
+        let branch = Path::new(&source).join(".git/refs/heads/aaa");
+        fs::write(branch, format!("{}\n", MASTER)).unwrap();
+        let dir = std::env::temp_dir().join("rs-git-lib-local-clone");
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_str().unwrap().to_owned();
+        let repo = Repo::clone_with(&source, Some(dir.clone()), &CloneOptions::new()).unwrap();
+        assert_eq!(repo.commits().unwrap()[0].sha(), MASTER);
+        assert_eq!(repo.count_objects, 30);
+        let git_dir = Path::new(&dir).join(".git");
+        assert_eq!(
+            fs::read_to_string(git_dir.join("refs/remotes/origin/master")).unwrap(),
+            format!("{}\n", MASTER)
+        );
+        assert_eq!(
+            fs::read_to_string(git_dir.join("refs/remotes/origin/HEAD")).unwrap(),
+            "ref: refs/remotes/origin/master\n"
+        );
+        assert_eq!(
+            fs::read_to_string(git_dir.join("HEAD")).unwrap(),
+            "ref: refs/heads/master\n"
+        );
+        assert_eq!(
+            fs::read_to_string(git_dir.join("packed-refs")).unwrap(),
+            format!(
+                "# pack-refs with: peeled fully-peeled sorted \n\
+                 {} refs/tags/test_tag\n\
+                 ^3c7cfac73a699ef415bc737ce5529ac66c5692a9\n",
+                TAG
+            )
+        );
+        assert!(Path::new(&dir).join("git.txt").is_file());
+    }
+
+    #[test]
+    fn cloned_and_opened_repos_have_the_same_refs() {
+        let source = fixture_repo("refs-source");
+        let dir = temp_dir("refs-clone").join("clone");
+        let dir = dir.to_str().unwrap().to_owned();
+        let cloned = Repo::clone_with(&source, Some(dir.clone()), &CloneOptions::new()).unwrap();
+        let opened = Repo::open(&dir).unwrap();
+        let names = |refs: Refs| -> Vec<(String, String)> {
+            refs.into_iter().map(|r| (r.name, r.id)).collect()
+        };
+        let cloned = names(cloned.refs());
+        assert_eq!(cloned, names(opened.refs()));
+        assert!(cloned
+            .iter()
+            .any(|(name, _)| name == "refs/remotes/origin/master"));
+    }
+
+    #[test]
+    fn cloning_a_local_repo_with_a_depth() {
+        if skip_without_git() {
+            return;
+        }
+        let source = fixture_repo("local-depth-source");
+        let dir = std::env::temp_dir().join("rs-git-lib-local-depth-clone");
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_str().unwrap().to_owned();
+        // The objects are fetched through upload-pack instead of copied
+        let options = CloneOptions::new().bare(true).depth(1);
+        let repo = Repo::clone_with(&source, Some(dir.clone()), &options).unwrap();
+        assert_eq!(repo.commits().unwrap().len(), 1);
+        let shallow = fs::read_to_string(Path::new(&dir).join("shallow")).unwrap();
+        assert!(shallow.lines().any(|sha| sha == MASTER));
+    }
+
+    #[test]
+    fn cloning_a_file_url() {
+        if skip_without_git() {
+            return;
+        }
+        let source = fixture_repo("file-source");
+        let dir = std::env::temp_dir().join("rs-git-lib-file-clone");
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_str().unwrap().to_owned();
+        let url = format!("file://{}", source);
+        let options = CloneOptions::new().bare(true).progress(|_| ());
+        let repo = Repo::clone_with(&url, Some(dir.clone()), &options).unwrap();
+        assert_eq!(repo.commits().unwrap()[0].sha(), MASTER);
+        // A pack was fetched, not copied from the source
+        let source_pack = Path::new(PACK_FILE).with_extension("pack");
+        let packs = fs::read_dir(Path::new(&dir).join("objects/pack")).unwrap();
+        assert!(packs
+            .map(|p| p.unwrap().file_name())
+            .all(|name| Some(name.as_os_str()) != source_pack.file_name()));
+    }
+
+    #[test]
+    fn cloning_reports_its_progress() {
+        if skip_without_git() {
+            return;
+        }
+        use std::sync::Mutex;
+        let source = fixture_repo("progress-source");
+        let dir = std::env::temp_dir().join("rs-git-lib-progress-clone");
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_str().unwrap().to_owned();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let received = events.clone();
+        let options =
+            CloneOptions::new().progress(move |p| received.lock().unwrap().push(p.clone()));
+        Repo::clone_with(&format!("file://{}", source), Some(dir), &options).unwrap();
+
+        let events = events.lock().unwrap();
+        assert!(events
+            .iter()
+            .any(|e| matches!(e, Progress::Received { bytes } if *bytes > 0)));
+        let objects: Vec<(usize, usize)> = events
+            .iter()
+            .filter_map(|e| match e {
+                Progress::Objects { parsed, total } => Some((*parsed, *total)),
+                _ => None,
+            })
+            .collect();
+        assert!(objects
+            .last()
+            .is_some_and(|&(parsed, total)| parsed == total && total > 0));
+        let deltas: Vec<(usize, usize)> = events
+            .iter()
+            .filter_map(|e| match e {
+                Progress::Deltas { resolved, total } => Some((*resolved, *total)),
+                _ => None,
+            })
+            .collect();
+        assert!(deltas
+            .last()
+            .is_some_and(|&(resolved, total)| resolved == total));
+        let files: Vec<(&str, usize, usize)> = events
+            .iter()
+            .filter_map(|e| match e {
+                Progress::CheckedOut { path, files, total } => Some((&path[..], *files, *total)),
+                _ => None,
+            })
+            .collect();
+        assert!(files.iter().any(|f| f.0 == "git.txt"));
+        assert!(files.iter().enumerate().all(|(i, f)| f.1 == i + 1));
+        assert!(files.iter().all(|f| f.2 == files.len()));
+    }
+
+    #[test]
+    fn repos_and_clone_options_can_be_sent_to_other_threads() {
+        fn assert_send<T: Send>() {}
+        assert_send::<Repo>();
+        assert_send::<CloneOptions>();
+    }
+
+    #[test]
+    fn a_cancelled_clone_removes_its_directory() {
+        if skip_without_git() {
+            return;
+        }
+        let source = fixture_repo("cancel-source");
+        let url = format!("file://{}", source);
+        let dir = std::env::temp_dir().join("rs-git-lib-cancelled-clone");
+        let clone_cancelled_on = |cancel_on: fn(&Progress) -> bool, bare: bool| {
+            let token = CancellationToken::new();
+            let canceller = token.clone();
+            let options = CloneOptions::new()
+                .bare(bare)
+                .cancellation(token)
+                .progress(move |p| {
+                    if cancel_on(p) {
+                        canceller.cancel()
+                    }
+                });
+            let dir_name = dir.to_str().unwrap().to_owned();
+            let err = Repo::clone_with(&url, Some(dir_name), &options)
+                .err()
+                .unwrap();
+            assert!(matches!(err, Error::Cancelled));
+        };
+        // Cancelled while the pack is received, and while checking it out
+        let on_objects: fn(&Progress) -> bool = |p| matches!(p, Progress::Objects { .. });
+        let on_checkout: fn(&Progress) -> bool = |p| matches!(p, Progress::CheckedOut { .. });
+        for cancel_on in [on_objects, on_checkout].iter().copied() {
+            let _ = fs::remove_dir_all(&dir);
+            clone_cancelled_on(cancel_on, false);
+            assert!(!dir.exists());
+        }
+
+        // What a directory given to the clone held is kept
+        let entries = || {
+            let mut names: Vec<String> = fs::read_dir(&dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+        for &(cancel_on, bare) in [(on_checkout, false), (on_objects, true)].iter() {
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("notes")).unwrap();
+            fs::write(dir.join("notes/todo.txt"), "kept").unwrap();
+            clone_cancelled_on(cancel_on, bare);
+            assert_eq!(entries(), vec!["notes"]);
+            assert!(dir.join("notes/todo.txt").is_file());
+        }
+    }
+
+    #[test]
+    fn computing_the_objects_to_push() {
+        let dir = fixture_repo("push-objects");
+        let repo = Repo::open(&dir).unwrap();
+        let parent = repo.commits().unwrap()[1].sha();
+        assert!(repo.is_ancestor(&parent, MASTER));
+        assert!(!repo.is_ancestor(MASTER, &parent));
+
+        let common = repo
+            .reachable_objects(std::slice::from_ref(&parent), &HashSet::new())
+            .unwrap();
+        let objects = repo
+            .objects_to_push(&[MASTER.to_owned()], std::slice::from_ref(&parent))
+            .unwrap();
+        assert!(objects.contains(MASTER));
+        assert!(!objects.contains(&parent));
+        assert!(objects.iter().all(|sha| !common.contains(sha)));
+        // The whole history is sent to an empty remote, nothing to its tip
+        let all = repo.reachable_objects(&[MASTER.to_owned()], &HashSet::new());
+        let objects = repo.objects_to_push(&[MASTER.to_owned()], &[]).unwrap();
+        assert_eq!(objects, all.unwrap());
+        let tips = [MASTER.to_owned()];
+        assert!(repo.objects_to_push(&tips, &tips).unwrap().is_empty());
+
+        let tag = repo.objects_to_push(&[TAG.to_owned()], &[]).unwrap();
+        assert!(tag.contains(TAG));
+        assert!(tag.contains(&repo.peel(TAG)));
+    }
+
+    #[test]
+    fn parsing_push_refspecs() {
+        let dir = fixture_repo("push-refspecs");
+        let repo = Repo::open(&dir).unwrap();
+        let (force, id, name) = repo.parse_push_refspec("master").unwrap();
+        assert_eq!(
+            (force, &id[..], &name[..]),
+            (false, MASTER, "refs/heads/master")
+        );
+        let (force, _, name) = repo.parse_push_refspec("+HEAD:other").unwrap();
+        assert_eq!((force, &name[..]), (true, "refs/heads/other"));
+        let (_, id, name) = repo.parse_push_refspec("test_tag").unwrap();
+        assert_eq!((&id[..], &name[..]), (TAG, "refs/tags/test_tag"));
+        let (_, id, name) = repo.parse_push_refspec(":old").unwrap();
+        assert_eq!((&id[..], &name[..]), (ZERO_ID, "refs/heads/old"));
+        assert!(repo.parse_push_refspec("missing").is_err());
+    }
+
+    #[test]
+    fn discovering_a_repo_from_a_subdirectory() {
+        let dir = fixture_repo("discover");
+        let sub_dir = Path::new(&dir).join("src/store");
+        fs::create_dir_all(&sub_dir).unwrap();
+        let (repo, relative) = Repo::discover(sub_dir.to_str().unwrap(), &[]).unwrap();
+        assert_eq!(relative, Path::new("src/store"));
+        assert_eq!(repo.commits().unwrap()[0].sha(), MASTER);
+
+        let (_, relative) = Repo::discover(&dir, &[]).unwrap();
+        assert_eq!(relative, Path::new(""));
+
+        let ceiling = Path::new(&dir).join("src");
+        let ceilings = [ceiling.to_str().unwrap()];
+        assert!(Repo::discover(sub_dir.to_str().unwrap(), &ceilings).is_err());
+    }
+
+    #[test]
+    fn discovering_a_repo_from_inside_its_git_dir() {
+        let dir = fixture_repo("discover-git-dir");
+        let start = Path::new(&dir).join(".git/refs/heads");
+        let (repo, relative) = Repo::discover(start.to_str().unwrap(), &[]).unwrap();
+        assert_eq!(relative, Path::new(".git/refs/heads"));
+        assert_eq!(repo.git_dir, git_dir_of(&dir));
+        assert_eq!(repo.dir(), dir);
+    }
+
+    #[test]
+    fn discovering_a_repo_through_a_gitdir_file() {
+        let dir = fixture_repo("gitdir");
+        let worktree = std::env::temp_dir().join("rs-git-lib-gitdir-worktree");
+        let _ = fs::remove_dir_all(&worktree);
+        fs::create_dir_all(worktree.join("docs")).unwrap();
+        let git_dir = Path::new(&dir).join(".git");
+        fs::write(
+            worktree.join(".git"),
+            format!("gitdir: {}\n", git_dir.display()),
+        )
+        .unwrap();
+
+        let start = worktree.join("docs");
+        let (repo, relative) = Repo::discover(start.to_str().unwrap(), &[]).unwrap();
+        assert_eq!(relative, Path::new("docs"));
+        assert_eq!(repo.commits().unwrap()[0].sha(), MASTER);
+    }
+
+    #[test]
+    fn initializing_a_repo() {
+        let dir = std::env::temp_dir().join("rs-git-lib-init");
+        let _ = fs::remove_dir_all(&dir);
+        let repo = Repo::init(dir.to_str().unwrap(), false, Some("main")).unwrap();
+        assert!(repo.refs.is_empty());
+        assert!(repo.commits().is_err());
+        let head = fs::read_to_string(dir.join(".git/HEAD")).unwrap();
+        assert_eq!(head, "ref: refs/heads/main\n");
+        let config = Config::open(dir.join(".git").to_str().unwrap()).unwrap();
+        assert_eq!(config.get("core", None, "bare"), Some("false"));
+
+        // Reinitializing keeps the existing repo
+        Repo::init(dir.to_str().unwrap(), false, None).unwrap();
+        let head = fs::read_to_string(dir.join(".git/HEAD")).unwrap();
+        assert_eq!(head, "ref: refs/heads/main\n");
+    }
+
+    #[test]
+    fn initializing_a_bare_repo() {
+        let dir = std::env::temp_dir().join("rs-git-lib-init-bare");
+        let _ = fs::remove_dir_all(&dir);
+        let repo = Repo::init(dir.to_str().unwrap(), true, None).unwrap();
+        assert_eq!(repo.git_dir, repo.dir);
+        assert!(!dir.join(".git").exists());
+        assert!(dir.join("refs/tags").is_dir());
+        let head = fs::read_to_string(dir.join("HEAD")).unwrap();
+        assert_eq!(head, "ref: refs/heads/master\n");
+        let config = Config::open(dir.to_str().unwrap()).unwrap();
+        assert_eq!(config.get("core", None, "bare"), Some("true"));
+    }
+
+    #[test]
+    fn commits_should_stop_at_shallow_boundaries() {
+        let dir = fixture_repo("shallow");
+        let git_dir = git_dir_of(&dir);
+        // Drop the initial commit from the repo
+        fs::remove_dir_all(Path::new(&git_dir).join("objects/pack")).unwrap();
+        let pack = PackFile::open(format!("{}.pack", PACK_FILE)).unwrap();
+        let tip = pack.find_by_sha(MASTER).unwrap().unwrap();
+        tip.write(&git_dir).unwrap();
+        assert!(Repo::open(&dir).unwrap().commits().is_err());
+
+        let shallow: HashSet<String> = vec![MASTER.to_string()].into_iter().collect();
+        write_shallow(&git_dir, &shallow).unwrap();
+        let repo = Repo::open(&dir).unwrap();
+        let commits = repo.commits().unwrap();
+        assert_eq!(commits.len(), 1);
+        assert_eq!(commits[0].sha(), MASTER);
+    }
+
+    #[test]
+    fn fetching_into_a_shallow_clone() {
+        if skip_without_git() {
+            return;
+        }
+        let source = temp_dir("shallow-source");
+        let git = |args: &[&str], input: &str| git(&source, args, input);
+        git(&["init", "-q"], "");
+        let one = git(&["hash-object", "-w", "--stdin"], "one\n");
+        let two = git(&["hash-object", "-w", "--stdin"], "two\n");
+        let tree_one = git(&["mktree"], &format!("100644 blob {}\ta.txt\n", one));
+        let tree_two = git(&["mktree"], &format!("100644 blob {}\ta.txt\n", two));
+        let first = git(&["commit-tree", &tree_one, "-m", "one"], "");
+        let second = git(&["commit-tree", &tree_two, "-p", &first, "-m", "two"], "");
+        git(&["update-ref", "refs/heads/master", &second], "");
+        git(&["symbolic-ref", "HEAD", "refs/heads/master"], "");
+
+        let dir = std::env::temp_dir().join("rs-git-lib-shallow-fetch");
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_str().unwrap().to_owned();
+        let url = format!("file://{}", source.to_str().unwrap());
+        let options = CloneOptions::new().bare(true).depth(1);
+        let mut repo = Repo::clone_with(&url, Some(dir), &options).unwrap();
+        assert!(repo.read_object(&first).is_err());
+
+        // The fork point is past the boundary, the server has to know the
+        // clone is shallow not to assume it has the parents of its tip
+        let side = git(&["commit-tree", &tree_one, "-p", &first, "-m", "side"], "");
+        git(&["update-ref", "refs/heads/side", &side], "");
+        repo.fetch("origin").unwrap();
+        assert!(repo.read_object(&side).is_ok());
+        assert!(repo.read_object(&first).is_ok());
+    }
+
+    #[test]
+    fn fetched_tags_are_packed_with_their_peeled_ref() {
+        if skip_without_git() {
+            return;
+        }
+        let source = fixture_repo("tags-source");
+        let dir = std::env::temp_dir().join("rs-git-lib-tags-fetch");
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.to_str().unwrap().to_owned();
+        let url = format!("file://{}", source);
+        let options = CloneOptions::new().no_checkout(true);
+        let mut repo = Repo::clone_with(&url, Some(dir.clone()), &options).unwrap();
+
+        let git = |args: &[&str]| git(Path::new(&source), args, "");
+        let tree = git(&["rev-parse", "master^{tree}"]);
+        let tip = git(&["commit-tree", &tree, "-p", MASTER, "-m", "tip"]);
+        git(&["update-ref", "refs/heads/master", &tip]);
+        git(&["tag", "-a", "v2", "-m", "v2", &tip]);
+        let tag = git(&["rev-parse", "v2"]);
+        repo.fetch("origin").unwrap();
+
+        let git_dir = Path::new(&dir).join(".git");
+        assert!(!git_dir.join("refs/tags/v2").exists());
+        let packed_refs = fs::read_to_string(git_dir.join("packed-refs")).unwrap();
+        assert!(packed_refs.starts_with("# pack-refs with: peeled fully-peeled sorted \n"));
+        assert!(packed_refs.contains(&format!("{} refs/tags/v2\n^{}\n", tag, tip)));
+        assert!(repo
+            .refs()
+            .iter()
+            .any(|r| r.name == "refs/tags/v2" && r.id == tag));
+    }
+
+    #[test]
+    fn fetched_refs_escaping_the_git_dir_are_rejected() {
+        if skip_without_git() {
+            return;
+        }
+        let source = fixture_repo("escape-source");
+        let dir = temp_dir("escape-fetch");
+        let work_tree = dir.join("clone").to_str().unwrap().to_owned();
+        let url = format!("file://{}", source);
+        let options = CloneOptions::new().no_checkout(true);
+        let mut repo = Repo::clone_with(&url, Some(work_tree.clone()), &options).unwrap();
+
+        let git_dir = Path::new(&work_tree).join(".git");
+        let mut config = Config::open(git_dir.to_str().unwrap()).unwrap();
+        config.set(
+            "remote",
+            Some("origin"),
+            "fetch",
+            "+refs/heads/*:refs/../../../*",
+        );
+        config.write().unwrap();
+        let result = repo.fetch("origin");
+        assert!(matches!(result, Err(Error::Ref(_))));
+        assert!(!dir.join("master").exists());
+    }
+
+    #[test]
+    fn fetching_honours_every_refspec_and_its_force_flag() {
+        if skip_without_git() {
+            return;
+        }
+        let source = fixture_repo("refspecs-source");
+        let dir = temp_dir("refspecs-fetch");
+        let work_tree = dir.join("clone").to_str().unwrap().to_owned();
+        let url = format!("file://{}", source);
+        let options = CloneOptions::new().no_checkout(true);
+        let mut repo = Repo::clone_with(&url, Some(work_tree.clone()), &options).unwrap();
+
+        let config_path = Path::new(&work_tree).join(".git/config");
+        let config = fs::read_to_string(&config_path).unwrap();
+        let refspec = "\tfetch = refs/heads/master:refs/heads/upstream\n";
+        let with_refspec = format!("{}[remote \"origin\"]\n{}", config, refspec);
+        fs::write(&config_path, &with_refspec).unwrap();
+        let updated = repo.fetch("origin").unwrap();
+        assert!(updated
+            .iter()
+            .any(|r| r.name == "refs/heads/upstream" && r.id == MASTER));
+
+        // Only the forced refspec takes a master that no longer fast-forwards
+        let git = |args: &[&str]| git(Path::new(&source), args, "");
+        let tree = git(&["rev-parse", "master^{tree}"]);
+        let unrelated = git(&["commit-tree", &tree, "-m", "unrelated"]);
+        git(&["update-ref", "refs/heads/master", &unrelated]);
+        let updated = repo.fetch("origin").unwrap();
+        let names: Vec<&str> = updated.iter().map(|r| &r.name[..]).collect();
+        assert_eq!(names, vec!["refs/remotes/origin/master"]);
+        let git_dir = Path::new(&work_tree).join(".git");
+        let id = |name: &str| resolve_ref(git_dir.to_str().unwrap(), name).unwrap();
+        assert_eq!(id("refs/remotes/origin/master"), unrelated);
+        assert_eq!(id("refs/heads/upstream"), MASTER);
+
+        let negative = format!("{}\tfetch = ^refs/heads/wip\n", with_refspec);
+        fs::write(&config_path, negative).unwrap();
+        let result = repo.fetch("origin");
+        assert!(matches!(result, Err(Error::Unsupported(_))));
+    }
+
+    #[test]
+    fn opening_a_missing_repo() {
+        let dir = std::env::temp_dir().join("rs-git-lib-missing");
+        let result = Repo::open(dir.to_str().unwrap());
+        assert!(matches!(result, Err(Error::RepositoryNotFound(_))));
+    }
+}
@@ -0,0 +1,211 @@
+use crate::cancel::CancellationToken;
+use crate::credentials::{CredentialCallback, CredentialProvider};
+use crate::progress::{Progress, ProgressCallback};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+///
+/// Options for `Repo::clone_with`
+///
+/// ```
+/// use rs_git_lib::CloneOptions;
+/// let options = CloneOptions::new()
+///     .remote_name("upstream")
+///     .no_checkout(true)
+///     .progress(|p| eprintln!("{:?}", p));
+/// ```
+///
+#[derive(Clone)]
+pub struct CloneOptions {
+    pub(crate) bare: bool,
+    pub(crate) mirror: bool,
+    pub(crate) branch: Option<String>,
+    pub(crate) remote_name: String,
+    pub(crate) no_checkout: bool,
+    pub(crate) depth: Option<usize>,
+    pub(crate) single_branch: bool,
+    pub(crate) progress: Option<ProgressCallback>,
+    pub(crate) credentials: Option<CredentialCallback>,
+    pub(crate) http: HttpOptions,
+    pub(crate) cancel: Option<CancellationToken>,
+}
+
+impl CloneOptions {
+    pub fn new() -> Self {
+        CloneOptions {
+            bare: false,
+            mirror: false,
+            branch: None,
+            remote_name: "origin".to_string(),
+            no_checkout: false,
+            depth: None,
+            single_branch: false,
+            progress: None,
+            credentials: None,
+            http: HttpOptions::new(),
+            cancel: None,
+        }
+    }
+
+    /// clone without working directory, objects and refs are written
+    /// directly in the target directory
+    pub fn bare(mut self, bare: bool) -> Self {
+        self.bare = bare;
+        self
+    }
+
+    /// clone as a bare repo copying every ref of the remote verbatim
+    pub fn mirror(mut self, mirror: bool) -> Self {
+        self.mirror = mirror;
+        self
+    }
+
+    /// the branch to check out instead of the remote's `HEAD`
+    pub fn branch(mut self, branch: &str) -> Self {
+        self.branch = Some(branch.to_string());
+        self
+    }
+
+    /// the name given to the remote, `origin` by default
+    pub fn remote_name(mut self, name: &str) -> Self {
+        self.remote_name = name.to_string();
+        self
+    }
+
+    /// skip the checkout of `HEAD` after the clone
+    pub fn no_checkout(mut self, no_checkout: bool) -> Self {
+        self.no_checkout = no_checkout;
+        self
+    }
+
+    /// fetch only the last `depth` commits of history
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.depth = Some(depth);
+        self
+    }
+
+    /// fetch only the history of the branch being checked out
+    pub fn single_branch(mut self, single_branch: bool) -> Self {
+        self.single_branch = single_branch;
+        self
+    }
+
+    /// a callback receiving the progress of the clone, kept by the cloned
+    /// repo for its fetches
+    pub fn progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&Progress) + Send + Sync + 'static,
+    {
+        self.progress = Some(Arc::new(callback));
+        self
+    }
+
+    /// the provider of credentials for servers requiring authentication,
+    /// kept by the cloned repo for its fetches and pushes
+    pub fn credentials<P>(mut self, provider: P) -> Self
+    where
+        P: CredentialProvider + Send + Sync + 'static,
+    {
+        self.credentials = Some(Arc::new(provider));
+        self
+    }
+
+    /// the settings of the http client, kept by the cloned repo for its
+    /// fetches and pushes
+    pub fn http(mut self, http: HttpOptions) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// a token aborting the clone when cancelled
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancel = Some(token);
+        self
+    }
+}
+
+impl Default for CloneOptions {
+    fn default() -> Self {
+        CloneOptions::new()
+    }
+}
+
+///
+/// Settings of the client used for http and https remotes
+///
+/// ```
+/// use rs_git_lib::{CloneOptions, HttpOptions};
+/// use std::time::Duration;
+/// let http = HttpOptions::new()
+///     .proxy("http://proxy.example.com:3128")
+///     .connect_timeout(Duration::from_secs(10))
+///     .header("X-Team", "tools");
+/// let options = CloneOptions::new().http(http);
+/// ```
+///
+#[derive(Debug, Clone, Default)]
+pub struct HttpOptions {
+    pub(crate) proxy: Option<String>,
+    pub(crate) ca_bundle: Option<PathBuf>,
+    pub(crate) insecure: bool,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) user_agent: Option<String>,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) max_redirects: Option<usize>,
+}
+
+impl HttpOptions {
+    pub fn new() -> Self {
+        HttpOptions::default()
+    }
+
+    /// the proxy every request goes through, like `http://proxy:3128`
+    pub fn proxy(mut self, url: &str) -> Self {
+        self.proxy = Some(url.to_string());
+        self
+    }
+
+    /// a PEM file with a certificate authority trusted besides the system ones
+    pub fn ca_bundle<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.ca_bundle = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// skip the verification of the server certificates
+    pub fn insecure(mut self, insecure: bool) -> Self {
+        self.insecure = insecure;
+        self
+    }
+
+    /// the time allowed to connect to the server
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// the time allowed to each request, 30 seconds by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// the `User-Agent` sent with the requests
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = Some(user_agent.to_string());
+        self
+    }
+
+    /// a header sent with every request, replacing any set before with that name
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// the number of redirects followed, 10 by default, 0 to follow none
+    pub fn max_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = Some(max_redirects);
+        self
+    }
+}
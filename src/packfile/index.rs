@@ -1,15 +1,17 @@
-use crate::store::object::GitObject;
+use crate::error::{Error, Result};
 use crate::utils::{sha1_hash, sha1_hash_hex};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use rustc_serialize::hex::{FromHex, ToHex};
 use std::fs::File;
-use std::io::{Error, ErrorKind, Read, Result as IOResult, Write};
+use std::io::{ErrorKind, Read, Write};
 use std::path::Path;
 
-type SHA = [u8; 20];
+type Sha = [u8; 20];
 
 static MAGIC: [u8; 4] = [255, 116, 79, 99];
 static VERSION: u32 = 2;
+/// Offsets with this bit set point into the table of 64 bit offsets
+const LARGE_OFFSET: u32 = 0x8000_0000;
 
 ///
 /// Version 2 of the Git Packfile Index containing separate
@@ -19,48 +21,57 @@ static VERSION: u32 = 2;
 ///
 pub struct PackIndex {
     fanout: [u32; 256],
-    offsets: Vec<u32>,
-    shas: Vec<SHA>,
+    offsets: Vec<u64>,
+    shas: Vec<Sha>,
     checksums: Vec<u32>,
     pack_sha: String,
 }
 
 impl PackIndex {
     #[allow(unused)]
-    pub fn open<P: AsRef<Path>>(path: P) -> IOResult<Option<Self>> {
-        use std::io::Error as IoError;
-        use std::io::ErrorKind;
-
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
         let mut file = match File::open(path) {
             Ok(file) => file,
-            Err(ref io_error @ IoError { .. }) if ErrorKind::NotFound == io_error.kind() => {
-                return Ok(None)
-            }
-            Err(io) => return Err(io),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
         };
         let mut contents = Vec::new();
         file.read_to_end(&mut contents)?;
         Self::parse(&contents).map(Some)
     }
 
-    #[allow(unused)]
-    fn parse(mut content: &[u8]) -> IOResult<Self> {
+    pub(crate) fn parse(mut content: &[u8]) -> Result<Self> {
+        // The header, the fanout table and the trailer
+        if content.len() < 8 + 256 * 4 + 40 {
+            return Err(Error::BadPack("truncated pack index".to_owned()));
+        }
         let checksum = sha1_hash_hex(&content[..content.len() - 20]);
 
         // Parse header
         let mut magic = [0; 4];
         content.read_exact(&mut magic)?;
-        assert_eq!(magic, MAGIC);
+        if magic != MAGIC {
+            return Err(Error::BadPack("not a pack index".to_owned()));
+        }
 
         let version = content.read_u32::<BigEndian>()?;
-        assert_eq!(version, VERSION);
+        if version != VERSION {
+            let err = format!("unsupported pack index version {}", version);
+            return Err(Error::BadPack(err));
+        }
 
         // Parse Fanout table
         let mut fanout = [0; 256];
         for f in fanout.iter_mut() {
             *f = content.read_u32::<BigEndian>()?;
         }
+        if fanout.windows(2).any(|w| w[0] > w[1]) {
+            return Err(Error::BadPack("bad fanout table".to_owned()));
+        }
         let size = fanout[255] as usize;
+        if content.len() < size * 28 + 40 {
+            return Err(Error::BadPack("truncated pack index".to_owned()));
+        }
 
         // Parse N Shas
         let mut shas = Vec::with_capacity(size);
@@ -78,10 +89,34 @@ impl PackIndex {
         }
 
         // Parse N Offsets
-        let mut offsets = Vec::with_capacity(size);
+        let mut small_offsets = Vec::with_capacity(size);
         for _ in 0..size {
             let off = content.read_u32::<BigEndian>()?;
-            offsets.push(off);
+            small_offsets.push(off);
+        }
+
+        // Parse the 64 bit offsets of packs over 2GB
+        let large = small_offsets
+            .iter()
+            .filter(|&&o| o & LARGE_OFFSET != 0)
+            .count();
+        let mut large_offsets = Vec::with_capacity(large);
+        for _ in 0..large {
+            let offset = content
+                .read_u64::<BigEndian>()
+                .map_err(|_| Error::BadPack("truncated pack index".to_owned()))?;
+            large_offsets.push(offset);
+        }
+        let mut offsets = Vec::with_capacity(size);
+        for off in small_offsets {
+            if off & LARGE_OFFSET == 0 {
+                offsets.push(off as u64);
+            } else {
+                let large = large_offsets
+                    .get((off & !LARGE_OFFSET) as usize)
+                    .ok_or_else(|| Error::BadPack("bad large offset".to_owned()))?;
+                offsets.push(*large);
+            }
         }
 
         // Parse trailer
@@ -91,7 +126,9 @@ impl PackIndex {
         let mut idx_sha = [0; 20];
         content.read_exact(&mut idx_sha)?;
 
-        assert_eq!(idx_sha.to_hex(), checksum);
+        if idx_sha.to_hex() != checksum {
+            return Err(Error::BadPack("bad pack index checksum".to_owned()));
+        }
 
         Ok(PackIndex {
             fanout,
@@ -102,40 +139,32 @@ impl PackIndex {
         })
     }
 
-    pub fn from_objects(
-        objects: &mut Vec<(usize, u32, GitObject)>,
-        pack_sha: &str,
-        dir: Option<&str>,
-    ) -> IOResult<Self> {
-        let size = objects.len();
+    ///
+    /// Builds the index of the pack entries given as their sha, offset and
+    /// the crc32 of their packed bytes.
+    ///
+    pub fn from_entries(entries: &mut [(Sha, usize, u32)], pack_sha: &str) -> Result<Self> {
+        let size = entries.len();
         let mut fanout = [0u32; 256];
         let mut offsets = vec![0; size];
         let mut shas = vec![[0; 20]; size];
         let mut checksums: Vec<u32> = vec![0; size];
 
-        // Sort the objects by SHA
-        objects.sort_by(|&(_, _, ref oa), &(_, _, ref ob)| oa.sha().cmp(&ob.sha()));
-
-        for (i, &(offset, crc, ref obj)) in objects.iter().enumerate() {
-            if let Some(path) = dir {
-                obj.write(path)?;
-            }
-            let mut sha = [0u8; 20];
-            let vsha = &obj.sha().from_hex().unwrap();
-            sha.clone_from_slice(&vsha);
+        // Sort the entries by Sha
+        entries.sort_by_key(|entry| entry.0);
 
-            // Checksum should be of packed content in the packfile.
+        for (i, &(sha, offset, crc)) in entries.iter().enumerate() {
             let fanout_start = sha[0] as usize;
             // By definition of the fanout table we need to increment every entry >= this sha
             for f in fanout.iter_mut().skip(fanout_start) {
                 *f += 1;
             }
             shas[i] = sha;
-            offsets[i] = offset as u32;
+            offsets[i] = offset as u64;
             checksums[i] = crc;
         }
         if size as u32 != fanout[255] {
-            return Err(Error::new(ErrorKind::Other, "bad fanout size"));
+            return Err(Error::BadPack("bad fanout size".to_owned()));
         }
         Ok(PackIndex {
             fanout,
@@ -147,28 +176,33 @@ impl PackIndex {
     }
 
     ///
-    /// Returns the offset in the packfile for the given SHA, if any.
+    /// Returns the offset in the packfile for the given Sha, if any.
     ///
-    #[allow(dead_code)]
     pub fn find(&self, sha: &[u8]) -> Option<usize> {
-        let fan = sha[0] as usize;
+        let fan = *sha.first()? as usize;
         let start = if fan > 0 {
             self.fanout[fan - 1] as usize
         } else {
             0
         };
         let end = self.fanout[fan] as usize;
-        self.shas[start..=end]
-            .binary_search_by(|ref s| s[..].cmp(sha))
+        self.shas[start..end]
+            .binary_search_by(|s| s[..].cmp(sha))
             .map(|i| self.offsets[i + start] as usize)
             .ok()
     }
 
+    ///
+    /// Returns the hex shas of the objects in the pack, in order.
+    ///
+    pub fn shas(&self) -> impl Iterator<Item = String> + '_ {
+        self.shas.iter().map(|sha| sha.to_hex())
+    }
+
     ///
     /// Encodes the index into binary format for writing.
     ///
-    #[allow(dead_code)]
-    pub fn encode(&self) -> IOResult<Vec<u8>> {
+    pub fn encode(&self) -> Result<Vec<u8>> {
         let size = self.shas.len();
         let total_size = (2 * 4) + 256 * 4 + size * 28;
         let mut buf: Vec<u8> = Vec::with_capacity(total_size);
@@ -185,11 +219,24 @@ impl PackIndex {
         for f in &self.checksums {
             buf.write_u32::<BigEndian>(*f)?;
         }
+        let mut large_offsets = Vec::new();
         for f in &self.offsets {
-            buf.write_u32::<BigEndian>(*f)?;
+            if *f < LARGE_OFFSET as u64 {
+                buf.write_u32::<BigEndian>(*f as u32)?;
+            } else {
+                buf.write_u32::<BigEndian>(LARGE_OFFSET | large_offsets.len() as u32)?;
+                large_offsets.push(*f);
+            }
+        }
+        for f in large_offsets {
+            buf.write_u64::<BigEndian>(f)?;
         }
 
-        buf.write_all(&self.pack_sha.from_hex().unwrap())?;
+        let pack_sha = self
+            .pack_sha
+            .from_hex()
+            .map_err(|_| Error::BadPack(format!("bad pack sha {}", self.pack_sha)))?;
+        buf.write_all(&pack_sha)?;
         let checksum = sha1_hash(&buf[..]);
         buf.write_all(&checksum)?;
 
@@ -201,17 +248,15 @@ impl PackIndex {
 mod tests {
     use super::*;
     use rustc_serialize::hex::{FromHex, ToHex};
-    use std::fs::File;
+    use std::fs::{self, File};
     use std::io::Read;
 
     use crate::packfile::PackFile;
 
-    static PACK_FILE: &'static str =
-        "tests/data/packs/pack-73e0a23f5ebfc74c7ea1940e2843a408ce1789d0.pack";
-    static IDX_FILE: &'static str =
-        "tests/data/packs/pack-73e0a23f5ebfc74c7ea1940e2843a408ce1789d0.idx";
+    static PACK_FILE: &str = "tests/data/packs/pack-73e0a23f5ebfc74c7ea1940e2843a408ce1789d0.pack";
+    static IDX_FILE: &str = "tests/data/packs/pack-73e0a23f5ebfc74c7ea1940e2843a408ce1789d0.idx";
 
-    static COMMIT: &'static str = "fb6fb3d9b81142566f4b2466857b0302617768de";
+    static COMMIT: &str = "fb6fb3d9b81142566f4b2466857b0302617768de";
 
     #[test]
     fn reading_an_index() {
@@ -221,21 +266,35 @@ mod tests {
         PackIndex::parse(&bytes[..]).unwrap();
     }
 
+    #[test]
+    fn malformed_indexes_are_errors() {
+        let bytes = fs::read(IDX_FILE).unwrap();
+        let is_bad_pack = |bytes: &[u8]| matches!(PackIndex::parse(bytes), Err(Error::BadPack(_)));
+        assert!(is_bad_pack(&bytes[..100]));
+        assert!(is_bad_pack(&bytes[..bytes.len() - 30]));
+        let mut corrupted = bytes.clone();
+        corrupted[0] = 0;
+        assert!(is_bad_pack(&corrupted));
+        let mut corrupted = bytes.clone();
+        corrupted[1200] ^= 0xff;
+        assert!(is_bad_pack(&corrupted));
+    }
+
     #[test]
     fn creating_an_index() {
         // Create an index from the associated packfile
         //
         // The packfile index when encoded should exactly
-        // match the one which was read when the Packfile::open
-        // call was made.
-        let pack = PackFile::open(PACK_FILE).unwrap();
-
-        let index = {
-            let mut bytes = Vec::new();
-            let mut file = File::open(IDX_FILE).unwrap();
-            file.read_to_end(&mut bytes).unwrap();
-            PackIndex::parse(&bytes[..]).unwrap()
-        };
+        // match the one git wrote for it, checksums included.
+        let mut contents = Vec::new();
+        let mut file = File::open(PACK_FILE).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        let pack = PackFile::from_contents(&contents).unwrap();
+
+        let mut bytes = Vec::new();
+        let mut file = File::open(IDX_FILE).unwrap();
+        file.read_to_end(&mut bytes).unwrap();
+        let index = PackIndex::parse(&bytes[..]).unwrap();
 
         let test_shas = pack
             .index
@@ -246,9 +305,21 @@ mod tests {
         let idx_shas = index.shas.iter().map(|s| s.to_hex()).collect::<Vec<_>>();
         assert_eq!(idx_shas.len(), test_shas.len());
         assert_eq!(idx_shas, test_shas);
-        let test_encoded = pack.index.encode().unwrap();
-        let idx_encoded = index.encode().unwrap();
-        assert_eq!(idx_encoded, test_encoded);
+        assert_eq!(pack.index.encode().unwrap(), bytes);
+    }
+
+    #[test]
+    fn large_offsets_should_round_trip() {
+        let mut entries = vec![
+            ([1; 20], 12, 1),
+            ([2; 20], 0x8000_0000, 2),
+            ([3; 20], 0x1_0000_0000, 3),
+        ];
+        let index = PackIndex::from_entries(&mut entries, &"ab".repeat(20)).unwrap();
+        let parsed = PackIndex::parse(&index.encode().unwrap()).unwrap();
+        assert_eq!(parsed.find(&[2; 20]), Some(0x8000_0000));
+        assert_eq!(parsed.find(&[3; 20]), Some(0x1_0000_0000));
+        assert_eq!(parsed.encode().unwrap(), index.encode().unwrap());
     }
 
     #[test]
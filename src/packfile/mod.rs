@@ -1,128 +1,576 @@
 pub mod index;
 pub mod packfile_parser;
 pub mod refs;
+use crate::error::{Error, Result};
 use crate::packfile::packfile_parser::PackFileParser;
-use crate::store::object::GitObject;
-use crate::utils::sha1_hash;
-use byteorder::{BigEndian, WriteBytesExt};
-use crc::crc32;
+use crate::store::object::{GitObject, GitObjectType};
+use crate::utils::create_temp_file;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use flate2::bufread::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
 use index::PackIndex;
-use nom::lib::std::collections::HashMap;
+use num_traits::cast::FromPrimitive;
+use rustc_serialize::hex::{FromHex, ToHex};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::fs;
 use std::fs::File;
-use std::io::{Read, Result as IOResult, Write};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
 pub const MAGIC_HEADER: u32 = 1_346_454_347; // "PACK"
 const HEADER_LENGTH: usize = 12; // Magic + Len + Version
+/// The prefix of the packs being received, named like git does
+const TEMP_PACK_PREFIX: &str = "tmp_pack_";
+/// The bytes of resolved delta bases kept in memory while reading a pack
+pub(crate) const DELTA_BASE_CACHE_SIZE: usize = 32 * 1024 * 1024;
+/// The longest chain of deltas read, the limit of git, past which the pack
+/// is taken as corrupt, like one whose deltas have themselves as bases
+const MAX_DELTA_DEPTH: usize = 4095;
 
+///
+/// A packfile stored on disk, whose objects are read through its index
+/// when asked for.
+///
 pub struct PackFile {
-    version: u32,
-    num_objects: usize,
-    encoded_objects: Vec<u8>,
     hexsha: String,
     pub index: PackIndex,
-    objects: HashMap<String, GitObject>,
-    //offset_objects: HashMap<usize, GitObject>,
+    reader: PackReader,
 }
 
 impl PackFile {
-    #[allow(dead_code)]
-    pub fn open<P: AsRef<Path>>(p: P) -> IOResult<Self> {
+    pub fn open<P: AsRef<Path>>(p: P) -> Result<Self> {
         let path = p.as_ref();
-        let mut contents = Vec::new();
-        let mut file = File::open(path)?;
-        file.read_to_end(&mut contents)?;
-
         let idx_path = path.with_extension("idx");
-        let idx = PackIndex::open(idx_path)?;
-        PackFile::parse_with_index(&contents, idx, None)
+        match PackIndex::open(idx_path)? {
+            Some(idx) => PackFile::with_index(path, idx, false),
+            // Without its index the pack is parsed like a received one
+            None => PackFile::from_contents(&fs::read(path)?),
+        }
     }
 
-    fn parse_with_index(
-        contents: &[u8],
-        idx: Option<PackIndex>,
-        dir: Option<&str>,
-    ) -> IOResult<Self> {
-        let mut parser = PackFileParser::from_contents(contents);
+    ///
+    /// Parses a packfile read in memory, storing it in a temporary file.
+    ///
+    pub(crate) fn from_contents(contents: &[u8]) -> Result<Self> {
+        let mut parser = PackFileParser::from_contents(contents)?;
         parser.slurp()?;
-        parser.parse(dir, idx)
+        parser.parse(None)
     }
 
-    pub fn write(&self, dir: &str) -> IOResult<()> {
-        let mut root = PathBuf::new();
-        root.push(dir);
-        root.push(".git");
-        self.write_to_path(&root)
+    ///
+    /// Opens the pack at `path` with its index. Temporary packs are removed
+    /// when dropped.
+    ///
+    pub(crate) fn with_index(path: &Path, index: PackIndex, temporary: bool) -> Result<Self> {
+        let reader = PackReader::open(path, temporary)?;
+        let hexsha = reader.read_checksum()?;
+        Ok(PackFile {
+            hexsha,
+            index,
+            reader,
+        })
     }
 
-    fn write_to_path(&self, root: &PathBuf) -> IOResult<()> {
-        let mut path = root.clone();
+    ///
+    /// Stores the pack and its index in the repository, moving the pack
+    /// when it is a temporary one.
+    ///
+    pub fn write(&mut self, git_dir: &str) -> Result<()> {
+        let mut path = PathBuf::new();
+        path.push(git_dir);
         path.push("objects/pack");
         fs::create_dir_all(&path)?;
         path.push(format!("pack-{}", self.sha()));
         path.set_extension("pack");
+        if path != self.reader.path {
+            if self.reader.temporary {
+                move_file(&self.reader.path, &path)?;
+                self.reader.path = path.clone();
+                self.reader.temporary = false;
+            } else {
+                fs::copy(&self.reader.path, &path)?;
+            }
+        }
 
-        let mut pack_file = File::create(&path)?;
-
-        let pack = self.encode()?;
-        pack_file.write_all(&pack)?;
+        path.set_extension("idx");
+        let mut idx_file = File::create(&path)?;
+        idx_file.write_all(&self.index.encode()?)?;
 
         Ok(())
     }
 
-    pub fn encode(&self) -> IOResult<Vec<u8>> {
-        let mut encoded = Vec::with_capacity(HEADER_LENGTH + self.encoded_objects.len());
-        encoded.write_u32::<BigEndian>(MAGIC_HEADER)?;
-        encoded.write_u32::<BigEndian>(self.version)?;
-        encoded.write_u32::<BigEndian>(self.num_objects as u32)?;
-        encoded.write_all(&self.encoded_objects[..])?;
-        let checksum = sha1_hash(&encoded);
-        encoded.write_all(&checksum[..])?;
-        Ok(encoded)
+    ///
+    /// Returns the pack as stored on disk.
+    ///
+    #[allow(dead_code)]
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        Ok(fs::read(&self.reader.path)?)
     }
 
     pub fn sha(&self) -> &str {
         &self.hexsha
     }
 
-    pub fn find_by_sha(&self, sha: &str) -> IOResult<Option<GitObject>> {
-        Ok(self.objects.get(sha).cloned())
+    pub fn find_by_sha(&self, sha: &str) -> Result<Option<GitObject>> {
+        let sha = match sha.from_hex() {
+            Ok(ref sha) if sha.len() == 20 => sha.clone(),
+            _ => return Ok(None),
+        };
+        match self.index.find(&sha) {
+            Some(offset) => {
+                let object = self.reader.read_object(offset, &|base: &str| {
+                    let offset = base.from_hex().ok().and_then(|sha| self.index.find(&sha));
+                    Ok(offset.map(DeltaBase::Entry))
+                })?;
+                Ok(Some(object))
+            }
+            None => Ok(None),
+        }
+    }
+
+    ///
+    /// Returns the shas of all the objects stored in the packfile.
+    ///
+    pub fn shas(&self) -> impl Iterator<Item = String> + '_ {
+        self.index.shas()
     }
 }
 
-#[derive(Debug)]
-pub enum PackObject {
-    Base(GitObject),
-    OfsDelta(usize, Vec<u8>),
-    RefDelta([u8; 20], Vec<u8>),
+///
+/// The kind of a pack entry, as told by its header.
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind {
+    Base(GitObjectType),
+    /// a delta on the entry at the given offset of the pack
+    OfsDelta(usize),
+    /// a delta on the object with the given sha
+    RefDelta([u8; 20]),
+}
+
+///
+/// The base of a ref delta, as found by its sha.
+///
+pub(crate) enum DeltaBase {
+    /// the entry at the given offset of the same pack, read through the
+    /// cache of bases
+    Entry(usize),
+    /// an object out of the pack
+    Object(GitObject),
 }
 
-impl PackObject {
-    pub fn crc32(&self) -> u32 {
-        let content = match *self {
-            PackObject::Base(ref b) => &b.content[..][..],
-            PackObject::RefDelta(_, ref c) => &c[..],
-            PackObject::OfsDelta(_, ref c) => &c[..],
+///
+/// Parses the header of the entry at `offset` of a pack, from its type and
+/// size up to its delta base, if any. Returns the kind of the entry, the
+/// size of its inflated content and the length of the header, or `None`
+/// when `data` doesn't hold the whole header.
+///
+pub(crate) fn parse_entry_header(
+    data: &[u8],
+    offset: usize,
+) -> Result<Option<(EntryKind, usize, usize)>> {
+    let mut pos = 0;
+    let mut next_byte = || {
+        let byte = data.get(pos).copied();
+        pos += 1;
+        byte
+    };
+    let mut c = match next_byte() {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+    let type_id = (c >> 4) & 7;
+    let mut size: usize = (c & 0x0F) as usize;
+    let mut shift: usize = 4;
+    // Parse the variable length size header for the object.
+    // Read the MSB and check if we need to continue
+    // consuming bytes to get the object size
+    while (c & 0x80) > 0 {
+        c = match next_byte() {
+            Some(c) => c,
+            None => return Ok(None),
         };
-        crc32::checksum_ieee(content)
+        size += ((c & 0x7f) as usize)
+            .checked_shl(shift as u32)
+            .ok_or_else(|| bad_entry("size too large", offset))?;
+        shift += 7;
+    }
+    let kind = match GitObjectType::from_u8(type_id) {
+        Some(object_type) => EntryKind::Base(object_type),
+        None => match type_id {
+            6 => {
+                let mut c = match next_byte() {
+                    Some(c) => c,
+                    None => return Ok(None),
+                };
+                let mut base_offset = (c & 0x7f) as usize;
+                while c & 0x80 != 0 {
+                    c = match next_byte() {
+                        Some(c) => c,
+                        None => return Ok(None),
+                    };
+                    base_offset = base_offset
+                        .checked_add(1)
+                        .and_then(|o| o.checked_mul(1 << 7))
+                        .ok_or_else(|| bad_entry("bad delta base offset", offset))?;
+                    base_offset += (c & 0x7f) as usize;
+                }
+                if base_offset == 0 || base_offset > offset {
+                    return Err(bad_entry("bad delta base offset", offset));
+                }
+                EntryKind::OfsDelta(offset - base_offset)
+            }
+            7 => {
+                let base = match data.get(pos..pos + 20) {
+                    Some(base) => base,
+                    None => return Ok(None),
+                };
+                let mut sha = [0; 20];
+                sha.copy_from_slice(base);
+                pos += 20;
+                EntryKind::RefDelta(sha)
+            }
+            _ => {
+                let err = format!("unexpected object type {}", type_id);
+                return Err(bad_entry(&err, offset));
+            }
+        },
+    };
+    Ok(Some((kind, size, pos)))
+}
+
+fn bad_entry(reason: &str, offset: usize) -> Error {
+    Error::BadPack(format!("{} in the entry at {}", reason, offset))
+}
+
+///
+/// Reads the entries of a pack stored on disk, resolving their deltas. The
+/// delta bases read are cached up to `DELTA_BASE_CACHE_SIZE` bytes.
+///
+pub(crate) struct PackReader {
+    path: PathBuf,
+    file: RefCell<File>,
+    cache: RefCell<(HashMap<usize, GitObject>, usize)>,
+    /// whether the file is removed with the reader
+    temporary: bool,
+}
+
+impl PackReader {
+    pub(crate) fn open(path: &Path, temporary: bool) -> Result<Self> {
+        Ok(PackReader {
+            path: path.to_path_buf(),
+            file: RefCell::new(File::open(path)?),
+            cache: RefCell::new((HashMap::new(), 0)),
+            temporary,
+        })
+    }
+
+    ///
+    /// Reads the trailing checksum of the pack, naming it.
+    ///
+    fn read_checksum(&self) -> Result<String> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(0))?;
+        let magic = file.read_u32::<BigEndian>()?;
+        if magic != MAGIC_HEADER {
+            return Err(Error::BadPack("magic header not found".to_owned()));
+        }
+        file.seek(SeekFrom::End(-20))?;
+        let mut checksum = [0; 20];
+        file.read_exact(&mut checksum)?;
+        Ok(checksum.to_hex())
+    }
+
+    ///
+    /// Reads the kind and the inflated content of the entry at `offset`.
+    ///
+    fn read_entry(&self, offset: usize) -> Result<(EntryKind, Vec<u8>)> {
+        let mut file = self.file.borrow_mut();
+        file.seek(SeekFrom::Start(offset as u64))?;
+        // The header is at most 10 bytes of size and 20 of delta base
+        let mut header = [0; 32];
+        let mut read = 0;
+        while read < header.len() {
+            match file.read(&mut header[read..])? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        let (kind, size, consumed) = parse_entry_header(&header[..read], offset)?
+            .ok_or_else(|| bad_entry("truncated header", offset))?;
+        file.seek(SeekFrom::Start((offset + consumed) as u64))?;
+        let reader = BufReader::new(&mut *file);
+        // The size comes from the pack, it isn't trusted for allocating
+        let mut content = Vec::with_capacity(size.min(DELTA_BASE_CACHE_SIZE));
+        // One byte more than the size is enough to tell a mismatch
+        ZlibDecoder::new(reader)
+            .take(size as u64 + 1)
+            .read_to_end(&mut content)
+            .map_err(|_| bad_entry("bad compressed data", offset))?;
+        if content.len() != size {
+            return Err(bad_entry("size mismatch", offset));
+        }
+        Ok((kind, content))
+    }
+
+    ///
+    /// Reads the object of the entry at `offset`, asking `find_base` for the
+    /// bases of ref deltas. The chain of deltas is walked down to its base,
+    /// up to `MAX_DELTA_DEPTH` deltas, then patched back up.
+    ///
+    pub(crate) fn read_object<F>(&self, offset: usize, find_base: &F) -> Result<GitObject>
+    where
+        F: Fn(&str) -> Result<Option<DeltaBase>>,
+    {
+        let mut deltas = Vec::new();
+        let mut entry = offset;
+        let mut base = loop {
+            if deltas.len() > MAX_DELTA_DEPTH {
+                return Err(bad_entry("delta chain too deep", offset));
+            }
+            if !deltas.is_empty() {
+                if let Some(base) = self.cache.borrow().0.get(&entry) {
+                    break base.clone();
+                }
+            }
+            let (kind, content) = self.read_entry(entry)?;
+            let base = match kind {
+                EntryKind::Base(object_type) => {
+                    let base = GitObject::new(object_type, content);
+                    if !deltas.is_empty() {
+                        self.cache_base(entry, &base);
+                    }
+                    break base;
+                }
+                EntryKind::OfsDelta(base) => base,
+                EntryKind::RefDelta(base) => {
+                    let base = base.to_hex();
+                    match find_base(&base)?.ok_or(Error::MissingObject(base))? {
+                        DeltaBase::Entry(base) => base,
+                        DeltaBase::Object(base) => {
+                            deltas.push((entry, content));
+                            break base;
+                        }
+                    }
+                }
+            };
+            deltas.push((entry, content));
+            entry = base;
+        };
+        while let Some((entry, delta)) = deltas.pop() {
+            base = base.patch(&delta)?;
+            if !deltas.is_empty() {
+                self.cache_base(entry, &base);
+            }
+        }
+        Ok(base)
+    }
+
+    fn cache_base(&self, offset: usize, base: &GitObject) {
+        let (ref mut cache, ref mut cached_bytes) = *self.cache.borrow_mut();
+        if *cached_bytes + base.content.len() > DELTA_BASE_CACHE_SIZE {
+            cache.clear();
+            *cached_bytes = 0;
+        }
+        if base.content.len() <= DELTA_BASE_CACHE_SIZE {
+            *cached_bytes += base.content.len();
+            cache.insert(offset, base.clone());
+        }
     }
 }
 
+impl Drop for PackReader {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+///
+/// Moves a file, copying it when it is on another file system.
+///
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+///
+/// The directory the packs being received are written to: `objects/pack`
+/// of the repository, like git's `tmp_pack_*` files, or else the temporary
+/// directory of the system.
+///
+pub(crate) fn temp_pack_dir(git_dir: Option<&str>) -> PathBuf {
+    match git_dir {
+        Some(git_dir) => Path::new(git_dir).join("objects/pack"),
+        None => std::env::temp_dir(),
+    }
+}
+
+///
+/// Writes a pack to a temporary file one object at a time, its number of
+/// objects being written once they are all added. The file is removed when
+/// the writer is dropped before finishing it.
+///
+pub(crate) struct PackWriter {
+    path: Option<PathBuf>,
+    writer: BufWriter<File>,
+    num_objects: usize,
+}
+
+impl PackWriter {
+    pub fn create(git_dir: Option<&str>) -> Result<Self> {
+        let (path, file) = create_temp_file(&temp_pack_dir(git_dir), TEMP_PACK_PREFIX)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_u32::<BigEndian>(MAGIC_HEADER)?;
+        writer.write_u32::<BigEndian>(2)?;
+        writer.write_u32::<BigEndian>(0)?;
+        Ok(PackWriter {
+            path: Some(path),
+            writer,
+            num_objects: 0,
+        })
+    }
+
+    pub fn add(&mut self, object: &GitObject) -> Result<()> {
+        self.writer.write_all(&encode_object(object)?)?;
+        self.num_objects += 1;
+        Ok(())
+    }
+
+    ///
+    /// Completes the pack with its number of objects and its checksum,
+    /// returning its path.
+    ///
+    pub fn finish(mut self) -> Result<PathBuf> {
+        self.writer.flush()?;
+        seal_pack(self.writer.get_mut(), self.num_objects)?;
+        Ok(self.path.take().unwrap())
+    }
+}
+
+impl Drop for PackWriter {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+///
+/// Writes the number of objects in the header of the pack, then appends
+/// the checksum of the whole file, which it returns.
+///
+pub(crate) fn seal_pack(file: &mut File, num_objects: usize) -> Result<String> {
+    file.seek(SeekFrom::Start(8))?;
+    file.write_u32::<BigEndian>(num_objects as u32)?;
+    let mut hasher = Sha1::new();
+    let mut buffer = vec![0; 64 * 1024];
+    file.seek(SeekFrom::Start(0))?;
+    loop {
+        match file.read(&mut buffer)? {
+            0 => break,
+            n => hasher.input(&buffer[..n]),
+        }
+    }
+    let mut checksum = [0; 20];
+    hasher.result(&mut checksum);
+    file.write_all(&checksum)?;
+    Ok(checksum.to_hex())
+}
+
+///
+/// Opens every `.pack`/`.idx` pair stored under `objects/pack`.
+///
+pub(crate) fn open_packs(git_dir: &str) -> Result<Vec<PackFile>> {
+    let mut path = PathBuf::new();
+    path.push(git_dir);
+    path.push("objects/pack");
+    if !path.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut packs = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let pack_path = entry?.path();
+        if pack_path.extension() == Some(OsStr::new("pack"))
+            && pack_path.with_extension("idx").is_file()
+        {
+            packs.push(PackFile::open(&pack_path)?);
+        }
+    }
+    Ok(packs)
+}
+
+#[cfg(test)]
+fn encode_pack(version: u32, num_objects: usize, encoded_objects: &[u8]) -> Result<Vec<u8>> {
+    let mut encoded = Vec::with_capacity(HEADER_LENGTH + encoded_objects.len() + 20);
+    encoded.write_u32::<BigEndian>(MAGIC_HEADER)?;
+    encoded.write_u32::<BigEndian>(version)?;
+    encoded.write_u32::<BigEndian>(num_objects as u32)?;
+    encoded.write_all(encoded_objects)?;
+    let checksum = crate::utils::sha1_hash(&encoded);
+    encoded.write_all(&checksum[..])?;
+    Ok(encoded)
+}
+
+///
+/// Builds in memory a version 2 pack holding the given objects undeltified.
+///
+#[cfg(test)]
+pub(crate) fn build_pack(objects: &[GitObject]) -> Result<Vec<u8>> {
+    let mut encoded_objects = Vec::new();
+    for object in objects {
+        encoded_objects.extend(encode_object(object)?);
+    }
+    encode_pack(2, objects.len(), &encoded_objects)
+}
+
+///
+/// Encodes an object as an undeltified packfile entry: the type and size
+/// header followed by the zlib compressed content.
+///
+pub(crate) fn encode_object(object: &GitObject) -> Result<Vec<u8>> {
+    let mut size = object.content.len();
+    let mut c = ((object.object_type as u8) << 4) | (size & 0x0f) as u8;
+    size >>= 4;
+    let mut encoded = Vec::new();
+    while size > 0 {
+        encoded.push(c | 0x80);
+        c = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    encoded.push(c);
+    let mut z = ZlibEncoder::new(encoded, Compression::default());
+    z.write_all(&object.content)?;
+    Ok(z.finish()?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rustc_serialize::hex::ToHex;
     use std::fs::File;
     use std::io::Read;
 
-    static PACK_FILE: &'static str =
-        "tests/data/packs/pack-79f006bb5e8d079fdbe07e7ce41f97f4db7d341c.pack";
+    static PACK_FILE: &str = "tests/data/packs/pack-79f006bb5e8d079fdbe07e7ce41f97f4db7d341c.pack";
+
+    static THIN_PACK_FILE: &str =
+        "tests/data/packs/thin-b5c1f15a0d9b1ea4fd49b945c52bb7bc37c121ae.pack";
+    static THIN_TIP: &str = "33676d1c63d868803ed110b13be4e616bc8a29b7";
 
-    static BASE_SHA: &'static str = "7e690abcc93718dbf26ddea5c6ede644a63a5b34";
+    static BASE_SHA: &str = "7e690abcc93718dbf26ddea5c6ede644a63a5b34";
     // We need to test reading an object with a non-trivial delta
     // chain (4).
-    static DELTA_SHA: &'static str = "9b104dc31028e46f2f7d0b8a29989ab9a5155d41";
-    static DELTA_CONTENT: &'static str =
+    static DELTA_SHA: &str = "9b104dc31028e46f2f7d0b8a29989ab9a5155d41";
+    static DELTA_CONTENT: &str =
         "This is a test repo, used for testing the capabilities of the rgit tool. \
         rgit is a implementation of\n\
         the Git version control tool written in Rust.\n\n\
@@ -157,6 +605,136 @@ mod tests {
         pack.find_by_sha(DELTA_SHA).unwrap().unwrap();
     }
 
+    #[test]
+    fn encoded_objects_should_parse_back() {
+        let pack = read_pack();
+        let object = pack.find_by_sha(DELTA_SHA).unwrap().unwrap();
+        let contents = build_pack(std::slice::from_ref(&object)).unwrap();
+        let mut parser = PackFileParser::from_contents(&contents).unwrap();
+        parser.slurp().unwrap();
+        let parsed = parser.parse(None).unwrap();
+        let found = parsed.find_by_sha(DELTA_SHA).unwrap().unwrap();
+        assert_eq!(found.content, object.content);
+    }
+
+    #[test]
+    fn parsing_a_thin_pack_should_complete_it() {
+        let base_pack = read_pack();
+        let mut contents = Vec::new();
+        let mut file = File::open(THIN_PACK_FILE).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+
+        let mut parser = PackFileParser::from_contents(&contents).unwrap();
+        parser.slurp().unwrap();
+        assert!(parser.parse(None).is_err());
+
+        let mut parser = PackFileParser::from_contents(&contents).unwrap();
+        parser.slurp().unwrap();
+        let lookups = std::cell::RefCell::new(Vec::new());
+        let thin = parser
+            .parse_thin(None, |sha| {
+                lookups.borrow_mut().push(sha.to_owned());
+                base_pack.find_by_sha(sha).unwrap()
+            })
+            .unwrap();
+        assert_eq!(thin.shas().count(), 10);
+        // Each missing base is looked up once
+        let mut lookups = lookups.into_inner();
+        let count = lookups.len();
+        lookups.sort();
+        lookups.dedup();
+        assert_eq!(lookups.len(), count);
+        assert!(thin.find_by_sha(THIN_TIP).unwrap().is_some());
+
+        let encoded = thin.encode().unwrap();
+        assert_eq!(&encoded[encoded.len() - 20..].to_hex(), thin.sha());
+        let mut parser = PackFileParser::from_contents(&encoded).unwrap();
+        parser.slurp().unwrap();
+        let completed = parser.parse(None).unwrap();
+        assert_eq!(completed.shas().count(), 10);
+    }
+
+    #[test]
+    fn packs_are_parsed_as_they_are_received() {
+        let mut contents = Vec::new();
+        let mut file = File::open(PACK_FILE).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+
+        let mut parser = PackFileParser::new();
+        for chunk in contents.chunks(7) {
+            let mut line = vec![1];
+            line.extend_from_slice(chunk);
+            parser.add_line(&line).unwrap();
+        }
+        parser.process_pending_lines().unwrap();
+        let pack = parser.parse(None).unwrap();
+        let path = pack.reader.path.clone();
+        assert_eq!(pack.encode().unwrap(), contents);
+        assert_eq!(
+            pack.index.encode().unwrap(),
+            read_pack().index.encode().unwrap()
+        );
+        let delta = pack.find_by_sha(DELTA_SHA).unwrap().unwrap();
+        assert_eq!(&delta.content[..], DELTA_CONTENT.as_bytes());
+
+        // The temporary pack goes away with it
+        assert!(path.is_file());
+        drop(pack);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn packs_are_received_in_the_repository() {
+        let git_dir = crate::fixtures::temp_git_dir("received-pack");
+        let pack_dir = Path::new(&git_dir).join("objects/pack");
+        let temp_packs = || {
+            fs::read_dir(&pack_dir)
+                .unwrap()
+                .filter(|e| {
+                    let name = e.as_ref().unwrap().file_name();
+                    name.to_str().unwrap().starts_with(TEMP_PACK_PREFIX)
+                })
+                .count()
+        };
+        let contents = fs::read(PACK_FILE).unwrap();
+        let mut parser = PackFileParser::new();
+        parser.set_git_dir(Some(&git_dir));
+        parser.receive_from(&contents[..]).unwrap();
+        assert_eq!(temp_packs(), 1);
+        let pack = parser.parse(Some(&git_dir)).unwrap();
+        assert_eq!(temp_packs(), 0);
+        assert!(pack_dir.join(format!("pack-{}.pack", pack.sha())).is_file());
+
+        // Objects written one at a time parse back the same
+        let object = pack.find_by_sha(DELTA_SHA).unwrap().unwrap();
+        let mut writer = PackWriter::create(Some(&git_dir)).unwrap();
+        writer.add(&object).unwrap();
+        let mut parser = PackFileParser::new();
+        parser.receive_file(writer.finish().unwrap()).unwrap();
+        let written = parser.parse(None).unwrap();
+        assert_eq!(
+            written.find_by_sha(DELTA_SHA).unwrap().unwrap().content,
+            object.content
+        );
+        drop(written);
+        assert_eq!(temp_packs(), 0);
+    }
+
+    #[test]
+    fn truncated_or_corrupted_packs_are_rejected() {
+        let mut contents = Vec::new();
+        let mut file = File::open(PACK_FILE).unwrap();
+        file.read_to_end(&mut contents).unwrap();
+
+        let mut parser = PackFileParser::from_contents(&contents[..contents.len() - 30]).unwrap();
+        assert!(parser.slurp().is_err());
+        assert!(parser.parse(None).is_err());
+
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        assert!(PackFileParser::from_contents(&contents).is_err());
+    }
+
     #[test]
     fn reading_delta_objects_should_resolve_them_correctly() {
         use std::str;
@@ -165,4 +743,41 @@ mod tests {
         let content = str::from_utf8(&delta.content[..]).unwrap();
         assert_eq!(content, DELTA_CONTENT);
     }
+
+    ///
+    /// Opens a pack holding the single entry given, indexed under `sha`.
+    ///
+    fn pack_of_entry(header: Vec<u8>, content: &[u8], sha: [u8; 20]) -> PackFile {
+        let mut z = ZlibEncoder::new(header, Compression::default());
+        z.write_all(content).unwrap();
+        let contents = encode_pack(2, 1, &z.finish().unwrap()).unwrap();
+        let (path, mut file) = create_temp_file(&temp_pack_dir(None), TEMP_PACK_PREFIX).unwrap();
+        file.write_all(&contents).unwrap();
+        let index = PackIndex::from_entries(&mut [(sha, HEADER_LENGTH, 0)], "").unwrap();
+        PackFile::with_index(&path, index, true).unwrap()
+    }
+
+    #[test]
+    fn self_referencing_deltas_are_rejected() {
+        let sha = [0x42; 20];
+        // A ref delta, of an empty delta, whose base is itself
+        let mut header = vec![(7 << 4) | 2];
+        header.extend(&sha);
+        let pack = pack_of_entry(header, &[0, 0], sha);
+        match pack.find_by_sha(&sha.to_hex()) {
+            Err(Error::BadPack(_)) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn entries_larger_than_their_size_are_rejected() {
+        let sha = [0x42; 20];
+        // A blob of 2 bytes holding much more
+        let pack = pack_of_entry(vec![(3 << 4) | 2], &vec![0; 1024 * 1024], sha);
+        match pack.find_by_sha(&sha.to_hex()) {
+            Err(Error::BadPack(_)) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
 }
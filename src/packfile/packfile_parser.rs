@@ -1,33 +1,94 @@
-use super::{PackFile, PackObject};
+use super::{
+    encode_object, parse_entry_header, seal_pack, temp_pack_dir, DeltaBase, EntryKind, PackFile,
+    PackReader, HEADER_LENGTH, TEMP_PACK_PREFIX,
+};
+use crate::cancel::{check, CancellationToken};
+use crate::error::{Error, Result};
 use crate::packfile::index::PackIndex;
-use crate::packfile::HEADER_LENGTH;
-use crate::store::object::{GitObject, GitObjectType};
-use crate::utils::sha1_hash_hex;
+use crate::progress::{Progress, ProgressCallback};
+use crate::store::object::GitObject;
+use crate::transport::client::remote_error;
+use crate::utils::create_temp_file;
 use byteorder::{BigEndian, ReadBytesExt};
+use crc::crc32;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
 use flate2::{Decompress, FlushDecompress, Status};
-use nom::lib::std::collections::HashMap;
-use num_traits::cast::FromPrimitive;
-use rustc_serialize::hex::ToHex;
-use std::io::{Error, ErrorKind, Read, Result as IOResult};
+use rustc_serialize::hex::{FromHex, ToHex};
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+/// The size of the buffer the entries are inflated to
+const INFLATE_BUFFER_SIZE: usize = 64 * 1024;
+/// The bytes of the pack read at once from a reader
+const READ_SIZE: usize = 64 * 1024;
 
-#[derive(Debug, PartialEq)]
 enum ParseState {
     Init,
-    ParseEntryHeader(usize),
-    ParseEntryBody(u8, usize, usize, usize),
-    ParseCheckSum(usize),
+    ParseEntryHeader,
+    ParseEntryBody(EntryBody),
+    ParseCheckSum,
     End,
 }
 
-pub struct PackFileParser {
-    packfile_data: Vec<u8>,
-    lines: usize,
+///
+/// The entry being inflated. Only the sha of base objects is computed, their
+/// content and the one of deltas are dropped as they are inflated.
+///
+struct EntryBody {
+    kind: EntryKind,
     size: usize,
+    decompressor: Decompress,
+    hasher: Option<Sha1>,
+}
+
+///
+/// An entry of the pack, with the crc32 of its packed bytes and the sha of
+/// its object when it isn't a delta.
+///
+struct Entry {
+    offset: usize,
+    crc: u32,
+    kind: EntryKind,
+    sha: Option<[u8; 20]>,
+}
+
+///
+/// Parses a packfile as it is received, writing it to a temporary file and
+/// keeping in memory only the entries not parsed yet and the index of the
+/// ones already parsed. The deltas are resolved from the file once the pack
+/// is complete.
+///
+pub struct PackFileParser {
+    /// the directory the pack is written to as it is received
+    dir: PathBuf,
+    path: Option<PathBuf>,
+    writer: Option<BufWriter<File>>,
+    /// the bytes received not parsed yet, after the first `parsed` ones
+    pending: Vec<u8>,
+    /// the pending bytes already parsed, dropped as more bytes are received
+    parsed: usize,
+    /// the offset in the pack of the first pending byte not parsed
+    offset: usize,
+    /// the buffer the entries are inflated to
+    inflated: Vec<u8>,
+    received: usize,
+    /// the sha1 of the bytes parsed, to check the trailing checksum
+    hasher: Sha1,
+    /// the crc32 of the bytes parsed of the current entry
+    crc: u32,
+    entry_offset: usize,
     version: u32,
     entries: usize,
     state: ParseState,
     checksum: [u8; 20],
-    objects: Vec<(usize, u32, PackObject)>,
+    objects: Vec<Entry>,
+    progress: Option<ProgressCallback>,
+    cancel: Option<CancellationToken>,
+    shallow: Vec<String>,
 }
 
 const MAGIC_HEADER: u32 = 1_346_454_347; // "PACK"
@@ -36,303 +97,522 @@ const GIT_VERSION: u32 = 2;
 impl PackFileParser {
     pub fn new() -> Self {
         PackFileParser {
-            packfile_data: Vec::new(),
-            lines: 0,
-            size: 0,
+            dir: temp_pack_dir(None),
+            path: None,
+            writer: None,
+            pending: Vec::new(),
+            parsed: 0,
+            offset: 0,
+            inflated: vec![0; INFLATE_BUFFER_SIZE],
+            received: 0,
+            hasher: Sha1::new(),
+            crc: 0,
+            entry_offset: 0,
             version: 0,
             entries: 0,
             state: ParseState::Init,
             checksum: [0; 20],
             objects: vec![],
+            progress: None,
+            cancel: None,
+            shallow: vec![],
         }
     }
 
-    pub fn from_contents(contents: &[u8]) -> Self {
-        PackFileParser {
-            packfile_data: contents.to_vec(),
-            lines: 0,
-            size: contents.len(),
-            version: 0,
-            entries: 0,
-            state: ParseState::Init,
-            checksum: [0; 20],
-            objects: vec![],
-        }
+    pub fn from_contents(contents: &[u8]) -> Result<Self> {
+        let mut parser = PackFileParser::new();
+        parser.receive(contents)?;
+        Ok(parser)
+    }
+
+    pub fn parse(&mut self, dir: Option<&str>) -> Result<PackFile> {
+        self.parse_thin(dir, |_| None)
     }
 
-    pub fn parse(&mut self, dir: Option<&str>, index_opt: Option<PackIndex>) -> IOResult<PackFile> {
-        let sha_computed = sha1_hash_hex(&self.checksum);
-        let mut base_objects: HashMap<String, GitObject> = HashMap::new();
-        let mut base_offsets: HashMap<usize, String> = HashMap::new();
-        let mut objects: Vec<(usize, u32, GitObject)> = self
-            .objects
-            .iter()
-            .filter_map(|o| match o {
-                (offset, checksum, PackObject::Base(obj)) => {
-                    let sha = obj.sha();
-                    base_offsets.insert(*offset, sha.clone());
-                    base_objects.insert(sha, obj.clone());
-                    Some((*offset, *checksum, obj.clone()))
+    ///
+    /// Resolves the deltas of the pack and indexes it. A thin pack, whose
+    /// deltas may refer to base objects that are not in the pack, is
+    /// completed with these bases, looked up with `find_base`. The pack is
+    /// moved to the repository at `dir`, or else kept in a temporary file
+    /// removed with the returned pack.
+    ///
+    pub fn parse_thin<F>(&mut self, dir: Option<&str>, find_base: F) -> Result<PackFile>
+    where
+        F: Fn(&str) -> Option<GitObject>,
+    {
+        if !self.eof() {
+            return Err(Error::Protocol("packfile not received".to_owned()));
+        }
+        if let Some(writer) = self.writer.take() {
+            writer.into_inner().map_err(|e| e.into_error())?;
+        }
+        let path = self
+            .path
+            .clone()
+            .ok_or_else(|| Error::BadPack("packfile already parsed".to_owned()))?;
+
+        let mut offsets: HashMap<String, usize> = HashMap::new();
+        let mut entries: Vec<([u8; 20], usize, u32)> = Vec::new();
+        let mut deltas: Vec<&Entry> = Vec::new();
+        for entry in self.objects.iter() {
+            match entry.sha {
+                Some(sha) => {
+                    offsets.insert(sha.to_hex(), entry.offset);
+                    entries.push((sha, entry.offset, entry.crc));
+                }
+                None => deltas.push(entry),
+            }
+        }
+
+        let mut thin_bases: HashMap<String, GitObject> = HashMap::new();
+        let total_deltas = deltas.len();
+        let mut resolved = 0;
+        {
+            let reader = PackReader::open(&path, false)?;
+            loop {
+                // Ref deltas may come before their bases, so they are
+                // resolved in rounds until none is left
+                loop {
+                    let before = deltas.len();
+                    let mut unresolved = Vec::new();
+                    for entry in deltas {
+                        match read_in_pack(&reader, entry.offset, &offsets, &thin_bases) {
+                            Ok(object) => {
+                                let sha = object.sha();
+                                entries.push((sha_bytes(&sha)?, entry.offset, entry.crc));
+                                offsets.insert(sha, entry.offset);
+                                check(&self.cancel)?;
+                                resolved += 1;
+                                self.report(Progress::Deltas {
+                                    resolved,
+                                    total: total_deltas,
+                                });
+                            }
+                            Err(Error::MissingObject(_)) => unresolved.push(entry),
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    deltas = unresolved;
+                    if deltas.is_empty() || deltas.len() == before {
+                        break;
+                    }
                 }
-                _ => None,
-            })
-            .collect();
-        let mut refs_deltas: Vec<(usize, u32, GitObject)> = self
-            .objects
-            .iter()
-            .filter_map(|o| match o {
-                (offset, checksum, PackObject::RefDelta(base, patch)) => {
-                    let patched = {
-                        let sha = base.to_hex();
-                        let base_object = base_objects.get(&sha).unwrap();
-                        base_object.patch(patch).ok()?
-                    };
-                    let sha = patched.sha();
-                    base_offsets.insert(*offset, sha.clone());
-                    base_objects.insert(sha, patched.clone());
-                    Some((*offset, *checksum, patched))
+                if deltas.is_empty() {
+                    break;
                 }
-                _ => None,
-            })
-            .collect();
-        let mut ofs_deltas: Vec<(usize, u32, GitObject)> = self
-            .objects
-            .iter()
-            .filter_map(|o| match o {
-                (offset, checksum, PackObject::OfsDelta(base, patch)) => {
-                    let patched = {
-                        let base = *offset - *base;
-                        let base_sha = base_offsets.get(&base).unwrap();
-                        let base_object = base_objects.get(base_sha).unwrap();
-                        base_object.patch(patch).ok()?
-                    };
-                    let sha = patched.sha();
-                    base_offsets.insert(*offset, sha.clone());
-                    base_objects.insert(sha, patched.clone());
-                    Some((*offset, *checksum, patched))
+                // The bases left out of the pack are all looked up at once,
+                // the others may be deltas resolved with them
+                let mut found = false;
+                for entry in deltas.iter() {
+                    if let EntryKind::RefDelta(base) = entry.kind {
+                        let base = base.to_hex();
+                        if offsets.contains_key(&base) || thin_bases.contains_key(&base) {
+                            continue;
+                        }
+                        if let Some(object) = find_base(&base) {
+                            thin_bases.insert(base, object);
+                            found = true;
+                        }
+                    }
+                }
+                if !found {
+                    let entry = deltas[0];
+                    return Err(read_in_pack(&reader, entry.offset, &offsets, &thin_bases)
+                        .err()
+                        .unwrap_or_else(|| Error::BadPack("unresolved deltas".to_owned())));
                 }
-                _ => None,
-            })
-            .collect();
-
-        objects.append(&mut refs_deltas);
-        objects.append(&mut ofs_deltas);
-
-        let index = index_opt.unwrap_or(PackIndex::from_objects(&mut objects, &sha_computed, dir)?);
-        let objects = objects
-            .iter()
-            .map(|(_, _, obj)| (obj.sha(), obj.clone()))
-            .collect();
-        Ok(PackFile {
-            version: self.version,
-            num_objects: self.entries,
-            encoded_objects: self.packfile_data[HEADER_LENGTH..self.packfile_data.len() - 20]
-                .to_vec(),
-            hexsha: sha_computed,
-            index,
-            objects,
-        })
+            }
+        }
+
+        // A base found out of the pack may be in the pack too, as a delta
+        // depending on another of these bases
+        thin_bases.retain(|sha, _| !offsets.contains_key(sha));
+        let mut thin_bases: Vec<(String, GitObject)> = thin_bases.into_iter().collect();
+        thin_bases.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut num_objects = self.entries;
+        let mut sha_computed = self.checksum.to_hex();
+        if !thin_bases.is_empty() {
+            // Complete the thin pack with the bases it refers to
+            let mut file = OpenOptions::new().read(true).write(true).open(&path)?;
+            let mut offset = file.metadata()?.len() as usize - 20;
+            file.set_len(offset as u64)?;
+            file.seek(SeekFrom::Start(offset as u64))?;
+            for (sha, base) in thin_bases.iter() {
+                let encoded = encode_object(base)?;
+                file.write_all(&encoded)?;
+                let crc = crc32::checksum_ieee(&encoded);
+                entries.push((sha_bytes(sha)?, offset, crc));
+                offset += encoded.len();
+                num_objects += 1;
+            }
+            sha_computed = seal_pack(&mut file, num_objects)?;
+        }
+
+        let index = PackIndex::from_entries(&mut entries, &sha_computed)?;
+        let mut pack = PackFile::with_index(&path, index, true)?;
+        self.path = None;
+        if let Some(git_dir) = dir {
+            pack.write(git_dir)?;
+        }
+        Ok(pack)
     }
-    pub(crate) fn add_line(&mut self, line: &[u8]) -> IOResult<()> {
-        match line[0] {
-            1 => {
-                self.lines += 1;
-                self.packfile_data.extend_from_slice(&line[1..]);
-                self.size += line.len();
-                return self.process_line();
+
+    pub(crate) fn add_line(&mut self, line: &[u8]) -> Result<()> {
+        match line.first() {
+            Some(1) => {
+                return self.receive(&line[1..]);
             }
-            2 => {
-                self.print_remote_message(std::str::from_utf8(&line[1..]).unwrap());
+            Some(2) => {
+                self.print_remote_message(&String::from_utf8_lossy(&line[1..]));
             }
-            3 => {
-                self.print_remote_error(std::str::from_utf8(&line[1..]).unwrap());
+            Some(3) => {
+                return Err(remote_error(&String::from_utf8_lossy(&line[1..])));
             }
-            _ => return Err(Error::new(ErrorKind::Other, "Git server returned error")),
+            _ => return Err(Error::Protocol("unexpected sideband channel".to_owned())),
         }
         Ok(())
     }
 
-    pub(crate) fn process_pending_lines(&mut self) -> IOResult<()> {
-        while !self.eof() {
-            self.process_line()?;
+    ///
+    /// Receives the next bytes of the pack.
+    ///
+    fn receive(&mut self, data: &[u8]) -> Result<()> {
+        self.drop_parsed();
+        self.pending.extend_from_slice(data);
+        self.process_received(data.len())
+    }
+
+    ///
+    /// Receives a whole pack from the reader, as it is read.
+    ///
+    pub(crate) fn receive_from<R: Read>(&mut self, mut reader: R) -> Result<()> {
+        loop {
+            self.drop_parsed();
+            let start = self.pending.len();
+            self.pending.resize(start + READ_SIZE, 0);
+            let read = reader.read(&mut self.pending[start..]);
+            self.pending
+                .truncate(start + read.as_ref().map_or(0, |&n| n));
+            match read? {
+                0 => break,
+                n => self.process_received(n)?,
+            }
+        }
+        self.process_pending_lines()
+    }
+
+    ///
+    /// Writes the last `n` pending bytes, just received, to the temporary
+    /// file and parses the entries they complete.
+    ///
+    fn process_received(&mut self, n: usize) -> Result<()> {
+        if self.path.is_none() {
+            let (path, file) = create_temp_file(&self.dir, TEMP_PACK_PREFIX)?;
+            self.path = Some(path);
+            self.writer = Some(BufWriter::new(file));
+        }
+        if let Some(ref mut writer) = self.writer {
+            writer.write_all(&self.pending[self.pending.len() - n..])?;
+        }
+        self.received += n;
+        self.report(Progress::Received {
+            bytes: self.received,
+        });
+        self.process_line()
+    }
+
+    ///
+    /// Drops the pending bytes already parsed, once for every chunk
+    /// received rather than as they are parsed.
+    ///
+    fn drop_parsed(&mut self) {
+        self.pending.drain(..self.parsed);
+        self.parsed = 0;
+    }
+
+    fn unparsed(&self) -> &[u8] {
+        &self.pending[self.parsed..]
+    }
+
+    ///
+    /// Parses the pack written at `path`, which the parser takes over as if
+    /// it had received it.
+    ///
+    pub(crate) fn receive_file(&mut self, path: PathBuf) -> Result<()> {
+        let file = File::open(&path);
+        self.path = Some(path);
+        self.receive_from(file?)
+    }
+
+    pub(crate) fn process_pending_lines(&mut self) -> Result<()> {
+        self.process_line()?;
+        if !self.eof() {
+            return Err(Error::BadPack("the packfile ended unexpectedly".to_owned()));
         }
         Ok(())
     }
 
-    pub(crate) fn slurp(&mut self) -> IOResult<()> {
+    pub(crate) fn slurp(&mut self) -> Result<()> {
         self.process_pending_lines()
     }
 
-    pub(crate) fn process_line(&mut self) -> IOResult<()> {
+    ///
+    /// Parses the pending bytes as far as they go.
+    ///
+    pub(crate) fn process_line(&mut self) -> Result<()> {
+        while self.parse_step()? {
+            check(&self.cancel)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Parses the next part of the pack, returning whether there were
+    /// enough pending bytes to do so.
+    ///
+    fn parse_step(&mut self) -> Result<bool> {
         match self.state {
             ParseState::Init => {
-                let mut data: &[u8] = &self.packfile_data[0..12];
+                if self.unparsed().len() < HEADER_LENGTH {
+                    return Ok(false);
+                }
+                let mut data: &[u8] = &self.pending[self.parsed..self.parsed + HEADER_LENGTH];
                 let magic = data.read_u32::<BigEndian>()?;
                 if magic != MAGIC_HEADER {
-                    return Err(Error::new(ErrorKind::Other, "Magic Header Not Found"));
+                    return Err(Error::BadPack("magic header not found".to_owned()));
                 }
                 self.version = data.read_u32::<BigEndian>()?;
                 if self.version != GIT_VERSION {
-                    return Err(Error::new(ErrorKind::Other, "Unsupported version"));
+                    let err = format!("unsupported version {}", self.version);
+                    return Err(Error::BadPack(err));
                 }
                 self.entries = data.read_u32::<BigEndian>()? as usize;
-                self.state = ParseState::ParseEntryHeader(12);
-                Ok(())
-            }
-            ParseState::ParseEntryHeader(offset) => {
-                let mut data: &[u8] = &self.packfile_data[offset..];
-                let mut c = data.read_u8()?;
-                let type_id = (c >> 4) & 7;
-                let mut size: usize = (c & 0x0F) as usize;
-                let mut shift: usize = 4;
-                let mut pos = offset + 1;
-                // Parse the variable length size header for the object.
-                // Read the MSB and check if we need to continue
-                // consuming bytes to get the object size
-                while (c & 0x80) > 0 {
-                    c = data.read_u8()?;
-                    pos += 1;
-                    size += ((c & 0x7f) as usize) << shift;
-                    shift += 7;
-                }
-                assert!(type_id > 0 && type_id <= 7);
-                assert_ne!(type_id, 5);
-                self.state = ParseState::ParseEntryBody(type_id, offset, pos, size);
-                Ok(())
+                self.consume(HEADER_LENGTH);
+                self.state = self.after_entry();
+                Ok(true)
             }
-            ParseState::ParseEntryBody(type_id, offset, pos, size) => {
-                if self.size > pos {
-                    let (obj, consumed) = self.parse_object_content(type_id, pos, size)?;
-                    self.add_object(offset, obj);
-                    if self.entries == self.objects.len() {
-                        self.state = ParseState::ParseCheckSum(pos + consumed);
-                    } else {
-                        self.state = ParseState::ParseEntryHeader(pos + consumed);
+            ParseState::ParseEntryHeader => {
+                let (kind, size, consumed) = match parse_entry_header(self.unparsed(), self.offset)?
+                {
+                    Some(header) => header,
+                    None => return Ok(false),
+                };
+                self.entry_offset = self.offset;
+                self.crc = 0;
+                self.consume(consumed);
+                let hasher = match kind {
+                    EntryKind::Base(object_type) => {
+                        let mut hasher = Sha1::new();
+                        hasher.input(&GitObject::header_for(object_type, size));
+                        Some(hasher)
                     }
-                }
-                Ok(())
+                    _ => None,
+                };
+                self.state = ParseState::ParseEntryBody(EntryBody {
+                    kind,
+                    size,
+                    decompressor: Decompress::new(true),
+                    hasher,
+                });
+                Ok(true)
             }
-            ParseState::ParseCheckSum(pos) => {
-                let mut data: &[u8] = &self.packfile_data[pos..];
-                data.read_exact(&mut self.checksum)?;
+            ParseState::ParseEntryBody(_) => self.inflate_entry(),
+            ParseState::ParseCheckSum => {
+                if self.unparsed().len() < 20 {
+                    return Ok(false);
+                }
+                self.checksum
+                    .copy_from_slice(&self.pending[self.parsed..self.parsed + 20]);
+                self.parsed += 20;
+                self.offset += 20;
+                let mut computed = [0; 20];
+                self.hasher.result(&mut computed);
+                if computed != self.checksum {
+                    return Err(Error::BadPack("bad checksum".to_owned()));
+                }
                 self.state = ParseState::End;
-                Ok(())
+                Ok(true)
             }
-            _ => Ok(()),
+            ParseState::End => Ok(false),
         }
     }
 
-    fn parse_object_content(
-        &mut self,
-        type_id: u8,
-        pos: usize,
-        size: usize,
-    ) -> IOResult<(PackObject, usize)> {
-        let err = &format!("unexpected id: {} for git object", type_id)[..];
-        match type_id {
-            1 | 2 | 3 | 4 => {
-                let (content, consumed) = self.read_object_content(pos, size)?;
-                let base_type: GitObjectType = GitObjectType::from_u8(type_id)
-                    .ok_or_else(|| Error::new(ErrorKind::Other, err))?;
-                Ok((
-                    PackObject::Base(GitObject::new(base_type, content)),
-                    consumed,
-                ))
-            }
-            6 => {
-                let (ref_offset, consumed1) = self.read_offset(pos)?;
-                let (content, consumed2) = self.read_object_content(pos + consumed1, size)?;
-                Ok((
-                    PackObject::OfsDelta(ref_offset, content),
-                    consumed1 + consumed2,
-                ))
+    ///
+    /// Inflates the pending bytes of the current entry.
+    ///
+    fn inflate_entry(&mut self) -> Result<bool> {
+        let mut body = match std::mem::replace(&mut self.state, ParseState::ParseEntryHeader) {
+            ParseState::ParseEntryBody(body) => body,
+            _ => unreachable!(),
+        };
+        let mut progressed = false;
+        loop {
+            let (total_in, total_out) =
+                (body.decompressor.total_in(), body.decompressor.total_out());
+            let status = body
+                .decompressor
+                .decompress(
+                    &self.pending[self.parsed..],
+                    &mut self.inflated,
+                    FlushDecompress::None,
+                )
+                .map_err(|e| {
+                    let err = format!("bad compressed data at {}: {}", self.entry_offset, e);
+                    Error::BadPack(err)
+                })?;
+            let nread = (body.decompressor.total_in() - total_in) as usize;
+            let nwritten = (body.decompressor.total_out() - total_out) as usize;
+            if let Some(ref mut hasher) = body.hasher {
+                hasher.input(&self.inflated[..nwritten]);
             }
-            7 => {
-                let mut base: [u8; 20] = [0; 20];
-                let mut data: &[u8] = &self.packfile_data[pos..];
-                data.read_exact(&mut base)?;
-                let (content, consumed) = self.read_object_content(pos + 20, size)?;
-                Ok((PackObject::RefDelta(base, content), consumed + 20))
+            self.consume(nread);
+            if body.decompressor.total_out() as usize > body.size {
+                return Err(self.size_mismatch());
             }
-            _ => {
-                let err = &format!("unexpected id: {} for git object", type_id)[..];
-                Err(Error::new(ErrorKind::Other, err))
+            match status {
+                Status::StreamEnd => {
+                    if body.decompressor.total_out() as usize != body.size {
+                        return Err(self.size_mismatch());
+                    }
+                    let sha = body.hasher.map(|mut hasher| {
+                        let mut sha = [0; 20];
+                        hasher.result(&mut sha);
+                        sha
+                    });
+                    self.objects.push(Entry {
+                        offset: self.entry_offset,
+                        crc: self.crc,
+                        kind: body.kind,
+                        sha,
+                    });
+                    self.report(Progress::Objects {
+                        parsed: self.objects.len(),
+                        total: self.entries,
+                    });
+                    self.state = self.after_entry();
+                    return Ok(true);
+                }
+                Status::Ok | Status::BufError => {
+                    if nread == 0 && nwritten == 0 {
+                        // Waits for more bytes of the entry
+                        self.state = ParseState::ParseEntryBody(body);
+                        return Ok(progressed);
+                    }
+                    progressed = true;
+                }
             }
         }
     }
 
-    fn read_offset(&mut self, pos: usize) -> IOResult<(usize, usize)> {
-        let mut data: &[u8] = &self.packfile_data[pos..];
-        let mut c = data.read_u8()?;
-        let mut offset = (c & 0x7f) as usize;
-        let mut consumed = 1;
-        while c & 0x80 != 0 {
-            c = data.read_u8()?;
-            consumed += 1;
-            offset += 1;
-            offset <<= 7;
-            offset += (c & 0x7f) as usize;
+    fn after_entry(&self) -> ParseState {
+        if self.objects.len() == self.entries {
+            ParseState::ParseCheckSum
+        } else {
+            ParseState::ParseEntryHeader
         }
-        Ok((offset, consumed))
     }
 
-    fn read_object_content(&mut self, offset: usize, size: usize) -> IOResult<(Vec<u8>, usize)> {
-        let mut decompressor = Decompress::new(true);
-        let mut object_buffer = Vec::with_capacity(size);
-        let mut consumed = 0;
-        let mut pos = offset;
-        loop {
-            let last_total_in = decompressor.total_in();
-            let res = {
-                let zlib_buffer = &self.packfile_data[pos..];
-                decompressor.decompress_vec(zlib_buffer, &mut object_buffer, FlushDecompress::None)
-            };
-            let nread = (decompressor.total_in() - last_total_in) as usize;
-            pos += nread;
-            consumed += nread;
-            match res {
-                Ok(Status::StreamEnd) => {
-                    if decompressor.total_out() as usize != size {
-                        return Err(Error::new(
-                            ErrorKind::Other,
-                            "Size does not match for expected object contents",
-                        ));
-                    }
+    ///
+    /// Marks the next `n` pending bytes as parsed.
+    ///
+    fn consume(&mut self, n: usize) {
+        let parsed = &self.pending[self.parsed..self.parsed + n];
+        self.hasher.input(parsed);
+        self.crc = crc32::update(self.crc, &crc32::IEEE_TABLE, parsed);
+        self.parsed += n;
+        self.offset += n;
+    }
 
-                    return Ok((object_buffer, consumed));
-                }
-                Ok(Status::BufError) => {
-                    return Err(Error::new(
-                        ErrorKind::Other,
-                        "Encountered zlib buffer error",
-                    ))
-                }
-                Ok(Status::Ok) => (),
-                Err(e) => {
-                    let s = &format!("Encountered zlib decompression error: {}", e)[..];
-                    return Err(Error::new(ErrorKind::Other, s));
-                }
-            }
-        }
+    ///
+    /// Writes the pack in `objects/pack` of the repository as it is
+    /// received, rather than in the temporary directory of the system.
+    ///
+    pub fn set_git_dir(&mut self, git_dir: Option<&str>) {
+        self.dir = temp_pack_dir(git_dir);
+    }
+
+    pub fn set_progress(&mut self, progress: Option<ProgressCallback>) {
+        self.progress = progress;
+    }
+
+    pub fn set_cancellation(&mut self, cancel: Option<CancellationToken>) {
+        self.cancel = cancel;
+    }
+
+    ///
+    /// Fails once the parser is cancelled.
+    ///
+    pub(crate) fn check_cancelled(&self) -> Result<()> {
+        check(&self.cancel)
+    }
+
+    pub fn set_shallow(&mut self, shallow: Vec<String>) {
+        self.shallow = shallow;
+    }
+
+    ///
+    /// Returns the commits the server marked as shallow for this pack.
+    ///
+    pub fn shallow(&self) -> &[String] {
+        &self.shallow
+    }
+
+    fn size_mismatch(&self) -> Error {
+        let err = format!("size mismatch in the entry at {}", self.entry_offset);
+        Error::BadPack(err)
     }
 
     fn print_remote_message(&self, msg: &str) {
-        println!("{}", msg);
+        self.report(Progress::Remote(msg.to_string()));
     }
 
-    fn print_remote_error(&self, msg: &str) {
-        println!("{}", msg);
+    fn report(&self, event: Progress) {
+        if let Some(ref progress) = self.progress {
+            progress(&event);
+        }
     }
 
     pub fn count_objects(&self) -> usize {
         self.objects.len()
     }
 
-    fn add_object(&mut self, offset: usize, object: PackObject) {
-        self.objects.push((offset, object.crc32(), object));
+    pub fn eof(&self) -> bool {
+        matches!(self.state, ParseState::End)
     }
+}
 
-    pub fn eof(&self) -> bool {
-        self.state == ParseState::End
+impl Drop for PackFileParser {
+    fn drop(&mut self) {
+        // The pack was never parsed
+        if let Some(ref path) = self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+///
+/// Reads the object of an entry whose deltas have their bases in the pack,
+/// at the given offsets, or among the bases of a thin pack keyed by sha.
+///
+fn read_in_pack(
+    reader: &PackReader,
+    offset: usize,
+    offsets: &HashMap<String, usize>,
+    thin_bases: &HashMap<String, GitObject>,
+) -> Result<GitObject> {
+    reader.read_object(offset, &|sha: &str| match offsets.get(sha) {
+        Some(&base) => Ok(Some(DeltaBase::Entry(base))),
+        None => Ok(thin_bases.get(sha).cloned().map(DeltaBase::Object)),
+    })
+}
+
+fn sha_bytes(sha: &str) -> Result<[u8; 20]> {
+    match sha.from_hex() {
+        Ok(ref bytes) if bytes.len() == 20 => {
+            let mut sha = [0; 20];
+            sha.copy_from_slice(bytes);
+            Ok(sha)
+        }
+        _ => Err(Error::CorruptObject(format!("bad sha {}", sha))),
     }
 }
@@ -1,11 +1,12 @@
+use crate::error::{Error, Result};
 use crate::utils::is_sha;
 use std::fs;
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::Result as IOResult;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Ref {
     pub id: String,
     pub name: String,
@@ -13,99 +14,863 @@ pub struct Ref {
 
 pub type Refs = Vec<Ref>;
 
-pub(crate) fn create_refs(repo: &str, refs: &[Ref]) -> IOResult<()> {
-    let (tags, branches): (Vec<_>, Vec<_>) = refs
+/// How many symbolic refs are followed before giving up, as git does
+const MAX_SYMREF_DEPTH: usize = 5;
+
+///
+/// Writes the advertised branches under `branches_path` and the tags to
+/// `packed-refs`, along with the objects their peeled `^{}` refs point to.
+///
+pub(crate) fn create_refs(git_dir: &str, branches_path: &str, refs: &[Ref]) -> Result<()> {
+    let branches: Vec<_> = refs
         .iter()
-        .filter(|r| !r.name.ends_with("^{}"))
-        .partition(|r| r.name.starts_with("refs/tags"));
+        .filter(|r| r.name.starts_with("refs/heads/") && !r.name.ends_with("^{}"))
+        .collect();
+    let tags: Vec<_> = refs
+        .iter()
+        .filter(|r| r.name.starts_with("refs/tags/"))
+        .collect();
 
-    write_refs(repo, "refs/remotes/origin", &branches)?;
-    write_refs(repo, "refs/tags", &tags)?;
-    Ok(())
+    write_refs(git_dir, branches_path, "refs/heads/", &branches)?;
+    write_packed_refs(git_dir, &tags)
+}
+
+///
+/// Writes every advertised ref with its own name, as a mirror does, the
+/// tags to `packed-refs` along with their peeled refs.
+///
+pub(crate) fn create_mirror_refs(git_dir: &str, refs: &[Ref]) -> Result<()> {
+    let (tags, others): (Vec<_>, Vec<_>) = refs
+        .iter()
+        .filter(|r| r.name.starts_with("refs/"))
+        .partition(|r| r.name.starts_with("refs/tags/"));
+    let others: Vec<_> = others
+        .into_iter()
+        .filter(|r| !r.name.ends_with("^{}"))
+        .collect();
+    write_refs(git_dir, "refs", "refs/", &others)?;
+    write_packed_refs(git_dir, &tags)
 }
 
-pub(crate) fn update_head(repo: &str, refs: &[Ref]) -> IOResult<()> {
+///
+/// Points `HEAD` to the branch of the remote `HEAD`, returning its name.
+///
+pub(crate) fn update_head(
+    git_dir: &str,
+    refs: &[Ref],
+    head_target: Option<&str>,
+) -> Result<Option<String>> {
     if let Some(head) = refs.iter().find(|r| r.name == "HEAD") {
         let sha1 = &head.id;
-        let true_ref = refs.iter().find(|r| r.name != "HEAD" && r.id == *sha1);
+        let true_ref = find_head_branch(refs, head_target);
         let dir = true_ref.map_or("refs/heads/master", |r| &r.name[..]);
-        create_ref(repo, dir, &sha1)?;
-        create_sym_ref(repo, "HEAD", dir)?;
+        check_ref_name(dir)?;
+        create_ref(git_dir, dir, sha1)?;
+        create_sym_ref(git_dir, "HEAD", dir)?;
+        return Ok(Some(dir.to_owned()));
     }
-    Ok(())
+    Ok(None)
+}
+
+///
+/// Finds the branch the remote `HEAD` points to, given by the `symref`
+/// capability when the server advertises it. Otherwise guesses it as the
+/// advertised branch sharing the remote `HEAD` sha.
+///
+pub(crate) fn find_head_branch<'a>(refs: &'a [Ref], head_target: Option<&str>) -> Option<&'a Ref> {
+    if let Some(branch) = head_target.and_then(|t| refs.iter().find(|r| r.name == t)) {
+        return Some(branch);
+    }
+    let head = refs.iter().find(|r| r.name == "HEAD")?;
+    refs.iter()
+        .find(|r| r.name.starts_with("refs/heads/") && r.id == head.id)
 }
 
-fn write_refs(repo: &str, parent_path: &str, refs: &[&Ref]) -> IOResult<()> {
+///
+/// Finds the advertised branch or tag with the given short name.
+/// For annotated tags the peeled ref is returned, so it can be checked out.
+///
+pub(crate) fn find_branch_or_tag<'a>(refs: &'a [Ref], name: &str) -> Result<&'a Ref> {
+    let branch = format!("refs/heads/{}", name);
+    let tag = format!("refs/tags/{}", name);
+    let peeled = format!("{}^{{}}", tag);
+    refs.iter()
+        .find(|r| r.name == branch)
+        .or_else(|| refs.iter().find(|r| r.name == peeled))
+        .or_else(|| refs.iter().find(|r| r.name == tag))
+        .ok_or_else(|| Error::Ref(format!("remote branch {} not found in upstream", name)))
+}
+
+///
+/// Points `HEAD` to the given branch, or detaches it when the ref is a tag.
+/// Returns the name of the branch `HEAD` points to.
+///
+pub(crate) fn update_head_to(git_dir: &str, the_ref: &Ref) -> Result<Option<String>> {
+    if the_ref.name.starts_with("refs/heads/") {
+        check_ref_name(&the_ref.name)?;
+        create_ref(git_dir, &the_ref.name, &the_ref.id)?;
+        create_sym_ref(git_dir, "HEAD", &the_ref.name)?;
+        Ok(Some(the_ref.name.clone()))
+    } else {
+        create_ref(git_dir, "HEAD", &the_ref.id)?;
+        Ok(None)
+    }
+}
+
+fn write_refs(git_dir: &str, parent_path: &str, prefix: &str, refs: &[&Ref]) -> Result<()> {
     let mut path = PathBuf::new();
     path.push(parent_path);
 
+    // Nothing is written when any of the names is invalid
+    for r in refs {
+        check_ref_name(&r.name)?;
+    }
     for r in refs {
         let mut full_path = path.clone();
-        let simple_name = Path::new(&r.name).file_name().unwrap();
-        full_path.push(&simple_name);
-        create_ref(repo, full_path.to_str().unwrap(), &r.id)?;
+        full_path.push(r.name.trim_start_matches(prefix));
+        create_ref(git_dir, &full_path.to_string_lossy(), &r.id)?;
+    }
+    Ok(())
+}
+
+///
+/// Checks a ref name as `git check-ref-format` does, so a name given by a
+/// remote can't point outside the refs of the repository once joined to
+/// the git dir.
+///
+pub(crate) fn check_ref_name(name: &str) -> Result<()> {
+    let invalid = name.is_empty()
+        || name == "@"
+        || name.starts_with('/')
+        || name.ends_with('/')
+        || name.ends_with('.')
+        || name.contains("//")
+        || name.contains("..")
+        || name.contains("@{")
+        || name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+        || name
+            .split('/')
+            .any(|part| part.starts_with('.') || part.ends_with(".lock"));
+    if invalid {
+        return Err(Error::Ref(format!("invalid ref name {:?}", name)));
     }
     Ok(())
 }
 
-fn create_ref(repo: &str, path: &str, id: &str) -> IOResult<()> {
+pub(crate) fn create_ref(git_dir: &str, path: &str, id: &str) -> Result<()> {
     let mut full_path = PathBuf::new();
-    full_path.push(repo);
-    full_path.push(".git");
+    full_path.push(git_dir);
     full_path.push(path);
-    fs::create_dir_all(full_path.parent().unwrap())?;
+    if let Some(parent) = full_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
     let mut file = File::create(full_path)?;
     file.write_fmt(format_args!("{}\n", id))?;
     Ok(())
 }
 
+///
+/// Adds refs to `packed-refs`, each annotated tag followed by a `^<sha>`
+/// line with the object of its peeled `^{}` ref. The loose refs with the
+/// same names are removed, as they would take precedence.
+///
+pub(crate) fn write_packed_refs(git_dir: &str, refs: &[&Ref]) -> Result<()> {
+    if refs.is_empty() {
+        return Ok(());
+    }
+    let mut path = PathBuf::new();
+    path.push(git_dir);
+    path.push("packed-refs");
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => Some(contents),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    // Refs packed by others without their peeled lines stay that way
+    let fully_peeled = match contents.as_ref().and_then(|c| c.lines().next()) {
+        Some(header) => {
+            header.starts_with("# pack-refs with:") && header.contains(" fully-peeled ")
+        }
+        None => true,
+    };
+
+    let base_name = |r: &Ref| r.name.trim_end_matches("^{}").to_owned();
+    let replaced: Vec<String> = refs.iter().map(|r| base_name(r)).collect();
+    // The loose refs with these names are removed, they must be refs
+    let refs_dir = Path::new(git_dir).join("refs");
+    let mut loose_paths = Vec::new();
+    for name in replaced.iter() {
+        check_ref_name(name)?;
+        let loose = Path::new(git_dir).join(name);
+        if !loose.starts_with(&refs_dir) {
+            return Err(Error::Ref(format!("{} is not under refs/", name)));
+        }
+        loose_paths.push(loose);
+    }
+    let mut packed = contents.as_deref().map_or_else(Vec::new, parse_packed_refs);
+    packed.retain(|p| !replaced.contains(&base_name(p)));
+    packed.extend(refs.iter().map(|&r| r.clone()));
+    // Sorted by name, with the peeled ref right after its tag
+    packed.sort_by_key(|r| (base_name(r), r.name.ends_with("^{}")));
+
+    let mut packed_refs = String::from(if fully_peeled {
+        "# pack-refs with: peeled fully-peeled sorted \n"
+    } else {
+        "# pack-refs with: sorted \n"
+    });
+    let mut previous: Option<&str> = None;
+    for r in packed.iter() {
+        match r.name.strip_suffix("^{}") {
+            Some(tag) if previous == Some(tag) => {
+                packed_refs.push_str(&format!("^{}\n", r.id));
+            }
+            Some(_) => continue,
+            None => packed_refs.push_str(&format!("{} {}\n", r.id, r.name)),
+        }
+        previous = Some(&r.name);
+    }
+    fs::write(path, packed_refs)?;
+
+    for loose in loose_paths {
+        match fs::remove_file(loose) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+            res => res?,
+        }
+    }
+    Ok(())
+}
+
+///
+/// Deletes a ref from the given repository, whether it is loose or packed.
+///
+pub(crate) fn delete_ref(git_dir: &str, name: &str) -> Result<()> {
+    let mut path = PathBuf::new();
+    path.push(git_dir);
+    path.push(name);
+    match fs::remove_file(&path) {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+        res => res?,
+    }
+    let mut path = PathBuf::new();
+    path.push(git_dir);
+    path.push("packed-refs");
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    // Drop the ref along with the peeled line that follows it
+    let mut kept = String::new();
+    let mut deleted = false;
+    for line in contents.lines() {
+        if line.starts_with('^') && deleted {
+            continue;
+        }
+        deleted = line.split_once(' ').map(|(_, n)| n) == Some(name);
+        if !deleted {
+            kept.push_str(line);
+            kept.push('\n');
+        }
+    }
+    Ok(fs::write(path, kept)?)
+}
+
+///
+/// Expands a short name like `master` or `v1.0` to the full name of the
+/// local branch or tag. `HEAD` expands to the branch it points to, or
+/// stays `HEAD` when it is detached.
+///
+pub(crate) fn expand_ref_name(git_dir: &str, name: &str) -> Result<String> {
+    if name == "HEAD" {
+        let mut path = PathBuf::new();
+        path.push(git_dir);
+        path.push("HEAD");
+        let contents = fs::read_to_string(path)?;
+        return Ok(contents
+            .strip_prefix("ref: ")
+            .map_or("HEAD", |r| r.trim())
+            .to_owned());
+    }
+    let refs = read_refs(git_dir)?;
+    ["", "refs/heads/", "refs/tags/"]
+        .iter()
+        .map(|prefix| [prefix, name].concat())
+        .find(|full_name| refs.iter().any(|r| &r.name == full_name))
+        .ok_or_else(|| Error::Ref(format!("ref {} not found", name)))
+}
+
+///
+/// Checks that a fetch refspec has the `[+]src:dst` form `map_refspec`
+/// handles, with a `*` ending both sides or neither. Negative refspecs and
+/// the ones without a destination are unsupported.
+///
+pub(crate) fn check_fetch_refspec(refspec: &str) -> Result<()> {
+    let mut split = refspec.trim_start_matches('+').splitn(2, ':');
+    let (src, dst) = (split.next().unwrap_or(""), split.next().unwrap_or(""));
+    let glob = |side: &str| side.trim_end_matches('*').contains('*');
+    if src.is_empty()
+        || dst.is_empty()
+        || src.starts_with('^')
+        || glob(src)
+        || glob(dst)
+        || src.ends_with('*') != dst.ends_with('*')
+    {
+        let err = format!("unsupported fetch refspec {}", refspec);
+        return Err(Error::Unsupported(err));
+    }
+    Ok(())
+}
+
+///
+/// Maps a remote ref name through a fetch refspec like
+/// `+refs/heads/*:refs/remotes/origin/*`, returns None if it doesn't match.
+///
+pub(crate) fn map_refspec(refspec: &str, name: &str) -> Option<String> {
+    let mut split = refspec.trim_start_matches('+').splitn(2, ':');
+    let (src, dst) = (split.next()?, split.next()?);
+    match (src.strip_suffix('*'), dst.strip_suffix('*')) {
+        (Some(src), Some(dst)) => name.strip_prefix(src).map(|rest| [dst, rest].concat()),
+        (None, None) if src == name => Some(dst.to_owned()),
+        _ => None,
+    }
+}
+
 ///
 /// Creates a symbolic ref in the given repository.
 ///
-fn create_sym_ref(repo: &str, name: &str, the_ref: &str) -> IOResult<()> {
+pub(crate) fn create_sym_ref(git_dir: &str, name: &str, the_ref: &str) -> Result<()> {
     let mut path = PathBuf::new();
-    path.push(repo);
-    path.push(".git");
+    path.push(git_dir);
     path.push(name);
     let mut file = File::create(path)?;
     file.write_fmt(format_args!("ref: {}\n", the_ref))?;
     Ok(())
 }
 
-pub fn resolve_ref(repo: &str, name: &str) -> IOResult<String> {
+pub fn resolve_ref(git_dir: &str, name: &str) -> Result<String> {
+    resolve_ref_within(git_dir, name, MAX_SYMREF_DEPTH)
+}
+
+fn resolve_ref_within(git_dir: &str, name: &str, depth: usize) -> Result<String> {
     // Check if the name is already a sha.
     let trimmed = name.trim();
     if is_sha(trimmed) {
         Ok(trimmed.to_owned())
+    } else if depth == 0 {
+        Err(Error::Ref(format!(
+            "symbolic ref {} nested too deeply",
+            name
+        )))
     } else {
-        read_sym_ref(repo, trimmed)
+        read_sym_ref(git_dir, trimmed, depth - 1)
     }
 }
 
-fn read_sym_ref(repo: &str, name: &str) -> IOResult<String> {
+fn read_sym_ref(git_dir: &str, name: &str, depth: usize) -> Result<String> {
     // Read the symbolic ref directly and parse the actual ref out
     let mut path = PathBuf::new();
-    path.push(repo);
-    path.push(".git");
+    path.push(git_dir);
 
+    let mut full_name = PathBuf::new();
     if name != "HEAD" {
         if !name.contains('/') {
-            path.push("refs/heads");
+            full_name.push("refs/heads");
         } else if !name.starts_with("refs/") {
-            path.push("refs/remotes");
+            full_name.push("refs/remotes");
         }
     }
-    path.push(name);
+    full_name.push(name);
+    path.push(&full_name);
 
     // Read the actual ref out
     let mut contents = String::new();
-    let mut file = File::open(path)?;
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            // The ref may have been packed
+            let full_name = full_name.to_str().unwrap_or(name);
+            return read_packed_refs(git_dir)?
+                .into_iter()
+                .find(|r| r.name == full_name)
+                .map(|r| r.id)
+                .ok_or_else(|| Error::Ref(format!("ref {} not found", name)));
+        }
+        Err(e) => return Err(e.into()),
+    };
     file.read_to_string(&mut contents)?;
 
-    if contents.starts_with("ref: ") {
-        let the_ref = contents.split("ref: ").nth(1).unwrap().trim();
-        resolve_ref(repo, &the_ref)
+    if let Some(the_ref) = contents.strip_prefix("ref: ") {
+        resolve_ref_within(git_dir, the_ref.trim(), depth)
     } else {
         Ok(contents.trim().to_owned())
     }
 }
+
+///
+/// Reads the refs stored in the given git dir, both loose and packed,
+/// with `HEAD` first when it can be resolved.
+///
+pub(crate) fn read_refs(git_dir: &str) -> Result<Refs> {
+    let mut refs: Refs = read_packed_refs(git_dir)?;
+    refs.retain(|r| !r.name.ends_with("^{}"));
+    let mut root = PathBuf::new();
+    root.push(git_dir);
+    root.push("refs");
+    read_loose_refs(git_dir, &root, &mut refs)?;
+    refs.sort_by(|a, b| a.name.cmp(&b.name));
+
+    if let Ok(id) = resolve_ref(git_dir, "HEAD") {
+        refs.insert(
+            0,
+            Ref {
+                id,
+                name: "HEAD".to_string(),
+            },
+        );
+    }
+    Ok(refs)
+}
+
+fn read_loose_refs(git_dir: &str, dir: &Path, refs: &mut Refs) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            read_loose_refs(git_dir, &path, refs)?;
+            continue;
+        }
+        // Lock files are refs being written by git
+        if path.extension().is_some_and(|e| e == "lock") {
+            continue;
+        }
+        let name = path
+            .strip_prefix(git_dir)
+            .ok()
+            .and_then(|p| p.to_str())
+            .map(|p| p.to_owned());
+        if let Some(name) = name {
+            // Dangling symrefs, like `refs/remotes/origin/HEAD` pointing
+            // to a deleted branch, are skipped as git does
+            let id = match resolve_ref(git_dir, &name) {
+                Ok(id) => id,
+                Err(Error::Ref(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            // Loose refs take precedence over packed ones
+            refs.retain(|r| r.name != name);
+            refs.push(Ref { id, name });
+        }
+    }
+    Ok(())
+}
+
+fn read_packed_refs(git_dir: &str) -> Result<Refs> {
+    let mut path = PathBuf::new();
+    path.push(git_dir);
+    path.push("packed-refs");
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(parse_packed_refs(&contents))
+}
+
+///
+/// Parses `packed-refs`, the peeled lines (`^<sha>`) following an annotated
+/// tag returned as its `^{}` ref, like in the advertisement of a server.
+///
+fn parse_packed_refs(contents: &str) -> Refs {
+    let mut refs: Refs = Vec::new();
+    for line in contents.lines().filter(|l| !l.starts_with('#')) {
+        if let Some(id) = line.strip_prefix('^') {
+            let tag = match refs.last() {
+                Some(tag) if is_sha(id.trim()) && !tag.name.ends_with("^{}") => &tag.name,
+                _ => continue,
+            };
+            let name = format!("{}^{{}}", tag);
+            refs.push(Ref {
+                id: id.trim().to_owned(),
+                name,
+            });
+            continue;
+        }
+        let mut split = line.splitn(2, ' ');
+        if let (Some(id), Some(name)) = (split.next(), split.next()) {
+            if is_sha(id) {
+                refs.push(Ref {
+                    id: id.to_owned(),
+                    name: name.trim().to_owned(),
+                });
+            }
+        }
+    }
+    refs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::temp_git_dir;
+
+    fn advertised_refs() -> Refs {
+        let refs = [
+            ("HEAD", "33676d1c63d868803ed110b13be4e616bc8a29b7"),
+            (
+                "refs/heads/feature/x",
+                "718e7fc194a0fef1b1067b12689e5d343f533497",
+            ),
+            (
+                "refs/heads/master",
+                "33676d1c63d868803ed110b13be4e616bc8a29b7",
+            ),
+            (
+                "refs/pull/1/head",
+                "2f2466ca0129f2b8fec6bb12cb99c2eba9778639",
+            ),
+            (
+                "refs/tags/test_tag",
+                "7a4219fa5df9550fa54636f2783cd7c3cb63b1f3",
+            ),
+            (
+                "refs/tags/test_tag^{}",
+                "3c7cfac73a699ef415bc737ce5529ac66c5692a9",
+            ),
+        ];
+        refs.iter()
+            .map(|(name, id)| Ref {
+                id: id.to_string(),
+                name: name.to_string(),
+            })
+            .collect()
+    }
+
+    fn ref_names(git_dir: &str) -> Vec<String> {
+        read_refs(git_dir)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.name)
+            .collect()
+    }
+
+    #[test]
+    fn creating_remote_tracking_refs() {
+        let git_dir = temp_git_dir("remote");
+        create_refs(&git_dir, "refs/remotes/origin", &advertised_refs()).unwrap();
+        assert_eq!(
+            ref_names(&git_dir),
+            vec![
+                "refs/remotes/origin/feature/x",
+                "refs/remotes/origin/master",
+                "refs/tags/test_tag",
+            ]
+        );
+    }
+
+    #[test]
+    fn creating_bare_refs() {
+        let git_dir = temp_git_dir("bare");
+        create_refs(&git_dir, "refs/heads", &advertised_refs()).unwrap();
+        assert_eq!(
+            ref_names(&git_dir),
+            vec![
+                "refs/heads/feature/x",
+                "refs/heads/master",
+                "refs/tags/test_tag"
+            ]
+        );
+    }
+
+    #[test]
+    fn creating_mirror_refs() {
+        let git_dir = temp_git_dir("mirror");
+        create_mirror_refs(&git_dir, &advertised_refs()).unwrap();
+        assert_eq!(
+            ref_names(&git_dir),
+            vec![
+                "refs/heads/feature/x",
+                "refs/heads/master",
+                "refs/pull/1/head",
+                "refs/tags/test_tag",
+            ]
+        );
+    }
+
+    #[test]
+    fn tags_are_packed_with_their_peeled_refs() {
+        let git_dir = temp_git_dir("packed-tags");
+        create_ref(
+            &git_dir,
+            "refs/tags/test_tag",
+            "33676d1c63d868803ed110b13be4e616bc8a29b7",
+        )
+        .unwrap();
+        fs::write(
+            Path::new(&git_dir).join("packed-refs"),
+            "# pack-refs with: peeled fully-peeled sorted \n\
+             2f2466ca0129f2b8fec6bb12cb99c2eba9778639 refs/tags/old\n\
+             7a4219fa5df9550fa54636f2783cd7c3cb63b1f3 refs/tags/test_tag-rc\n\
+             ^3c7cfac73a699ef415bc737ce5529ac66c5692a9\n",
+        )
+        .unwrap();
+        create_refs(&git_dir, "refs/remotes/origin", &advertised_refs()).unwrap();
+        assert!(!Path::new(&git_dir).join("refs/tags/test_tag").exists());
+        assert_eq!(
+            fs::read_to_string(Path::new(&git_dir).join("packed-refs")).unwrap(),
+            "# pack-refs with: peeled fully-peeled sorted \n\
+             2f2466ca0129f2b8fec6bb12cb99c2eba9778639 refs/tags/old\n\
+             7a4219fa5df9550fa54636f2783cd7c3cb63b1f3 refs/tags/test_tag\n\
+             ^3c7cfac73a699ef415bc737ce5529ac66c5692a9\n\
+             7a4219fa5df9550fa54636f2783cd7c3cb63b1f3 refs/tags/test_tag-rc\n\
+             ^3c7cfac73a699ef415bc737ce5529ac66c5692a9\n"
+        );
+        assert_eq!(
+            resolve_ref(&git_dir, "refs/tags/test_tag").unwrap(),
+            "7a4219fa5df9550fa54636f2783cd7c3cb63b1f3"
+        );
+    }
+
+    #[test]
+    fn finding_a_branch_or_tag() {
+        let refs = advertised_refs();
+        let branch = find_branch_or_tag(&refs, "feature/x").unwrap();
+        assert_eq!(branch.name, "refs/heads/feature/x");
+        let tag = find_branch_or_tag(&refs, "test_tag").unwrap();
+        assert_eq!(tag.id, "3c7cfac73a699ef415bc737ce5529ac66c5692a9");
+        assert!(find_branch_or_tag(&refs, "missing").is_err());
+    }
+
+    #[test]
+    fn updating_head_to_a_tag_detaches_it() {
+        let git_dir = temp_git_dir("detached");
+        let refs = advertised_refs();
+        let tag = find_branch_or_tag(&refs, "test_tag").unwrap();
+        assert_eq!(update_head_to(&git_dir, tag).unwrap(), None);
+        assert_eq!(resolve_ref(&git_dir, "HEAD").unwrap(), tag.id);
+
+        let branch = find_branch_or_tag(&refs, "master").unwrap();
+        let head = update_head_to(&git_dir, branch).unwrap();
+        assert_eq!(head, Some("refs/heads/master".to_string()));
+        let contents = fs::read_to_string(Path::new(&git_dir).join("HEAD")).unwrap();
+        assert_eq!(contents, "ref: refs/heads/master\n");
+    }
+
+    #[test]
+    fn finding_the_head_branch() {
+        let refs = advertised_refs();
+        assert_eq!(
+            find_head_branch(&refs, None).unwrap().name,
+            "refs/heads/master"
+        );
+        assert!(find_head_branch(&refs[1..], None).is_none());
+    }
+
+    #[test]
+    fn the_head_symref_wins_over_branches_sharing_its_sha() {
+        let mut refs = advertised_refs();
+        refs.insert(
+            1,
+            Ref {
+                id: refs[0].id.clone(),
+                name: "refs/heads/develop".to_string(),
+            },
+        );
+        let guessed = find_head_branch(&refs, None).unwrap();
+        assert_eq!(guessed.name, "refs/heads/develop");
+        let told = find_head_branch(&refs, Some("refs/heads/master")).unwrap();
+        assert_eq!(told.name, "refs/heads/master");
+
+        let git_dir = temp_git_dir("symref-head");
+        let head = update_head(&git_dir, &refs, Some("refs/heads/master")).unwrap();
+        assert_eq!(head, Some("refs/heads/master".to_string()));
+        let contents = fs::read_to_string(Path::new(&git_dir).join("HEAD")).unwrap();
+        assert_eq!(contents, "ref: refs/heads/master\n");
+    }
+
+    #[test]
+    fn checking_fetch_refspecs() {
+        for refspec in &[
+            "+refs/heads/*:refs/remotes/origin/*",
+            "refs/heads/master:refs/remotes/origin/master",
+            "+refs/*:refs/*",
+        ] {
+            assert!(check_fetch_refspec(refspec).is_ok(), "{}", refspec);
+        }
+        for refspec in &[
+            "refs/heads/master",
+            "^refs/heads/wip/*",
+            "refs/heads/*:refs/remotes/origin/master",
+            "refs/heads/*/x:refs/remotes/origin/*",
+            ":refs/heads/master",
+        ] {
+            let result = check_fetch_refspec(refspec);
+            assert!(matches!(result, Err(Error::Unsupported(_))), "{}", refspec);
+        }
+    }
+
+    #[test]
+    fn mapping_refspecs() {
+        let refspec = "+refs/heads/*:refs/remotes/origin/*";
+        assert_eq!(
+            map_refspec(refspec, "refs/heads/feature/x"),
+            Some("refs/remotes/origin/feature/x".to_string())
+        );
+        assert_eq!(map_refspec(refspec, "refs/tags/test_tag"), None);
+        assert_eq!(map_refspec(refspec, "HEAD"), None);
+        assert_eq!(
+            map_refspec("+refs/*:refs/*", "refs/pull/1/head"),
+            Some("refs/pull/1/head".to_string())
+        );
+        let single = "+refs/heads/master:refs/remotes/origin/master";
+        assert_eq!(
+            map_refspec(single, "refs/heads/master"),
+            Some("refs/remotes/origin/master".to_string())
+        );
+        assert_eq!(map_refspec(single, "refs/heads/other"), None);
+    }
+
+    #[test]
+    fn expanding_ref_names() {
+        let git_dir = temp_git_dir("expand");
+        create_refs(&git_dir, "refs/heads", &advertised_refs()).unwrap();
+        create_sym_ref(&git_dir, "HEAD", "refs/heads/master").unwrap();
+        assert_eq!(
+            expand_ref_name(&git_dir, "feature/x").unwrap(),
+            "refs/heads/feature/x"
+        );
+        assert_eq!(
+            expand_ref_name(&git_dir, "test_tag").unwrap(),
+            "refs/tags/test_tag"
+        );
+        assert_eq!(
+            expand_ref_name(&git_dir, "HEAD").unwrap(),
+            "refs/heads/master"
+        );
+        assert!(expand_ref_name(&git_dir, "missing").is_err());
+    }
+
+    #[test]
+    fn deleting_loose_and_packed_refs() {
+        let git_dir = temp_git_dir("delete");
+        create_ref(
+            &git_dir,
+            "refs/heads/master",
+            "33676d1c63d868803ed110b13be4e616bc8a29b7",
+        )
+        .unwrap();
+        fs::write(
+            Path::new(&git_dir).join("packed-refs"),
+            "7a4219fa5df9550fa54636f2783cd7c3cb63b1f3 refs/tags/test_tag\n\
+             ^3c7cfac73a699ef415bc737ce5529ac66c5692a9\n\
+             718e7fc194a0fef1b1067b12689e5d343f533497 refs/heads/feature/x\n",
+        )
+        .unwrap();
+        delete_ref(&git_dir, "refs/heads/master").unwrap();
+        delete_ref(&git_dir, "refs/tags/test_tag").unwrap();
+        assert_eq!(ref_names(&git_dir), vec!["refs/heads/feature/x"]);
+    }
+
+    #[test]
+    fn checking_ref_names() {
+        for name in [
+            "HEAD",
+            "refs/heads/master",
+            "refs/heads/feature/x",
+            "refs/tags/v1.0",
+        ] {
+            assert!(check_ref_name(name).is_ok(), "{}", name);
+        }
+        for name in [
+            "",
+            "@",
+            "/etc/passwd",
+            "refs/heads/",
+            "refs/heads/../../x",
+            "refs//heads/x",
+            "refs/heads/x.lock",
+            "refs/heads/.hidden",
+            "refs/heads/x.",
+            "refs/heads/a\\b",
+            "refs/heads/a\tb",
+            "refs/heads/a@{1}",
+            "refs/tags/v1^{}",
+        ] {
+            assert!(check_ref_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn advertised_refs_escaping_the_git_dir_are_rejected() {
+        let git_dir = temp_git_dir("malicious");
+        let mut refs = advertised_refs();
+        refs.push(Ref {
+            id: "33676d1c63d868803ed110b13be4e616bc8a29b7".to_string(),
+            name: "refs/heads/../../../escaped".to_string(),
+        });
+        let res = create_refs(&git_dir, "refs/remotes/origin", &refs);
+        assert!(matches!(res, Err(Error::Ref(_))));
+        assert!(!Path::new(&git_dir).join("../escaped").exists());
+        assert!(!Path::new(&git_dir).join("escaped").exists());
+    }
+
+    #[test]
+    fn packing_refs_never_removes_files_outside_refs() {
+        let git_dir = temp_git_dir("packed-escape");
+        let victim = Path::new(&git_dir).join("victim");
+        fs::write(&victim, "keep me\n").unwrap();
+        for name in ["refs/tags/../../victim", "victim"] {
+            let tag = Ref {
+                id: "7a4219fa5df9550fa54636f2783cd7c3cb63b1f3".to_string(),
+                name: name.to_string(),
+            };
+            let res = write_packed_refs(&git_dir, &[&tag]);
+            assert!(matches!(res, Err(Error::Ref(_))));
+        }
+        assert!(victim.exists());
+        assert!(!Path::new(&git_dir).join("packed-refs").exists());
+    }
+
+    #[test]
+    fn reading_refs_skips_dangling_symrefs_and_lock_files() {
+        let git_dir = temp_git_dir("dangling");
+        create_refs(&git_dir, "refs/remotes/origin", &advertised_refs()).unwrap();
+        create_sym_ref(
+            &git_dir,
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/gone",
+        )
+        .unwrap();
+        fs::write(
+            Path::new(&git_dir).join("refs/remotes/origin/master.lock"),
+            "33676d1c63d868803ed110b13be4e616bc8a29b7\n",
+        )
+        .unwrap();
+        assert_eq!(
+            ref_names(&git_dir),
+            vec![
+                "refs/remotes/origin/feature/x",
+                "refs/remotes/origin/master",
+                "refs/tags/test_tag",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_packed_refs() {
+        let contents = "# pack-refs with: peeled fully-peeled sorted \n\
+            fb6fb3d9b81142566f4b2466857b0302617768de refs/heads/master\n\
+            7a4219fa5df9550fa54636f2783cd7c3cb63b1f3 refs/tags/v1.0\n\
+            ^fb6fb3d9b81142566f4b2466857b0302617768de\n";
+        let refs = parse_packed_refs(contents);
+        assert_eq!(refs.len(), 3);
+        assert_eq!(refs[0].name, "refs/heads/master");
+        assert_eq!(refs[0].id, "fb6fb3d9b81142566f4b2466857b0302617768de");
+        assert_eq!(refs[1].name, "refs/tags/v1.0");
+        assert_eq!(refs[2].name, "refs/tags/v1.0^{}");
+        assert_eq!(refs[2].id, "fb6fb3d9b81142566f4b2466857b0302617768de");
+    }
+}
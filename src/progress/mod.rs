@@ -0,0 +1,26 @@
+use std::sync::Arc;
+
+///
+/// Events reported while a repo is being cloned or fetched.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum Progress {
+    /// A message sent by the remote on the progress sideband channel,
+    /// like "Counting objects: 100% (25/25), done."
+    Remote(String),
+    /// The bytes of the pack received so far
+    Received { bytes: usize },
+    /// The objects of the pack parsed so far, out of the ones it holds
+    Objects { parsed: usize, total: usize },
+    /// The deltas of the pack resolved so far, out of the ones it holds
+    Deltas { resolved: usize, total: usize },
+    /// A file written to the working directory, with the number of files
+    /// checked out so far, out of the ones to check out
+    CheckedOut {
+        path: String,
+        files: usize,
+        total: usize,
+    },
+}
+
+pub(crate) type ProgressCallback = Arc<dyn Fn(&Progress) + Send + Sync>;
@@ -1,6 +1,5 @@
 use super::object::GitObject;
-use chrono::naive::NaiveDateTime;
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, TimeZone};
 use nom::character::complete::{digit1, line_ending, newline, space0, space1};
 use nom::combinator::rest;
 use std::str::from_utf8;
@@ -13,6 +12,23 @@ pub struct Person<'a> {
     timestamp: DateTime<FixedOffset>,
 }
 
+impl<'a> Person<'a> {
+    /// returns the name of the person
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// returns the email of the person, without its angle brackets
+    pub fn email(&self) -> &'a str {
+        self.email
+    }
+
+    /// returns the date of the signature, in the time zone it was made in
+    pub fn timestamp(&self) -> DateTime<FixedOffset> {
+        self.timestamp
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Commit<'a> {
     pub tree: &'a str,
@@ -20,7 +36,6 @@ pub struct Commit<'a> {
     author: Person<'a>,
     committer: Person<'a>,
     message: &'a str,
-    raw: &'a GitObject,
 }
 
 impl<'a> Commit<'a> {
@@ -38,7 +53,6 @@ impl<'a> Commit<'a> {
                 author,
                 committer,
                 message,
-                raw: obj,
             }
         })
     }
@@ -48,14 +62,13 @@ impl<'a> Commit<'a> {
         self.message.to_string()
     }
 
-
     /// returns commit author
-    pub fn get_author(&self) -> Person {
+    pub fn get_author(&self) -> Person<'_> {
         self.author.clone()
     }
 
     /// returns commit committer
-    pub fn get_committer(&self) -> Person {
+    pub fn get_committer(&self) -> Person<'_> {
         self.committer.clone()
     }
 }
@@ -78,7 +91,7 @@ named!(i32_digit(&[u8]) -> i32,
     FromStr::from_str)
 );
 
-named!(parse_person(&[u8]) -> Person,
+named!(pub(super) parse_person(&[u8]) -> Person<'_>,
     do_parse!(
         name: map_res!(take_until!(" <"), from_utf8) >>
         take!(2) >>
@@ -87,27 +100,24 @@ named!(parse_person(&[u8]) -> Person,
         ts: u64_digit >>
         space1 >>
         sign: alt!(char!('+') | char!('-')) >>
-        tz: i32_digit >>
+        timestamp: map_opt!(i32_digit, |tz| to_timestamp(ts, sign, tz)) >>
         newline >>
-        ({
-            let sgn = if sign == '-' {
-                -1
-            } else {
-                1
-            };
-            let naive = NaiveDateTime::from_timestamp(ts as i64, 0);
-            let offset = FixedOffset::east(sgn * tz/100 * 3600);
-            let timestamp = DateTime::from_utc(naive, offset);
-            Person {
-                name,
-                email,
-                timestamp,
-            }
+        (Person {
+            name,
+            email,
+            timestamp,
         })
     )
 );
 
-named!(parse_commit_inner(&[u8]) -> (&str, Vec<&str>, Person, Person, &str),
+/// None when the time or the offset are out of range
+fn to_timestamp(ts: u64, sign: char, tz: i32) -> Option<DateTime<FixedOffset>> {
+    let sgn = if sign == '-' { -1 } else { 1 };
+    let offset = FixedOffset::east_opt(sgn * (tz / 100).checked_mul(3600)?)?;
+    offset.timestamp_opt(ts as i64, 0).single()
+}
+
+named!(parse_commit_inner(&[u8]) -> (&str, Vec<&str>, Person<'_>, Person<'_>, &str),
   do_parse!(
     tag!("tree ") >>
     tree: map_res!(take!(40), from_utf8) >>
@@ -144,11 +154,19 @@ mod tests {
         let input = b"The Author <author@devs.com> 1353116070 +1100\n";
 
         if let IResult::Ok((_, person)) = parse_person(&input[..]) {
-            assert_eq!(person.name, "The Author");
-            assert_eq!(person.email, "author@devs.com");
+            assert_eq!(person.name(), "The Author");
+            assert_eq!(person.email(), "author@devs.com");
+            assert_eq!(person.timestamp().timestamp(), 1353116070);
+            assert_eq!(person.timestamp().offset().local_minus_utc(), 11 * 3600);
         }
     }
 
+    #[test]
+    fn out_of_range_dates_are_not_parsed() {
+        assert!(parse_person(&b"A <a@b.c> 1353116070 +9999\n"[..]).is_err());
+        assert!(parse_person(&b"A <a@b.c> 99999999999999999999 +0100\n"[..]).is_err());
+    }
+
     #[test]
     fn test_commit_parsing() {
         let input = b"tree asdf456789012345678901234567890123456789\n\
@@ -159,7 +177,7 @@ mod tests {
             \n\
             Bump version to 1.6";
         let input2 = b"tree 9f5829a852fcd8e3381e343b45cb1c9ff33abf56\nauthor Christian Briones <christian@whisper.sh> 1418004896 -0800\ncommitter Christian Briones <christian@whisper.sh> 1418004914 -0800\n\ninit\n";
-        let object = GitObject::new(GitObjectType::Commit, (&input[..]).to_owned());
+        let object = GitObject::new(GitObjectType::Commit, input[..].to_owned());
         if let Some(commit) = Commit::from_raw(&object) {
             assert_eq!(commit.tree, "asdf456789012345678901234567890123456789");
             let parents = vec![
@@ -172,7 +190,7 @@ mod tests {
             panic!("Failed to parse commit.");
         }
 
-        let object2 = GitObject::new(GitObjectType::Commit, (&input2[..]).to_owned());
+        let object2 = GitObject::new(GitObjectType::Commit, input2[..].to_owned());
         assert!(Commit::from_raw(&object2).is_some())
     }
 }
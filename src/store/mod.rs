@@ -1,3 +1,4 @@
 pub mod commit;
 pub mod object;
+pub mod tag;
 pub mod tree;
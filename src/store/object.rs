@@ -1,15 +1,18 @@
 use crate::delta;
+use crate::error::{Error, Result};
 use crate::store::commit::Commit;
+use crate::store::tag::Tag;
 use crate::store::tree::Tree;
-use crate::utils::sha1_hash_hex;
+use crate::utils::{is_sha, sha1_hash_hex};
 use flate2::read::ZlibDecoder;
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
 use std::cell::RefCell;
 use std::fs;
 use std::fs::File;
-use std::io::{Error, ErrorKind, Read, Result as IOResult, Write};
+use std::io::{Read, Write};
 use std::path::PathBuf;
+use std::str::FromStr;
 
 #[derive(Debug, Copy, Clone, FromPrimitive, PartialEq)]
 pub enum GitObjectType {
@@ -19,6 +22,22 @@ pub enum GitObjectType {
     Tag = 4,
 }
 
+impl FromStr for GitObjectType {
+    type Err = Error;
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "commit" => Ok(GitObjectType::Commit),
+            "tree" => Ok(GitObjectType::Tree),
+            "blob" => Ok(GitObjectType::Blob),
+            "tag" => Ok(GitObjectType::Tag),
+            _ => Err(Error::CorruptObject(format!(
+                "unknown object type {}",
+                name
+            ))),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct GitObject {
     pub object_type: GitObjectType,
@@ -35,10 +54,10 @@ impl GitObject {
         }
     }
 
-    pub fn patch(&self, patch: &[u8]) -> IOResult<Self> {
+    pub fn patch(&self, patch: &[u8]) -> Result<Self> {
         Ok(GitObject {
             object_type: self.object_type,
-            content: delta::patch(&self.content, &patch)?,
+            content: delta::patch(&self.content, patch)?,
             sha: RefCell::new(None),
         })
     }
@@ -46,29 +65,37 @@ impl GitObject {
     ///
     /// Opens the given object from loose form in the repo.
     ///
-    pub fn open(repo: &str, sha1: &str) -> IOResult<Self> {
-        let path = object_path(repo, sha1);
+    pub fn open(git_dir: &str, sha1: &str) -> Result<Self> {
+        let path = object_path(git_dir, sha1);
+        GitObject::inflate(File::open(path)?, sha1)
+    }
+
+    ///
+    /// Reads an object in loose form, as stored under `objects/`.
+    ///
+    pub(crate) fn inflate<R: Read>(loose: R, sha1: &str) -> Result<Self> {
         let mut inflated = Vec::new();
-        let file = File::open(path)?;
-        let mut z = ZlibDecoder::new(file);
+        let mut z = ZlibDecoder::new(loose);
         z.read_to_end(&mut inflated)?;
 
         let sha1_checksum = sha1_hash_hex(&inflated);
         if sha1_checksum != sha1 {
-            return Err(Error::new(ErrorKind::Other, "bad checksum"));
+            let err = format!("bad checksum for {}", sha1);
+            return Err(Error::CorruptObject(err));
         }
 
-        let split_idx = inflated.iter().position(|x| *x == 0).unwrap();
-        let (object_type, size) = {
-            let header = std::str::from_utf8(&inflated[..split_idx]).unwrap();
-            GitObject::parse_header(header)
-        };
+        let split_idx = inflated
+            .iter()
+            .position(|x| *x == 0)
+            .ok_or_else(|| Error::CorruptObject(format!("no header in {}", sha1)))?;
+        let (object_type, size) = GitObject::parse_header(&inflated[..split_idx])?;
 
         let mut footer = Vec::new();
         footer.extend_from_slice(&inflated[split_idx + 1..]);
 
         if footer.len() != size {
-            return Err(Error::new(ErrorKind::Other, "footer len != size"));
+            let err = format!("the size of {} doesn't match its header", sha1);
+            return Err(Error::CorruptObject(err));
         }
 
         Ok(GitObject {
@@ -79,18 +106,17 @@ impl GitObject {
     }
 
     #[allow(unused)]
-    pub fn write(&self, repo: &str) -> IOResult<()> {
+    pub fn write(&self, git_dir: &str) -> Result<()> {
         let (sha1, blob) = self.encode();
-        let path = object_path(repo, &sha1);
+        let path = object_path(git_dir, &sha1);
 
-        fs::create_dir_all(
-            path.parent()
-                .ok_or_else(|| Error::new(ErrorKind::Other, "no path to write"))?,
-        )?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
 
         let file = File::create(&path)?;
         let mut z = ZlibEncoder::new(file, Compression::default());
-        z.write_all(&blob[..])
+        Ok(z.write_all(&blob[..])?)
     }
 
     ///
@@ -118,45 +144,43 @@ impl GitObject {
     }
 
     fn header(&self) -> Vec<u8> {
+        GitObject::header_for(self.object_type, self.content.len())
+    }
+
+    ///
+    /// The header hashed before the content of an object of the given type
+    /// and size.
+    ///
+    pub(crate) fn header_for(object_type: GitObjectType, size: usize) -> Vec<u8> {
         // header:
         // "type size \0"
-        let str_type = match self.object_type {
+        let str_type = match object_type {
             GitObjectType::Commit => "commit",
             GitObjectType::Tree => "tree",
             GitObjectType::Blob => "blob",
             GitObjectType::Tag => "tag",
         };
-        let str_size = self.content.len().to_string();
+        let str_size = size.to_string();
         let res = format!("{} {}\0", str_type, &str_size[..]);
         res.into_bytes()
     }
 
-    fn parse_header(header: &str) -> (GitObjectType, usize) {
-        let split: Vec<&str> = header.split(' ').collect();
-        if split.len() == 2 {
-            let (t, s) = (split[0], split[1]);
-            let obj_type = match t {
-                "commit" => GitObjectType::Commit,
-                "tree" => GitObjectType::Tree,
-                "blob" => GitObjectType::Blob,
-                "tag" => GitObjectType::Tag,
-                _ => panic!("unknown object type"),
-            };
-            let size = s.parse::<usize>().unwrap();
-
-            (obj_type, size)
-        } else {
-            panic!("Bad object header")
-        }
+    fn parse_header(header: &[u8]) -> Result<(GitObjectType, usize)> {
+        let header = String::from_utf8_lossy(header);
+        let bad_header = || Error::CorruptObject(format!("bad object header: {}", header));
+        let (t, s) = header.split_once(' ').ok_or_else(bad_header)?;
+        let obj_type = GitObjectType::from_str(t).map_err(|_| bad_header())?;
+        let size = s.parse::<usize>().map_err(|_| bad_header())?;
+        Ok((obj_type, size))
     }
 
     ///
     /// Parses the internal representation of this object into a Commit.
     /// Returns `None` if the object is not a Commit.
     ///
-    pub fn as_commit(&self) -> Option<Commit> {
+    pub fn as_commit(&self) -> Option<Commit<'_>> {
         if let GitObjectType::Commit = self.object_type {
-            Commit::from_raw(&self)
+            Commit::from_raw(self)
         } else {
             None
         }
@@ -173,14 +197,86 @@ impl GitObject {
             None
         }
     }
+
+    ///
+    /// Parses the internal representation of this object into an annotated Tag.
+    /// Returns `None` if the object is not a Tag.
+    ///
+    pub fn as_tag(&self) -> Option<Tag<'_>> {
+        if let GitObjectType::Tag = self.object_type {
+            Tag::parse(&self.content)
+        } else {
+            None
+        }
+    }
+}
+
+///
+/// Lists the shas of every loose object in the repo.
+///
+pub fn loose_objects(git_dir: &str) -> Result<Vec<String>> {
+    let mut path = PathBuf::new();
+    path.push(git_dir);
+    path.push("objects");
+    let mut shas = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let prefix = entry.file_name().to_string_lossy().into_owned();
+        if prefix.len() != 2 || !entry.path().is_dir() {
+            continue;
+        }
+        for object in fs::read_dir(entry.path())? {
+            let sha = format!("{}{}", prefix, object?.file_name().to_string_lossy());
+            if is_sha(&sha) {
+                shas.push(sha);
+            }
+        }
+    }
+    Ok(shas)
 }
 
-fn object_path(repo: &str, sha: &str) -> PathBuf {
+fn object_path(git_dir: &str, sha: &str) -> PathBuf {
     let mut path = PathBuf::new();
-    path.push(repo);
-    path.push(".git");
+    path.push(git_dir);
     path.push("objects");
     path.push(&sha[..2]);
     path.push(&sha[2..40]);
     path
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loose(contents: &[u8]) -> (Vec<u8>, String) {
+        let mut z = ZlibEncoder::new(Vec::new(), Compression::default());
+        z.write_all(contents).unwrap();
+        (z.finish().unwrap(), sha1_hash_hex(contents))
+    }
+
+    #[test]
+    fn reading_a_loose_object() {
+        let (compressed, sha) = loose(b"blob 5\0hello");
+        let object = GitObject::inflate(&compressed[..], &sha).unwrap();
+        assert_eq!(object.object_type, GitObjectType::Blob);
+        assert_eq!(object.content, b"hello");
+        assert_eq!(object.sha(), sha);
+    }
+
+    #[test]
+    fn malformed_loose_objects_are_errors() {
+        for contents in &[
+            &b"blob 5 hello"[..],
+            b"blob five\0hello",
+            b"note 5\0hello",
+            b"blob 9\0hello",
+        ] {
+            let (compressed, sha) = loose(contents);
+            let result = GitObject::inflate(&compressed[..], &sha);
+            assert!(matches!(result, Err(Error::CorruptObject(_))));
+        }
+        let (compressed, _) = loose(b"blob 5\0hello");
+        let result = GitObject::inflate(&compressed[..], &"0".repeat(40));
+        assert!(matches!(result, Err(Error::CorruptObject(_))));
+    }
+}
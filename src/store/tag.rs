@@ -0,0 +1,151 @@
+use super::commit::{parse_person, Person};
+use super::object::GitObjectType;
+use nom::character::complete::newline;
+use nom::combinator::rest;
+use std::str::{self, from_utf8, FromStr};
+
+/// The lines starting the signatures git embeds at the end of the message
+const SIGNATURE_STARTS: [&str; 4] = [
+    "-----BEGIN PGP SIGNATURE-----",
+    "-----BEGIN PGP MESSAGE-----",
+    "-----BEGIN SSH SIGNATURE-----",
+    "-----BEGIN SIGNED MESSAGE-----",
+];
+
+#[derive(Debug, Clone)]
+pub struct Tag<'a> {
+    /// the sha of the tagged object
+    pub object: &'a str,
+    pub object_type: GitObjectType,
+    /// the name of the tag, without `refs/tags/`
+    pub name: &'a str,
+    tagger: Option<Person<'a>>,
+    message: &'a str,
+    signature: Option<&'a str>,
+}
+
+impl<'a> Tag<'a> {
+    pub fn parse(content: &'a [u8]) -> Option<Self> {
+        let (_, (object, object_type, name, tagger, body)) = parse_tag_inner(content).ok()?;
+        // Headers git doesn't know about are skipped up to the blank line
+        let message = match body.strip_prefix('\n') {
+            Some(message) => message,
+            None => body.find("\n\n").map_or("", |end| &body[end + 2..]),
+        };
+        let (message, signature) = split_signature(message);
+        Some(Tag {
+            object,
+            object_type,
+            name,
+            tagger,
+            message,
+            signature,
+        })
+    }
+
+    /// returns tag message, without its signature
+    pub fn get_message(&self) -> String {
+        self.message.to_string()
+    }
+
+    /// returns tag tagger, old tags may not have one
+    pub fn get_tagger(&self) -> Option<Person<'_>> {
+        self.tagger.clone()
+    }
+
+    /// returns the signature embedded in the message of signed tags
+    pub fn get_signature(&self) -> Option<String> {
+        self.signature.map(|s| s.to_string())
+    }
+}
+
+///
+/// Splits the message from the signature following it, which starts at the
+/// last line opening a signature, as git does.
+///
+fn split_signature(message: &str) -> (&str, Option<&str>) {
+    let mut start = None;
+    let mut offset = 0;
+    for line in message.split_inclusive('\n') {
+        if SIGNATURE_STARTS.iter().any(|s| line.starts_with(s)) {
+            start = Some(offset);
+        }
+        offset += line.len();
+    }
+    match start {
+        Some(start) => (&message[..start], Some(&message[start..])),
+        None => (message, None),
+    }
+}
+
+named!(parse_tag_inner(&[u8]) -> (&str, GitObjectType, &str, Option<Person<'_>>, &str),
+    do_parse!(
+        tag!("object ") >>
+        object: map_res!(take!(40), from_utf8) >>
+        newline >>
+        tag!("type ") >>
+        object_type: map_res!(map_res!(take_until!("\n"), from_utf8), GitObjectType::from_str) >>
+        newline >>
+        tag!("tag ") >>
+        name: map_res!(take_until!("\n"), from_utf8) >>
+        newline >>
+        tagger: opt!(complete!(preceded!(tag!("tagger "), parse_person))) >>
+        body: map_res!(rest, from_utf8) >>
+        ((object, object_type, name, tagger, body))
+    )
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::TAG;
+    use crate::store::object::GitObject;
+    use std::fs::File;
+
+    #[test]
+    fn parsing_an_annotated_tag() {
+        let path = format!("tests/data/tags/{}/{}", &TAG[..2], &TAG[2..]);
+        let object = GitObject::inflate(File::open(path).unwrap(), TAG).unwrap();
+        let tag = object.as_tag().unwrap();
+        assert_eq!(tag.object, "3c7cfac73a699ef415bc737ce5529ac66c5692a9");
+        assert_eq!(tag.object_type, GitObjectType::Commit);
+        assert_eq!(tag.name, "test_tag");
+        let tagger = tag.get_tagger().unwrap();
+        assert_eq!(tagger.name(), "Christian Briones");
+        assert_eq!(tagger.email(), "cwbriones@gmail.com");
+        assert_eq!(
+            tag.get_message(),
+            "This is an example of an annotated tag.\n"
+        );
+        assert!(tag.get_signature().is_none());
+        assert!(object.as_commit().is_none());
+    }
+
+    #[test]
+    fn parsing_a_signed_tag_without_tagger() {
+        let content = b"object 3c7cfac73a699ef415bc737ce5529ac66c5692a9\n\
+            type tree\n\
+            tag v1.0\n\
+            \n\
+            Release 1.0\n\
+            -----BEGIN PGP SIGNATURE-----\n\
+            \n\
+            iQEzBAABCAAdFiEE\n\
+            -----END PGP SIGNATURE-----\n";
+        let tag = Tag::parse(&content[..]).unwrap();
+        assert_eq!(tag.object_type, GitObjectType::Tree);
+        assert_eq!(tag.name, "v1.0");
+        assert!(tag.get_tagger().is_none());
+        assert_eq!(tag.get_message(), "Release 1.0\n");
+        assert_eq!(
+            tag.get_signature().unwrap(),
+            "-----BEGIN PGP SIGNATURE-----\n\niQEzBAABCAAdFiEE\n-----END PGP SIGNATURE-----\n"
+        );
+
+        assert!(Tag::parse(b"object 3c7c\ntype commit\n").is_none());
+        assert!(
+            Tag::parse(b"object 3c7cfac73a699ef415bc737ce5529ac66c5692a9\ntype note\ntag x\n")
+                .is_none()
+        );
+    }
+}
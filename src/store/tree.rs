@@ -49,14 +49,14 @@ impl FromStr for EntryMode {
 
 named!(parse_tree_entry(&[u8]) -> TreeEntry,
     do_parse!(
-        mode: map_res!(take_until!(" "), from_utf8)  >>
+        mode: map_res!(map_res!(take_until!(" "), from_utf8), EntryMode::from_str)  >>
         space1  >>
         path: map_res!(take_until!("\0"), from_utf8) >>
         take!(1) >>
         sha: take!(20) >>
         (
         TreeEntry {
-            mode: EntryMode::from_str(mode).unwrap(),
+            mode,
             path: path.to_string(),
             sha: sha.to_hex(),
         }
@@ -94,9 +94,7 @@ fn test_parse_tree() {
         99, 0, 44, 153, 32, 248, 175, 44, 114, 130, 179, 183, 191, 144, 34, 196, 7, 92, 15, 177,
         105, 86,
     ];
-    if let IResult::Ok((_, _)) = parse_tree_entries(&input) {
-        ()
-    } else {
+    if parse_tree_entries(&input).is_err() {
         panic!("Failed to parse tree");
     }
 }
@@ -0,0 +1,250 @@
+use rustc_serialize::hex::FromHex;
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::packfile::index::PackIndex;
+use crate::packfile::packfile_parser::PackFileParser;
+use crate::packfile::refs::{Ref, Refs};
+use crate::packfile::{open_packs, PackFile, PackWriter};
+use crate::store::object::{GitObject, GitObjectType};
+use crate::store::tree::EntryMode;
+use crate::transport::client::FetchRequest;
+use crate::utils::is_sha;
+
+///
+/// Parses the `info/refs` file of a repository served by a dumb server,
+/// one `<sha>\t<name>` line per ref.
+///
+pub(crate) fn parse_info_refs(contents: &str) -> Result<Refs> {
+    let mut refs = Vec::new();
+    for line in contents.lines().filter(|l| !l.is_empty()) {
+        match line.split_once('\t') {
+            Some((id, name)) => refs.push(Ref {
+                id: id.to_owned(),
+                name: name.to_owned(),
+            }),
+            None => {
+                let err = format!("unexpected info/refs line: {}", line);
+                return Err(Error::Protocol(err));
+            }
+        }
+    }
+    Ok(refs)
+}
+
+///
+/// Fetches the objects of a repository served as static files, walking
+/// from the wanted refs. Objects are read loose from `objects/xx/...`, or
+/// else from the packs listed in `objects/info/packs`, downloaded whole.
+/// See https://git-scm.com/docs/http-protocol#_dumb_clients
+///
+pub(crate) struct DumbFetch<F> {
+    /// reads a file of the repository, `None` when it doesn't exist
+    get: F,
+    /// the packs of the server not downloaded yet, with their index
+    packs: Option<Vec<(String, PackIndex)>>,
+    downloaded: Vec<PackFile>,
+}
+
+impl<F, R> DumbFetch<F>
+where
+    F: FnMut(&str) -> Result<Option<R>>,
+    R: Read,
+{
+    pub fn new(get: F) -> Self {
+        DumbFetch {
+            get,
+            packs: None,
+            downloaded: Vec::new(),
+        }
+    }
+
+    ///
+    /// Writes every object reachable from the wants to a pack, not walking
+    /// past the objects the client already has, and returns its parser.
+    /// The packs downloaded along the way are read from temporary files.
+    ///
+    pub fn fetch(&mut self, request: &FetchRequest) -> Result<PackFileParser> {
+        let local_packs = match request.git_dir {
+            Some(git_dir) => open_packs(git_dir)?,
+            None => vec![],
+        };
+        let mut seen: HashSet<String> = HashSet::new();
+        let mut stack: Vec<String> = request.wants.iter().map(|r| r.id.clone()).collect();
+        let mut pack = PackWriter::create(request.git_dir)?;
+        while let Some(sha) = stack.pop() {
+            if request.haves.has(&sha) || !seen.insert(sha.clone()) {
+                continue;
+            }
+            // What the client has is complete, like the history of its haves
+            if has_locally(&sha, request.git_dir, &local_packs) {
+                continue;
+            }
+            let object = self.read_object(&sha, request)?;
+            match object.object_type {
+                GitObjectType::Commit => {
+                    let commit = object
+                        .as_commit()
+                        .ok_or_else(|| Error::CorruptObject(format!("invalid commit {}", sha)))?;
+                    stack.push(commit.tree.to_owned());
+                    stack.extend(commit.parents.iter().map(|p| p.to_string()));
+                }
+                GitObjectType::Tree => {
+                    let tree = object
+                        .as_tree()
+                        .ok_or_else(|| Error::CorruptObject(format!("invalid tree {}", sha)))?;
+                    // Submodule commits live in another repository
+                    stack.extend(
+                        tree.entries
+                            .into_iter()
+                            .filter(|e| !matches!(e.mode, EntryMode::Gitlink))
+                            .map(|e| e.sha),
+                    );
+                }
+                GitObjectType::Tag => stack.extend(object.as_tag().map(|t| t.object.to_owned())),
+                GitObjectType::Blob => (),
+            }
+            pack.add(&object)?;
+        }
+        let mut parser = request.parser();
+        parser.receive_file(pack.finish()?)?;
+        Ok(parser)
+    }
+
+    fn read_object(&mut self, sha: &str, request: &FetchRequest) -> Result<GitObject> {
+        if !is_sha(sha) {
+            return Err(Error::Protocol(format!("bad sha {}", sha)));
+        }
+        if let Some(object) = self.find_downloaded(sha)? {
+            return Ok(object);
+        }
+        let path = format!("objects/{}/{}", &sha[..2], &sha[2..]);
+        if let Some(loose) = (self.get)(&path)? {
+            return GitObject::inflate(loose, sha);
+        }
+        if self.download_pack_with(sha, request)? {
+            if let Some(object) = self.find_downloaded(sha)? {
+                return Ok(object);
+            }
+        }
+        Err(Error::MissingObject(sha.to_owned()))
+    }
+
+    fn find_downloaded(&self, sha: &str) -> Result<Option<GitObject>> {
+        for pack in self.downloaded.iter() {
+            if let Some(object) = pack.find_by_sha(sha)? {
+                return Ok(Some(object));
+            }
+        }
+        Ok(None)
+    }
+
+    ///
+    /// Downloads the pack holding the object, if any of the server does,
+    /// writing it to the repository as it is received.
+    ///
+    fn download_pack_with(&mut self, sha: &str, request: &FetchRequest) -> Result<bool> {
+        if self.packs.is_none() {
+            self.packs = Some(self.read_pack_indexes()?);
+        }
+        let sha_bytes = sha
+            .from_hex()
+            .map_err(|_| Error::Protocol(format!("bad sha {}", sha)))?;
+        let packs = self.packs.as_mut().unwrap();
+        let position = packs
+            .iter()
+            .position(|(_, idx)| idx.find(&sha_bytes).is_some());
+        let name = match position {
+            Some(i) => packs.remove(i).0,
+            None => return Ok(false),
+        };
+        let path = format!("objects/pack/{}", name);
+        let contents =
+            (self.get)(&path)?.ok_or_else(|| Error::Transport(format!("{} not found", path)))?;
+        let mut parser = PackFileParser::new();
+        parser.set_git_dir(request.git_dir);
+        parser.set_cancellation(request.cancel.clone());
+        parser.receive_from(contents)?;
+        self.downloaded.push(parser.parse(None)?);
+        Ok(true)
+    }
+
+    fn read_pack_indexes(&mut self) -> Result<Vec<(String, PackIndex)>> {
+        let list = match self.read_file("objects/info/packs")? {
+            Some(list) => String::from_utf8_lossy(&list).into_owned(),
+            None => return Ok(vec![]),
+        };
+        let mut packs = Vec::new();
+        for line in list.lines() {
+            // Lines like `P pack-<sha>.pack`
+            let name = match line.strip_prefix("P ") {
+                Some(name) => name.trim(),
+                None => continue,
+            };
+            let path = format!("objects/pack/{}", name.replace(".pack", ".idx"));
+            if let Some(idx) = self.read_file(&path)? {
+                packs.push((name.to_owned(), PackIndex::parse(&idx)?));
+            }
+        }
+        Ok(packs)
+    }
+
+    fn read_file(&mut self, path: &str) -> Result<Option<Vec<u8>>> {
+        match (self.get)(path)? {
+            Some(mut file) => {
+                let mut contents = Vec::new();
+                file.read_to_end(&mut contents)?;
+                Ok(Some(contents))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+///
+/// Whether the repository has the object, loose or in one of its packs.
+///
+fn has_locally(sha: &str, git_dir: Option<&str>, packs: &[PackFile]) -> bool {
+    let git_dir = match git_dir {
+        Some(git_dir) if is_sha(sha) => git_dir,
+        _ => return false,
+    };
+    let loose = Path::new(git_dir)
+        .join("objects")
+        .join(&sha[..2])
+        .join(&sha[2..]);
+    if loose.is_file() {
+        return true;
+    }
+    match sha.from_hex() {
+        Ok(bytes) => packs.iter().any(|p| p.index.find(&bytes).is_some()),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing_info_refs() {
+        let refs = parse_info_refs(
+            "33676d1c63d868803ed110b13be4e616bc8a29b7\trefs/heads/master\n\
+             7a4219fa5df9550fa54636f2783cd7c3cb63b1f3\trefs/tags/test_tag\n\
+             3c7cfac73a699ef415bc737ce5529ac66c5692a9\trefs/tags/test_tag^{}\n",
+        )
+        .unwrap();
+        let names: Vec<&str> = refs.iter().map(|r| &r.name[..]).collect();
+        assert_eq!(
+            names,
+            vec![
+                "refs/heads/master",
+                "refs/tags/test_tag",
+                "refs/tags/test_tag^{}"
+            ]
+        );
+        assert!(parse_info_refs("<html>not found</html>").is_err());
+    }
+}
@@ -1,27 +1,70 @@
-use std::io::Result as IOResult;
+use percent_encoding::percent_decode;
+use std::process::Command;
 
+use super::process::ProcessConnection;
+use crate::cancel::CancellationToken;
+use crate::error::Result;
 use crate::packfile::packfile_parser::PackFileParser;
-use crate::packfile::refs::{Ref, Refs};
-use crate::transport::client::Protocol;
+use crate::packfile::refs::Refs;
+use crate::transport::client::{FetchRequest, Protocol, ServerCapabilities};
 
+///
+/// Fetches from a repository on the local filesystem by spawning
+/// git-upload-pack, just like a remote one would be.
+///
 #[derive(Debug)]
 pub struct FileProtocol {
     path: String,
+    connection: Option<ProcessConnection>,
+    cancel: Option<CancellationToken>,
 }
 
 impl FileProtocol {
+    ///
+    /// Fetches from the path of a `file://` url, still percent-encoded.
+    ///
     pub fn new(path: String) -> Self {
-        FileProtocol { path }
+        let path = percent_decode(path.as_bytes())
+            .decode_utf8_lossy()
+            .into_owned();
+        FileProtocol::with_path(path)
+    }
+
+    ///
+    /// Fetches from a path taken as it is, like the ones of local clones.
+    ///
+    pub fn with_path(path: String) -> Self {
+        FileProtocol {
+            path,
+            connection: None,
+            cancel: None,
+        }
     }
 }
 
 impl Protocol for FileProtocol {
-    fn discover_refs(&mut self) -> IOResult<Refs> {
-        unimplemented!()
+    fn discover_refs(&mut self) -> Result<(Refs, ServerCapabilities)> {
+        let (connection, refs, capabilities) = ProcessConnection::spawn(
+            // A path starting with a dash isn't taken as an option
+            Command::new("git")
+                .arg("upload-pack")
+                .arg("--")
+                .arg(&self.path),
+            &self.cancel,
+        )?;
+        self.connection = Some(connection);
+        Ok((refs, capabilities))
+    }
+
+    fn fetch_packfile(&mut self, request: &FetchRequest) -> Result<PackFileParser> {
+        if self.connection.is_none() {
+            self.discover_refs()?;
+        }
+        self.connection.take().unwrap().fetch_packfile(request)
     }
 
-    fn fetch_packfile(&mut self, _reference: &[Ref]) -> IOResult<PackFileParser> {
-        unimplemented!()
+    fn set_cancellation(&mut self, cancel: CancellationToken) {
+        self.cancel = Some(cancel);
     }
 
     fn protocol(&self) -> &'static str {
@@ -1,31 +1,265 @@
-use std::io::Result as IOResult;
+use percent_encoding::percent_decode;
+use std::io::{BufReader, Error as IoError, ErrorKind, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::time::Duration;
 use url::Url;
 
+use super::packet::{fetch_packfile_stateful, packet_line, parse_refs_lines, receive_packet};
+use crate::cancel::{on_cancel, AbortOnCancel, CancellationToken};
+use crate::error::{Error, Result};
 use crate::packfile::packfile_parser::PackFileParser;
-use crate::packfile::refs::{Ref, Refs};
-use crate::transport::client::Protocol;
+use crate::packfile::refs::Refs;
+use crate::transport::client::{FetchRequest, Protocol, ServerCapabilities};
+use crate::transport::url_parser::UrlError;
 
+const DEFAULT_PORT: u16 = 9418;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const READ_TIMEOUT: Duration = Duration::from_secs(120);
+
+///
+/// Speaks to a git daemon over TCP, see
+/// https://git-scm.com/docs/pack-protocol#_git_transport
+///
 #[derive(Debug)]
 pub struct GitProtocol {
     url: Url,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    connection: Option<GitConnection>,
+    cancel: Option<CancellationToken>,
+}
+
+#[derive(Debug)]
+struct GitConnection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    capabilities: ServerCapabilities,
+    /// shuts the connection down when the token is cancelled
+    _abort: Option<AbortOnCancel>,
 }
 
 impl GitProtocol {
     pub fn new(url: &Url) -> Self {
-        GitProtocol { url: url.clone() }
+        GitProtocol::with_timeouts(url, CONNECT_TIMEOUT, READ_TIMEOUT)
+    }
+
+    pub fn with_timeouts(url: &Url, connect_timeout: Duration, read_timeout: Duration) -> Self {
+        GitProtocol {
+            url: url.clone(),
+            connect_timeout,
+            read_timeout,
+            connection: None,
+            cancel: None,
+        }
+    }
+
+    fn open_stream(&self, host: &str, port: u16) -> std::io::Result<TcpStream> {
+        let mut last_error = IoError::other(format!("can't resolve {}", host));
+        for addr in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.connect_timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    ///
+    /// Asks the daemon for the service and reads the refs and capabilities
+    /// it advertises.
+    ///
+    fn connect(&mut self, service: &str) -> Result<(Refs, ServerCapabilities)> {
+        let host = self.url.host_str().ok_or(UrlError::NoServer)?;
+        let port = self.url.port().unwrap_or(DEFAULT_PORT);
+        let stream = self.open_stream(host, port)?;
+        stream.set_read_timeout(Some(self.read_timeout))?;
+        stream.set_write_timeout(Some(self.read_timeout))?;
+
+        let path = percent_decode(self.url.path().as_bytes()).decode_utf8_lossy();
+        let host_param = match self.url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_owned(),
+        };
+        let request = format!("{} {}\0host={}\0", service, path, host_param);
+        let mut writer = stream.try_clone()?;
+        let shut = stream.try_clone()?;
+        let abort = on_cancel(&self.cancel, move || {
+            let _ = shut.shutdown(Shutdown::Both);
+        });
+        writer.write_all(packet_line(&request).as_bytes())?;
+        writer.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        let lines = match receive_packet(&mut reader) {
+            Err(Error::Io(ref e)) if e.kind() == ErrorKind::UnexpectedEof => vec![],
+            res => res?,
+        };
+        if lines.is_empty() {
+            // The daemon just hangs up on repositories it doesn't export
+            return Err(Error::RepositoryNotFound(path.into_owned()));
+        }
+        let (refs, capabilities) = parse_refs_lines(&lines)?;
+        self.connection = Some(GitConnection {
+            reader,
+            writer,
+            capabilities: capabilities.clone(),
+            _abort: abort,
+        });
+        Ok((refs, capabilities))
+    }
+}
+
+impl Drop for GitConnection {
+    fn drop(&mut self) {
+        // A flush ends the conversation when nothing was fetched
+        let _ = self.writer.write_all(b"0000");
     }
 }
 
 impl Protocol for GitProtocol {
-    fn discover_refs(&mut self) -> IOResult<Refs> {
-        unimplemented!()
+    fn discover_refs(&mut self) -> Result<(Refs, ServerCapabilities)> {
+        self.connect("git-upload-pack")
+    }
+
+    fn fetch_packfile(&mut self, request: &FetchRequest) -> Result<PackFileParser> {
+        if self.connection.is_none() {
+            self.connect("git-upload-pack")?;
+        }
+        let mut connection = self.connection.take().unwrap();
+        let capabilities = request.capabilities(&connection.capabilities)?;
+        fetch_packfile_stateful(
+            &mut connection.reader,
+            &mut connection.writer,
+            &capabilities,
+            request,
+        )
     }
 
-    fn fetch_packfile(&mut self, _reference: &[Ref]) -> IOResult<PackFileParser> {
-        unimplemented!()
+    fn set_cancellation(&mut self, cancel: CancellationToken) {
+        self.cancel = Some(cancel);
     }
 
     fn protocol(&self) -> &'static str {
         "git-protocol"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{fixture_remote, skip_without_git, MASTER};
+    use crate::packfile::refs::Ref;
+    use crate::transport::client::haves::Haves;
+    use crate::transport::client::packet::read_packet_line;
+    use std::net::TcpListener;
+    use std::os::fd::OwnedFd;
+    use std::process::{Command, Stdio};
+    use std::thread::{self, JoinHandle};
+
+    ///
+    /// Serves one connection like a git daemon, handing it to git-upload-pack
+    /// when `serve` is true. Returns the port and the request line received.
+    ///
+    fn fake_daemon(name: &str, serve: bool) -> (u16, JoinHandle<String>) {
+        let remote = fixture_remote(name);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_packet_line(&mut stream).unwrap().unwrap();
+            if serve {
+                let input = OwnedFd::from(stream.try_clone().unwrap());
+                Command::new("git-upload-pack")
+                    .arg("--strict")
+                    .arg(&remote)
+                    .stdin(Stdio::from(input))
+                    .stdout(Stdio::from(OwnedFd::from(stream)))
+                    .status()
+                    .unwrap();
+            }
+            String::from_utf8(request)
+                .unwrap()
+                .replace(remote.to_str().unwrap(), "<remote>")
+        });
+        (port, handle)
+    }
+
+    fn git_url(name: &str, port: u16) -> Url {
+        let remote = std::env::temp_dir().join(format!("rs-git-lib-remote-{}.git", name));
+        Url::parse(&format!("git://127.0.0.1:{}{}", port, remote.display())).unwrap()
+    }
+
+    #[test]
+    fn fetching_a_packfile_from_a_daemon() {
+        if skip_without_git() {
+            return;
+        }
+        let (port, daemon) = fake_daemon("git-fetch", true);
+        let mut protocol = GitProtocol::new(&git_url("git-fetch", port));
+        let (refs, _) = protocol.discover_refs().unwrap();
+        assert!(refs
+            .iter()
+            .any(|r| r.name == "refs/heads/master" && r.id == MASTER));
+
+        let wants = vec![Ref {
+            id: MASTER.to_owned(),
+            name: "refs/heads/master".to_owned(),
+        }];
+        let request = FetchRequest {
+            wants: &wants,
+            haves: &Haves::none(),
+            shallow: &[],
+            include_tag: false,
+            depth: None,
+            git_dir: None,
+            progress: Some(std::sync::Arc::new(|_| ())),
+            cancel: None,
+        };
+        let mut parser = protocol.fetch_packfile(&request).unwrap();
+        let packfile = parser.parse(None).unwrap();
+        assert!(packfile.find_by_sha(MASTER).unwrap().is_some());
+        assert_eq!(
+            daemon.join().unwrap(),
+            format!("git-upload-pack <remote>\0host=127.0.0.1:{}\0", port)
+        );
+    }
+
+    #[test]
+    fn a_daemon_hanging_up_means_not_found() {
+        let (port, daemon) = fake_daemon("git-missing", false);
+        let mut protocol = GitProtocol::new(&git_url("git-missing", port));
+        let err = protocol.discover_refs().unwrap_err();
+        assert!(matches!(err, Error::RepositoryNotFound(_)));
+        daemon.join().unwrap();
+    }
+
+    #[test]
+    fn reads_time_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let url = Url::parse(&format!("git://127.0.0.1:{}/repo.git", port)).unwrap();
+        let timeout = Duration::from_millis(100);
+        let mut protocol = GitProtocol::with_timeouts(&url, timeout, timeout);
+        assert!(protocol.discover_refs().is_err());
+    }
+
+    #[test]
+    fn cancelling_shuts_the_connection_down() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let url = Url::parse(&format!("git://127.0.0.1:{}/repo.git", port)).unwrap();
+        let mut protocol = GitProtocol::new(&url);
+        let token = CancellationToken::new();
+        protocol.set_cancellation(token.clone());
+        let canceller = thread::spawn(move || {
+            let _connection = listener.accept().unwrap();
+            thread::sleep(Duration::from_millis(100));
+            token.cancel();
+        });
+        // Without the shutdown the read waits for its two minutes timeout
+        let started = std::time::Instant::now();
+        assert!(protocol.discover_refs().is_err());
+        assert!(started.elapsed() < Duration::from_secs(10));
+        canceller.join().unwrap();
+    }
+}
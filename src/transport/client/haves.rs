@@ -0,0 +1,183 @@
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+
+///
+/// Reads the parents of a commit of the client, `None` when the commit is
+/// not in the repository. Shallow commits have no parents.
+///
+type ReadParents<'a> = dyn Fn(&str) -> Option<Vec<String>> + 'a;
+
+/// The haves sent without the server acknowledging a new one, past which
+/// the negotiation gives up, like git does on unrelated histories
+pub(crate) const MAX_IN_VAIN: usize = 256;
+
+///
+/// The commits the client has, walked from its ref tips back through their
+/// parents as the negotiation asks for more, so a fetch only reads the
+/// history up to the commits the server acknowledges.
+///
+pub struct Haves<'a> {
+    read_parents: Option<Box<ReadParents<'a>>>,
+    walk: RefCell<Walk>,
+}
+
+#[derive(Default)]
+struct Walk {
+    queue: VecDeque<String>,
+    seen: HashSet<String>,
+    /// the commits known to the server, along with their ancestors as the
+    /// walk reaches them
+    common: HashSet<String>,
+    /// the haves sent since the server last acknowledged a new one
+    in_vain: usize,
+}
+
+impl<'a> Haves<'a> {
+    pub fn new<F>(tips: Vec<String>, read_parents: F) -> Self
+    where
+        F: Fn(&str) -> Option<Vec<String>> + 'a,
+    {
+        let mut walk = Walk::default();
+        for tip in tips {
+            if walk.seen.insert(tip.clone()) {
+                walk.queue.push_back(tip);
+            }
+        }
+        Haves {
+            read_parents: Some(Box::new(read_parents)),
+            walk: RefCell::new(walk),
+        }
+    }
+
+    ///
+    /// The haves of a client with no commits, like a new clone.
+    ///
+    pub fn none() -> Self {
+        Haves {
+            read_parents: None,
+            walk: RefCell::new(Walk::default()),
+        }
+    }
+
+    ///
+    /// Whether the client has the commit.
+    ///
+    pub fn has(&self, sha: &str) -> bool {
+        self.parents(sha).is_some()
+    }
+
+    fn parents(&self, sha: &str) -> Option<Vec<String>> {
+        self.read_parents.as_ref().and_then(|read| read(sha))
+    }
+
+    ///
+    /// The next `count` commits of the walk, tips first, skipping the ones
+    /// the server is known to have. Empty once the walk is over, or once
+    /// `MAX_IN_VAIN` haves were sent without a new acknowledgment.
+    ///
+    pub fn next_round(&self, count: usize) -> Vec<String> {
+        let mut walk = self.walk.borrow_mut();
+        let count = count.min(MAX_IN_VAIN.saturating_sub(walk.in_vain));
+        let mut round = Vec::with_capacity(count);
+        while round.len() < count {
+            let sha = match walk.queue.pop_front() {
+                Some(sha) => sha,
+                None => break,
+            };
+            // Missing objects are skipped, like tags pointing to trees
+            let parents = match self.parents(&sha) {
+                Some(parents) => parents,
+                None => continue,
+            };
+            if walk.common.contains(&sha) {
+                // The ancestors of a common commit are common too
+                walk.common.extend(parents);
+                continue;
+            }
+            for parent in parents {
+                if walk.seen.insert(parent.clone()) {
+                    walk.queue.push_back(parent);
+                }
+            }
+            round.push(sha);
+        }
+        walk.in_vain += round.len();
+        round
+    }
+
+    ///
+    /// Records a commit acknowledged by the server, so neither it nor its
+    /// ancestors are sent anymore.
+    ///
+    pub fn ack(&self, sha: &str) {
+        let parents = self.parents(sha).unwrap_or_default();
+        let mut walk = self.walk.borrow_mut();
+        if walk.common.insert(sha.to_owned()) {
+            walk.in_vain = 0;
+        }
+        walk.common.extend(parents);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn walking_the_haves_in_rounds() {
+        // a <- b <- c <- d and a <- e, with d and e as tips
+        let parents: HashMap<&str, Vec<String>> = vec![
+            ("a", vec![]),
+            ("b", vec!["a".to_owned()]),
+            ("c", vec!["b".to_owned()]),
+            ("d", vec!["c".to_owned()]),
+            ("e", vec!["a".to_owned()]),
+        ]
+        .into_iter()
+        .collect();
+        let tips = vec!["d".to_owned(), "e".to_owned(), "missing".to_owned()];
+        let haves = Haves::new(tips, |sha| parents.get(sha).cloned());
+        assert!(haves.has("a"));
+        assert!(!haves.has("missing"));
+        assert_eq!(haves.next_round(2), vec!["d", "e"]);
+        haves.ack("e");
+        // a is common through e, so the walk stops at b
+        assert_eq!(haves.next_round(2), vec!["c", "b"]);
+        assert!(haves.next_round(2).is_empty());
+
+        assert!(Haves::none().next_round(32).is_empty());
+        assert!(!Haves::none().has("a"));
+    }
+
+    #[test]
+    fn walking_a_long_unrelated_history_gives_up() {
+        // A line of 1000 commits the server never acknowledges
+        let parent = |sha: &str| {
+            let n: usize = sha.parse().ok()?;
+            Some(if n > 0 {
+                vec![(n - 1).to_string()]
+            } else {
+                vec![]
+            })
+        };
+        let haves = Haves::new(vec!["999".to_owned()], parent);
+        let mut sent = Vec::new();
+        loop {
+            let round = haves.next_round(32);
+            if round.is_empty() {
+                break;
+            }
+            sent.extend(round);
+        }
+        assert_eq!(sent.len(), MAX_IN_VAIN);
+
+        // A new acknowledgment gives the walk another MAX_IN_VAIN haves
+        let haves = Haves::new(vec!["999".to_owned()], parent);
+        assert_eq!(haves.next_round(MAX_IN_VAIN).len(), MAX_IN_VAIN);
+        haves.ack("999");
+        haves.ack("999");
+        assert_eq!(haves.next_round(MAX_IN_VAIN + 1).len(), MAX_IN_VAIN);
+        assert!(haves.next_round(32).is_empty());
+    }
+}
@@ -1,79 +1,792 @@
-use reqwest::header::CONTENT_TYPE;
-use std::io::{BufReader, Error, ErrorKind, Result as IOResult};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use reqwest::{redirect, Certificate, Proxy, StatusCode};
+use std::fs;
+use std::fs::File;
+use std::io::{BufReader, Cursor, Read};
+use std::path::Path;
 use url::Url;
 
+use super::dumb::{parse_info_refs, DumbFetch};
 use super::packet::{
-    create_packfile_negotiation_request, parse_refs_lines, read_flush_packet, read_packet_line,
-    receive_packet, receive_packet_file_with_sideband, GIT_FLUSH_HEADER, GIT_UPLOAD_PACK_HEADER,
+    create_packfile_negotiation_request, create_receive_pack_request, negotiate_common_commits,
+    parse_refs_lines, read_flush_packet, read_packet_line, receive_packet,
+    receive_packet_file_with_sideband, receive_report_status, GIT_FLUSH_HEADER,
+    GIT_RECEIVE_PACK_HEADER, GIT_UPLOAD_PACK_HEADER, HAVES_PER_ROUND,
 };
+use super::v2::{
+    create_fetch_request, create_ls_refs_request, parse_capabilities, receive_fetch_response,
+    receive_ls_refs, GIT_PROTOCOL_HEADER, GIT_PROTOCOL_V2, VERSION_2_LINE,
+};
+use crate::cancel::check as check_cancelled;
+use crate::credentials::{url_credential, Credential, CredentialCallback};
+use crate::error::{Error, Result};
+use crate::options::HttpOptions;
 use crate::packfile::packfile_parser::PackFileParser;
 use crate::packfile::refs::{Ref, Refs};
-use crate::transport::client::Protocol;
+use crate::transport::client::{
+    filter_refs, not_advertised, FetchRequest, Protocol, PushStatus, RefUpdate, ServerCapabilities,
+    AGENT, ZERO_ID,
+};
+use crate::utils::is_sha;
 
 type Client = reqwest::blocking::Client;
+type Response = reqwest::blocking::Response;
+type RequestBuilder = reqwest::blocking::RequestBuilder;
+type Body = reqwest::blocking::Body;
 
-#[derive(Debug)]
 pub struct HttpProtocol {
     url: Url,
     client: Client,
+    /// whether the server speaks protocol version 2
+    protocol_v2: bool,
+    /// whether the server only serves the files of the repository
+    dumb: bool,
+    capabilities: ServerCapabilities,
+    /// asked for a credential when the server answers 401
+    credentials: Option<CredentialCallback>,
+    credential: Option<Credential>,
+}
+
+impl HttpProtocol {
+    pub fn new(url: &Url, options: &HttpOptions) -> Result<Self> {
+        // Credentials embedded in the url are sent as basic authentication
+        let credential = url_credential(url);
+        let mut url = url.clone();
+        let _ = url.set_username("");
+        let _ = url.set_password(None);
+        Ok(HttpProtocol {
+            url,
+            client: build_client(options)?,
+            protocol_v2: false,
+            dumb: false,
+            capabilities: ServerCapabilities::default(),
+            credentials: None,
+            credential,
+        })
+    }
+}
+
+///
+/// Builds the client sending every request with the given settings.
+///
+fn build_client(options: &HttpOptions) -> Result<Client> {
+    let mut builder = Client::builder().danger_accept_invalid_certs(options.insecure);
+    if let Some(ref proxy) = options.proxy {
+        builder = builder.proxy(Proxy::all(proxy).map_err(transport_error)?);
+    }
+    if let Some(ref path) = options.ca_bundle {
+        let pem = fs::read(path)?;
+        let certificate = Certificate::from_pem(&pem).map_err(transport_error)?;
+        builder = builder.add_root_certificate(certificate);
+    }
+    if let Some(timeout) = options.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    if let Some(timeout) = options.timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(ref user_agent) = options.user_agent {
+        builder = builder.user_agent(user_agent);
+    }
+    let mut headers = HeaderMap::new();
+    for (name, value) in options.headers.iter() {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| Error::Transport(format!("invalid header: {}", e)))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|e| Error::Transport(format!("invalid header: {}", e)))?;
+        headers.insert(name, value);
+    }
+    builder = builder.default_headers(headers);
+    builder = match options.max_redirects {
+        Some(0) => builder.redirect(redirect::Policy::none()),
+        Some(max) => builder.redirect(redirect::Policy::limited(max)),
+        None => builder,
+    };
+    builder.build().map_err(transport_error)
+}
+
+fn transport_error(e: reqwest::Error) -> Error {
+    Error::Http(Box::new(e))
 }
 
 impl HttpProtocol {
-    pub fn new(url: &Url) -> Self {
-        HttpProtocol {
-            url: url.clone(),
-            client: Client::new(),
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match self.credential {
+            Some(Credential::UserPassword {
+                ref username,
+                ref password,
+            }) => request.basic_auth(username, Some(password)),
+            Some(Credential::Token(ref token)) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    ///
+    /// Sends the request, asking the credential provider for a credential
+    /// each time the server answers 401, until it gives none, the same one
+    /// again, or `MAX_AUTH_ATTEMPTS` were refused.
+    ///
+    fn send_authorized<F>(&mut self, request: F) -> Result<Response>
+    where
+        F: Fn(&Client) -> Result<RequestBuilder>,
+    {
+        let mut asked = 0;
+        loop {
+            let res = self
+                .authorize(request(&self.client)?)
+                .send()
+                .map_err(transport_error)?;
+            if res.status() != StatusCode::UNAUTHORIZED {
+                return Ok(res);
+            }
+            let credential = self
+                .credentials
+                .as_ref()
+                .filter(|_| asked < MAX_AUTH_ATTEMPTS)
+                .and_then(|provider| provider.credentials(self.url.as_str()));
+            if credential.is_none() || credential == self.credential {
+                let err = format!("authentication failed for {}", self.url);
+                return Err(Error::Unauthorized(err));
+            }
+            self.credential = credential;
+            asked += 1;
+        }
+    }
+
+    fn post_upload_pack(&mut self, body: String) -> Result<Response> {
+        let pack_endpoint = [self.url.as_str(), UPLOAD_PACK_ENDPOINT].join("");
+        let protocol_v2 = self.protocol_v2;
+        let res = self.send_authorized(|client| {
+            let post = client
+                .post(&pack_endpoint)
+                .header(CONTENT_TYPE, "application/x-git-upload-pack-request")
+                .body(body.clone());
+            Ok(if protocol_v2 {
+                post.header(GIT_PROTOCOL_HEADER, GIT_PROTOCOL_V2)
+            } else {
+                post
+            })
+        })?;
+        check_status(res)
+    }
+
+    ///
+    /// Reads the refs and capabilities advertised for the service. Version 2
+    /// is asked for git-upload-pack, in which case the refs are listed with
+    /// `ls-refs`.
+    ///
+    fn discover_service_refs(
+        &mut self,
+        service: &str,
+        header: &[u8],
+        prefixes: &[&str],
+    ) -> Result<(Refs, ServerCapabilities)> {
+        let discovery_url = format!("{}{}{}", self.url.as_str(), REF_DISCOVERY_ENDPOINT, service);
+        let res = self.send_authorized(|client| {
+            let get = client.get(&discovery_url);
+            Ok(if service == UPLOAD_PACK_SERVICE {
+                get.header(GIT_PROTOCOL_HEADER, GIT_PROTOCOL_V2)
+            } else {
+                get
+            })
+        })?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Err(Error::RepositoryNotFound(self.url.to_string()));
+        }
+        let mut res = check_status(res)?;
+        let content_type = res
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|t| t.to_str().ok())
+            .unwrap_or("")
+            .to_owned();
+        let mut body = Vec::new();
+        res.read_to_end(&mut body)?;
+        let mut res = &body[..];
+        if !is_smart_advertisement(&content_type, service, &body) {
+            // Dumb servers just send the info/refs file
+            if service != UPLOAD_PACK_SERVICE {
+                return Err(Error::Transport(
+                    "push isn't supported by dumb http servers".to_owned(),
+                ));
+            }
+            self.dumb = true;
+            let contents = String::from_utf8_lossy(&body);
+            return self.discover_dumb_refs(&contents, prefixes);
         }
+
+        // Servers speaking version 2 may skip the service header
+        let mut first = read_packet_line(&mut res)?.unwrap_or_else(std::vec::Vec::new);
+        if first == header {
+            if &read_flush_packet(&mut res)? != GIT_FLUSH_HEADER {
+                return Err(Error::Protocol("flush not received".to_owned()));
+            }
+            first = read_packet_line(&mut res)?.unwrap_or_else(std::vec::Vec::new);
+        } else if first != VERSION_2_LINE {
+            return Err(Error::Protocol("flush not received".to_owned()));
+        }
+
+        self.protocol_v2 = first == VERSION_2_LINE;
+        if self.protocol_v2 {
+            self.capabilities = parse_capabilities(&receive_packet(&mut res)?);
+            let res =
+                self.post_upload_pack(create_ls_refs_request(&self.capabilities, prefixes))?;
+            let (refs, symrefs) = receive_ls_refs(&mut BufReader::new(res))?;
+            self.capabilities.symrefs = symrefs;
+            return Ok((refs, self.capabilities.clone()));
+        }
+        let mut lines = receive_packet(&mut res)?;
+        if !first.is_empty() {
+            lines.insert(0, String::from_utf8_lossy(&first).into_owned());
+        }
+        let (refs, capabilities) = parse_refs_lines(&lines)?;
+        self.capabilities = capabilities.clone();
+        Ok((filter_refs(refs, prefixes), capabilities))
+    }
+
+    ///
+    /// Reads the refs of a dumb server, with `HEAD` read from its file.
+    ///
+    fn discover_dumb_refs(
+        &mut self,
+        info_refs: &str,
+        prefixes: &[&str],
+    ) -> Result<(Refs, ServerCapabilities)> {
+        let mut refs = filter_refs(parse_info_refs(info_refs)?, prefixes);
+        let head = match self.get_file("HEAD")? {
+            Some(res) => res.text().map_err(transport_error)?.trim_end().to_owned(),
+            None => return Ok((refs, ServerCapabilities::default())),
+        };
+        let (id, capabilities) = match head.strip_prefix("ref: ") {
+            Some(target) => (
+                refs.iter().find(|r| r.name == target).map(|r| r.id.clone()),
+                ServerCapabilities::parse(&[format!("symref=HEAD:{}", target)]),
+            ),
+            None if is_sha(&head) => (Some(head.clone()), ServerCapabilities::default()),
+            None => return Err(Error::Protocol(format!("unexpected HEAD: {}", head))),
+        };
+        if let Some(id) = id {
+            let name = "HEAD".to_owned();
+            refs.insert(0, Ref { id, name });
+        }
+        Ok((refs, capabilities))
+    }
+
+    ///
+    /// Reads a file of the repository, `None` when the server doesn't have it.
+    ///
+    fn get_file(&mut self, path: &str) -> Result<Option<Response>> {
+        let url = format!("{}/{}", self.url.as_str(), path);
+        let res = self.send_authorized(|client| Ok(client.get(&url)))?;
+        if res.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        check_status(res).map(Some)
+    }
+
+    fn fetch_packfile_dumb(&mut self, request: &FetchRequest) -> Result<PackFileParser> {
+        if request.sends_shallow() {
+            return Err(not_advertised("shallow"));
+        }
+        let mut fetch = DumbFetch::new(|path: &str| {
+            check_cancelled(&request.cancel)?;
+            self.get_file(path)
+        });
+        fetch.fetch(request)
     }
+
+    fn fetch_packfile_v2(&mut self, request: &FetchRequest) -> Result<PackFileParser> {
+        if request.sends_shallow() && !self.capabilities.shallow {
+            return Err(not_advertised("shallow"));
+        }
+        let mut common: Vec<String> = Vec::new();
+        loop {
+            check_cancelled(&request.cancel)?;
+            // Stateless servers forget everything between rounds
+            let batch = request.haves.next_round(HAVES_PER_ROUND);
+            let done = batch.is_empty();
+            let mut haves = common.clone();
+            haves.extend(batch);
+            let body = create_fetch_request(request, &self.capabilities, &haves, done);
+            let res = self.post_upload_pack(body)?;
+            let mut reader = BufReader::with_capacity(16 * 1024, res);
+            let (acks, parser) = receive_fetch_response(&mut reader, request)?;
+            if let Some(parser) = parser {
+                return Ok(parser);
+            }
+            if done {
+                return Err(Error::Protocol("packfile not received".to_owned()));
+            }
+            for sha in acks.common {
+                request.haves.ack(&sha);
+                if !common.contains(&sha) {
+                    common.push(sha);
+                }
+            }
+        }
+    }
+}
+
+///
+/// Whether the refs come from a smart server: the advertisement media type
+/// is compared without its case and parameters, and servers sending the
+/// wrong one are still recognized by their first line.
+///
+fn is_smart_advertisement(content_type: &str, service: &str, body: &[u8]) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    let expected = format!("application/x-{}-advertisement", service);
+    let first_line = body.get(4..).unwrap_or(&[]);
+    media_type.eq_ignore_ascii_case(&expected)
+        || first_line.starts_with(b"# service=")
+        || first_line.starts_with(VERSION_2_LINE)
 }
 
-const REF_DISCOVERY_ENDPOINT: &str = "/info/refs?service=git-upload-pack";
-const REQUIRED_CAPABILTIES: [&str; 3] = ["multi_ack_detailed", "side-band-64k", "agent=git/1.8.1"];
+fn check_status(res: Response) -> Result<Response> {
+    let status = res.status();
+    if !status.is_success() {
+        let err = format!("http status {}", status.as_u16());
+        return Err(Error::Transport(err));
+    }
+    Ok(res)
+}
+
+/// the credentials asked to the provider for a request before giving up
+const MAX_AUTH_ATTEMPTS: usize = 3;
+const REF_DISCOVERY_ENDPOINT: &str = "/info/refs?service=";
+const UPLOAD_PACK_SERVICE: &str = "git-upload-pack";
 const UPLOAD_PACK_ENDPOINT: &str = "/git-upload-pack";
+const RECEIVE_PACK_ENDPOINT: &str = "/git-receive-pack";
 
 impl Protocol for HttpProtocol {
-    fn discover_refs(&mut self) -> IOResult<Refs> {
-        let discovery_url = format!("{}{}", self.url.as_str(), REF_DISCOVERY_ENDPOINT);
-        let mut res = reqwest::blocking::get(&discovery_url)
-            .map_err(|e| std::io::Error::new(ErrorKind::Other, e))?;
-        let status = res.status();
-        if !status.is_success() {
-            return Err(Error::new(
-                ErrorKind::Other,
-                &format!("HTTP ERROR: {}", status.as_u16())[..],
-            ));
+    fn discover_refs(&mut self) -> Result<(Refs, ServerCapabilities)> {
+        self.discover_refs_with_prefixes(&[])
+    }
+
+    fn discover_refs_with_prefixes(
+        &mut self,
+        prefixes: &[&str],
+    ) -> Result<(Refs, ServerCapabilities)> {
+        self.discover_service_refs(UPLOAD_PACK_SERVICE, GIT_UPLOAD_PACK_HEADER, prefixes)
+    }
+
+    fn fetch_packfile(&mut self, request: &FetchRequest) -> Result<PackFileParser> {
+        if self.dumb {
+            return self.fetch_packfile_dumb(request);
+        }
+        if self.protocol_v2 {
+            return self.fetch_packfile_v2(request);
         }
+        let capabilities = request.capabilities(&self.capabilities)?;
+        let common =
+            negotiate_common_commits(&capabilities, request, |body| self.post_upload_pack(body))?;
+        let body = create_packfile_negotiation_request(&capabilities, request, &common, true);
+        let res = self.post_upload_pack(body)?;
+        let mut reader = BufReader::with_capacity(16 * 1024, res);
+        receive_packet_file_with_sideband(&mut reader, request, request.depth.is_some())
+    }
 
-        let first = read_packet_line(&mut res)?.unwrap_or_else(std::vec::Vec::new);
-        if first != GIT_UPLOAD_PACK_HEADER {
-            return Err(Error::new(ErrorKind::Other, "flush not received"));
+    fn discover_push_refs(&mut self) -> Result<(Refs, ServerCapabilities)> {
+        self.discover_service_refs("git-receive-pack", GIT_RECEIVE_PACK_HEADER, &[])
+    }
+
+    fn send_pack(&mut self, updates: &[RefUpdate], pack: Option<&Path>) -> Result<Vec<PushStatus>> {
+        if !self.capabilities.has("report-status") {
+            return Err(not_advertised("report-status"));
+        }
+        let mut capabilities = vec!["report-status"];
+        if updates.iter().any(|u| u.new_id == ZERO_ID) {
+            if !self.capabilities.has("delete-refs") {
+                return Err(not_advertised("delete-refs"));
+            }
+            capabilities.push("delete-refs");
         }
+        if self.capabilities.agent.is_some() {
+            capabilities.push(AGENT);
+        }
+        let commands = create_receive_pack_request(&capabilities, updates).into_bytes();
+        let pack_size = match pack {
+            Some(path) => fs::metadata(path)?.len(),
+            None => 0,
+        };
+        let pack_endpoint = [self.url.as_str(), RECEIVE_PACK_ENDPOINT].join("");
+        // The pack is streamed from its file, read again along with each
+        // credential asked for
+        let res = self.send_authorized(|client| {
+            let body = match pack {
+                Some(path) => {
+                    let len = commands.len() as u64 + pack_size;
+                    Body::sized(Cursor::new(commands.clone()).chain(File::open(path)?), len)
+                }
+                None => Body::from(commands.clone()),
+            };
+            Ok(client
+                .post(&pack_endpoint)
+                .header(CONTENT_TYPE, "application/x-git-receive-pack-request")
+                .body(body))
+        })?;
+        receive_report_status(&mut check_status(res)?)
+    }
+
+    fn set_credentials(&mut self, credentials: CredentialCallback) {
+        self.credentials = Some(credentials);
+    }
+
+    fn protocol(&self) -> &'static str {
+        "http-protocol"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{fixture_remote, fixture_repo, skip_without_git, MASTER};
+    use crate::transport::client::haves::Haves;
+    use std::fs;
+    use std::io::{BufRead, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::process::Command;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    static AUTHORIZATION: &str = "Basic Z2l0OnMzY3JldA==";
+
+    ///
+    /// Serves each request with the status, content type and body given by
+    /// the handler for its path and headers. Returns the port.
+    ///
+    fn http_server<H>(handler: H) -> u16
+    where
+        H: Fn(&str, &[(String, String)]) -> (&'static str, &'static str, Vec<u8>) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let path = request_line.split(' ').nth(1).unwrap_or("").to_owned();
+                let mut headers = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim_end().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        headers.push((name.to_lowercase(), value.trim().to_owned()));
+                    }
+                }
+                // The body is read not to reset the connection with it unread
+                let length = header(&headers, "content-length").and_then(|l| l.parse().ok());
+                let mut body = vec![0; length.unwrap_or(0)];
+                reader.read_exact(&mut body).unwrap();
+                let (status, content_type, body) = handler(&path, &headers);
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: {}\r\nWWW-Authenticate: Basic realm=\"git\"\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    content_type,
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        port
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| &value[..])
+    }
+
+    ///
+    /// Serves the refs advertisement only to requests authorized as
+    /// `git:s3cret`, answering 401 otherwise. Returns the port and the
+    /// authorization headers received.
+    ///
+    fn basic_auth_server() -> (u16, Arc<Mutex<Vec<Option<String>>>>) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        let port = http_server(move |_, headers| {
+            let authorization = header(headers, "authorization");
+            log.lock()
+                .unwrap()
+                .push(authorization.map(|a| a.to_owned()));
+            if authorization != Some(AUTHORIZATION) {
+                return ("401 Unauthorized", "text/plain", vec![]);
+            }
+            let body = "001e# service=git-upload-pack\n0000\
+                007133676d1c63d868803ed110b13be4e616bc8a29b7 refs/heads/master\0\
+                multi_ack_detailed side-band-64k agent=git/2.39.5\n0000";
+            let content_type = "application/x-git-upload-pack-advertisement";
+            ("200 OK", content_type, body.as_bytes().to_vec())
+        });
+        (port, received)
+    }
+
+    ///
+    /// Serves the files of the repository, like static file hosting does.
+    ///
+    fn dumb_server(root: PathBuf) -> u16 {
+        http_server(move |path, _| {
+            let path = path.split('?').next().unwrap();
+            let file = root.join(path.trim_start_matches("/repo.git/"));
+            match fs::read(file) {
+                Ok(contents) => ("200 OK", "text/plain", contents),
+                Err(_) => ("404 Not Found", "text/plain", vec![]),
+            }
+        })
+    }
+
+    fn repo_url(port: u16, userinfo: &str) -> Url {
+        Url::parse(&format!("http://{}127.0.0.1:{}/repo.git", userinfo, port)).unwrap()
+    }
+
+    fn git_credential(password: &str) -> Option<Credential> {
+        Some(Credential::UserPassword {
+            username: "git".to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    #[test]
+    fn credentials_are_asked_on_401() {
+        let (port, received) = basic_auth_server();
+        let mut protocol = HttpProtocol::new(&repo_url(port, ""), &HttpOptions::new()).unwrap();
+        let asked = Arc::new(Mutex::new(Vec::new()));
+        let asked_urls = asked.clone();
+        protocol.set_credentials(Arc::new(move |url: &str| {
+            asked_urls.lock().unwrap().push(url.to_owned());
+            git_credential("s3cret")
+        }));
+        let (refs, _) = protocol.discover_refs().unwrap();
+        assert_eq!(refs[0].name, "refs/heads/master");
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![None, Some(AUTHORIZATION.to_owned())]
+        );
+        assert_eq!(
+            *asked.lock().unwrap(),
+            vec![format!("http://127.0.0.1:{}/repo.git", port)]
+        );
+    }
+
+    #[test]
+    fn credentials_embedded_in_the_url_are_sent() {
+        let (port, received) = basic_auth_server();
+        let mut protocol =
+            HttpProtocol::new(&repo_url(port, "git:s3cret@"), &HttpOptions::new()).unwrap();
+        assert!(protocol.discover_refs().is_ok());
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec![Some(AUTHORIZATION.to_owned())]
+        );
+    }
+
+    #[test]
+    fn rejected_credentials_fail_the_request() {
+        let (port, _) = basic_auth_server();
+        let mut protocol = HttpProtocol::new(&repo_url(port, ""), &HttpOptions::new()).unwrap();
+        let err = protocol.discover_refs().unwrap_err();
+        assert!(matches!(err, Error::Unauthorized(_)));
+
+        protocol.set_credentials(Arc::new(|_: &str| git_credential("wrong")));
+        let err = protocol.discover_refs().unwrap_err();
+        assert!(matches!(err, Error::Unauthorized(_)));
+
+        // A provider giving a new wrong password each time is asked a few times
+        let asked = Arc::new(AtomicUsize::new(0));
+        let count = asked.clone();
+        protocol.set_credentials(Arc::new(move |_: &str| {
+            let n = count.fetch_add(1, Ordering::SeqCst);
+            git_credential(&format!("wrong{}", n))
+        }));
+        let err = protocol.discover_refs().unwrap_err();
+        assert!(matches!(err, Error::Unauthorized(_)));
+        assert_eq!(asked.load(Ordering::SeqCst), MAX_AUTH_ATTEMPTS);
+    }
+
+    #[test]
+    fn credentials_are_asked_when_only_the_push_requires_them() {
+        // Anyone may list the refs, pushing requires `git:s3cret`
+        let port = http_server(|path, headers| {
+            if path.ends_with("/info/refs?service=git-receive-pack") {
+                let first = format!("{} refs/heads/master\0report-status\n", MASTER);
+                let body = format!(
+                    "001f# service=git-receive-pack\n0000{:04x}{}0000",
+                    first.len() + 4,
+                    first
+                );
+                let content_type = "application/x-git-receive-pack-advertisement";
+                return ("200 OK", content_type, body.into_bytes());
+            }
+            if header(headers, "authorization") != Some(AUTHORIZATION) {
+                return ("401 Unauthorized", "text/plain", vec![]);
+            }
+            let body = b"000eunpack ok\n0019ok refs/heads/master\n0000".to_vec();
+            ("200 OK", "application/x-git-receive-pack-result", body)
+        });
+        let updates = vec![RefUpdate {
+            name: "refs/heads/master".to_owned(),
+            old_id: MASTER.to_owned(),
+            new_id: "33676d1c63d868803ed110b13be4e616bc8a29b7".to_owned(),
+        }];
+
+        let pack = std::env::temp_dir().join("rs-git-lib-push-credentials.pack");
+        fs::write(&pack, b"PACK").unwrap();
 
-        let flush = read_flush_packet(&mut res)?.unwrap();
-        if flush != GIT_FLUSH_HEADER {
-            return Err(Error::new(ErrorKind::Other, "flush not received"));
+        let mut protocol = HttpProtocol::new(&repo_url(port, ""), &HttpOptions::new()).unwrap();
+        protocol.discover_push_refs().unwrap();
+        let err = protocol.send_pack(&updates, Some(&pack)).unwrap_err();
+        assert!(matches!(err, Error::Unauthorized(_)));
+
+        protocol.set_credentials(Arc::new(|_: &str| git_credential("s3cret")));
+        let statuses = protocol.send_pack(&updates, Some(&pack)).unwrap();
+        assert!(statuses[0].is_ok());
+    }
+
+    #[test]
+    fn telling_smart_servers_from_dumb_ones() {
+        let smart = |content_type: &str, body: &[u8]| {
+            is_smart_advertisement(content_type, UPLOAD_PACK_SERVICE, body)
+        };
+        let v0 = b"001e# service=git-upload-pack\n0000";
+        assert!(smart("application/x-git-upload-pack-advertisement", v0));
+        assert!(smart(
+            "Application/X-Git-Upload-Pack-Advertisement; charset=utf-8",
+            v0
+        ));
+        // Misconfigured servers still answer with a smart advertisement
+        assert!(smart("text/plain", v0));
+        assert!(smart("text/plain", b"000eversion 2\n0000"));
+        let info_refs = b"33676d1c63d868803ed110b13be4e616bc8a29b7\trefs/heads/master\n";
+        assert!(!smart("text/plain", info_refs));
+    }
+
+    #[test]
+    fn fetching_from_a_dumb_server() {
+        if skip_without_git() {
+            return;
         }
-        parse_refs_lines(&receive_packet(&mut res)?)
+        let remote = fixture_remote("dumb");
+        // A loose commit on top of the packed history
+        let git = |args: &[&str]| {
+            let output = Command::new("git")
+                .arg("--git-dir")
+                .arg(&remote)
+                .args(args)
+                .env("GIT_AUTHOR_NAME", "a")
+                .env("GIT_AUTHOR_EMAIL", "a@b")
+                .env("GIT_COMMITTER_NAME", "a")
+                .env("GIT_COMMITTER_EMAIL", "a@b")
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap().trim().to_owned()
+        };
+        let tree = format!("{}^{{tree}}", MASTER);
+        let tip = git(&["commit-tree", &tree, "-p", MASTER, "-m", "loose"]);
+        git(&["update-ref", "refs/heads/master", &tip]);
+        git(&["update-server-info"]);
+
+        let port = dumb_server(remote.clone());
+        let mut protocol = HttpProtocol::new(&repo_url(port, ""), &HttpOptions::new()).unwrap();
+        let (refs, capabilities) = protocol.discover_refs().unwrap();
+        assert_eq!(capabilities.symref("HEAD"), Some("refs/heads/master"));
+        assert_eq!(refs[0].name, "HEAD");
+        assert_eq!(refs[0].id, tip);
+
+        let request = FetchRequest {
+            wants: &refs[..1],
+            haves: &Haves::none(),
+            shallow: &[],
+            include_tag: false,
+            depth: None,
+            git_dir: None,
+            progress: None,
+            cancel: None,
+        };
+        let mut parser = protocol.fetch_packfile(&request).unwrap();
+        let packfile = parser.parse(None).unwrap();
+        assert!(packfile.find_by_sha(&tip).unwrap().is_some());
+        assert!(packfile.find_by_sha(MASTER).unwrap().is_some());
+
+        // Nothing the client has is downloaded again, whatever its type
+        let git_dir = format!("{}/.git", fixture_repo("dumb-local"));
+        let request = FetchRequest {
+            git_dir: Some(&git_dir),
+            ..request
+        };
+        let mut parser = protocol.fetch_packfile(&request).unwrap();
+        let packfile = parser.parse(None).unwrap();
+        assert_eq!(packfile.shas().collect::<Vec<_>>(), vec![tip]);
+        assert!(protocol.discover_push_refs().is_err());
     }
 
-    fn fetch_packfile(&mut self, refs: &[Ref]) -> IOResult<PackFileParser> {
-        self.client = Client::new();
-        let body = create_packfile_negotiation_request(&REQUIRED_CAPABILTIES, refs);
-        let pack_endpoint = [self.url.as_str(), UPLOAD_PACK_ENDPOINT].join("");
+    #[test]
+    fn configured_headers_are_sent() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        let port = http_server(move |_, headers| {
+            log.lock().unwrap().extend(headers.iter().cloned());
+            ("404 Not Found", "text/plain", vec![])
+        });
+        let options = HttpOptions::new()
+            .user_agent("rs-git-lib-test")
+            .header("X-Team", "tools")
+            .header("X-Team", "git")
+            .header("X-Trace", "1");
+        let mut protocol = HttpProtocol::new(&repo_url(port, ""), &options).unwrap();
+        assert!(protocol.discover_refs().is_err());
+        let headers = received.lock().unwrap();
+        assert_eq!(header(&headers, "user-agent"), Some("rs-git-lib-test"));
+        assert_eq!(header(&headers, "x-team"), Some("git"));
+        assert_eq!(header(&headers, "x-trace"), Some("1"));
+    }
 
-        let res = self
-            .client
-            .post(&pack_endpoint)
-            .header(CONTENT_TYPE, "application/x-git-upload-pack-request")
-            .body(body)
-            .send()
-            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    #[test]
+    fn invalid_options_fail_to_build_the_client() {
+        let url = repo_url(80, "");
+        let options = HttpOptions::new().header("bad header", "x");
+        assert!(HttpProtocol::new(&url, &options).is_err());
+        let options = HttpOptions::new().ca_bundle("/nonexistent/ca.pem");
+        assert!(HttpProtocol::new(&url, &options).is_err());
+        let options = HttpOptions::new().proxy("not a url");
+        assert!(HttpProtocol::new(&url, &options).is_err());
+    }
 
-        let mut reader = BufReader::with_capacity(16 * 1024, res);
-        receive_packet_file_with_sideband(&mut reader)
+    #[test]
+    fn errors_sent_by_the_server_are_reported() {
+        let port = http_server(|_, _| {
+            let body = "003cERR access denied or repository not exported: /repo.git\n";
+            let content_type = "application/x-git-upload-pack-advertisement";
+            ("200 OK", content_type, body.as_bytes().to_vec())
+        });
+        let mut protocol = HttpProtocol::new(&repo_url(port, ""), &HttpOptions::new()).unwrap();
+        let err = protocol.discover_refs().unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Remote(ref message) if message == "access denied or repository not exported: /repo.git"
+        ));
     }
 
-    fn protocol(&self) -> &'static str {
-        "ssh-protocol"
+    #[test]
+    fn client_errors_keep_their_source() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut protocol = HttpProtocol::new(&repo_url(port, ""), &HttpOptions::new()).unwrap();
+        let err = protocol.discover_refs().unwrap_err();
+        assert!(matches!(err, Error::Http(_)));
+        let source = std::error::Error::source(&err).unwrap();
+        assert!(source.downcast_ref::<reqwest::Error>().is_some());
     }
 }
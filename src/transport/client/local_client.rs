@@ -1,30 +1,300 @@
-use std::io::Result as IOResult;
+use std::fs;
+use std::path::{Path, PathBuf};
 
+use super::file_client::FileProtocol;
+use crate::cancel::CancellationToken;
+use crate::error::{Error, Result};
+use crate::packfile::open_packs;
 use crate::packfile::packfile_parser::PackFileParser;
-use crate::packfile::refs::{Ref, Refs};
-use crate::transport::client::Protocol;
+use crate::packfile::refs::{read_refs, Ref, Refs};
+use crate::store::object::GitObject;
+use crate::transport::client::{FetchRequest, Protocol, ServerCapabilities};
+use crate::utils::find_git_dir;
 
+///
+/// Clones a repository given by a path reading its refs and objects
+/// directly, packs are hard-linked, or copied, instead of re-encoded.
+/// Fetches still go through git-upload-pack.
+///
 #[derive(Debug)]
 pub struct LocalProtocol {
     path: String,
+    upload_pack: FileProtocol,
 }
 
 impl LocalProtocol {
     pub fn new(path: String) -> Self {
-        LocalProtocol { path }
+        LocalProtocol {
+            upload_pack: FileProtocol::with_path(path.clone()),
+            path,
+        }
+    }
+
+    fn git_dir(&self) -> Result<PathBuf> {
+        find_git_dir(Path::new(&self.path))?
+            .ok_or_else(|| Error::RepositoryNotFound(self.path.clone()))
     }
 }
 
 impl Protocol for LocalProtocol {
-    fn discover_refs(&mut self) -> IOResult<Refs> {
-        unimplemented!()
+    fn discover_refs(&mut self) -> Result<(Refs, ServerCapabilities)> {
+        let git_dir = self.git_dir()?;
+        let head = fs::read_to_string(git_dir.join("HEAD"))?;
+        let capabilities = match head.trim_end().strip_prefix("ref: ") {
+            Some(target) => ServerCapabilities::parse(&[format!("symref=HEAD:{}", target)]),
+            None => ServerCapabilities::default(),
+        };
+        let git_dir = git_dir.to_str().ok_or_else(|| {
+            Error::Unsupported(format!("{} is not a UTF-8 path", git_dir.display()))
+        })?;
+        let refs = read_refs(git_dir)?;
+        if !refs.iter().any(|r| r.name.starts_with("refs/tags/")) {
+            return Ok((refs, capabilities));
+        }
+
+        // Annotated tags are followed by their peeled ref, as servers advertise them
+        let packs = open_packs(git_dir)?;
+        let read_object = |sha: &str| {
+            GitObject::open(git_dir, sha)
+                .ok()
+                .or_else(|| packs.iter().find_map(|p| p.find_by_sha(sha).ok()?))
+        };
+        let mut advertised = Vec::with_capacity(refs.len());
+        for r in refs {
+            let mut peeled = None;
+            if r.name.starts_with("refs/tags/") {
+                let mut id = r.id.clone();
+                while let Some(target) =
+                    read_object(&id).and_then(|o| Some(o.as_tag()?.object.to_owned()))
+                {
+                    id = target;
+                    peeled = Some(id.clone());
+                }
+            }
+            let name = format!("{}^{{}}", r.name);
+            advertised.push(r);
+            if let Some(id) = peeled {
+                advertised.push(Ref { id, name });
+            }
+        }
+        Ok((advertised, capabilities))
+    }
+
+    fn fetch_packfile(&mut self, request: &FetchRequest) -> Result<PackFileParser> {
+        self.upload_pack.fetch_packfile(request)
     }
 
-    fn fetch_packfile(&mut self, _reference: &[Ref]) -> IOResult<PackFileParser> {
-        unimplemented!()
+    fn set_cancellation(&mut self, cancel: CancellationToken) {
+        self.upload_pack.set_cancellation(cancel);
+    }
+
+    fn copies_objects(&self) -> bool {
+        true
+    }
+
+    fn copy_objects(&mut self, git_dir: &str) -> Result<()> {
+        let source = self.git_dir()?;
+        let objects = Path::new(git_dir).join("objects");
+        // The objects borrowed from alternates are copied too, the clone
+        // doesn't depend on them
+        for dir in object_dirs(&source.join("objects"))? {
+            copy_object_dir(&dir, &objects)?;
+        }
+        let shallow = source.join("shallow");
+        if shallow.is_file() {
+            fs::copy(shallow, Path::new(git_dir).join("shallow"))?;
+        }
+        Ok(())
     }
 
     fn protocol(&self) -> &'static str {
         "local-protocol"
     }
 }
+
+/// How many alternates are followed from one another, as git does
+const MAX_ALTERNATE_DEPTH: usize = 5;
+
+///
+/// Lists the objects dir and the ones of its alternates, read from
+/// `objects/info/alternates`, relative paths being relative to the
+/// objects dir listing them.
+///
+fn object_dirs(objects: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![objects.to_path_buf()];
+    let mut depth = vec![0];
+    let mut i = 0;
+    while i < dirs.len() {
+        let alternates = dirs[i].join("info/alternates");
+        if depth[i] < MAX_ALTERNATE_DEPTH && alternates.is_file() {
+            for line in fs::read_to_string(&alternates)?.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let dir = dirs[i].join(line);
+                let dir = fs::canonicalize(&dir).unwrap_or(dir);
+                if !dirs.contains(&dir) {
+                    dirs.push(dir);
+                    depth.push(depth[i] + 1);
+                }
+            }
+        }
+        i += 1;
+    }
+    Ok(dirs)
+}
+
+///
+/// Links or copies the loose objects and the packs of an objects dir.
+///
+fn copy_object_dir(source: &Path, objects: &Path) -> Result<()> {
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_str().unwrap_or("");
+        // Loose objects live in dirs named after the first byte of their sha
+        let is_loose = name.len() == 2 && name.chars().all(|c| c.is_ascii_hexdigit());
+        if !is_loose && name != "pack" {
+            continue;
+        }
+        fs::create_dir_all(objects.join(name))?;
+        for file in fs::read_dir(entry.path())? {
+            let path = file?.path();
+            let is_pack = path.extension().is_some_and(|e| e == "pack" || e == "idx");
+            let dst = objects
+                .join(name)
+                .join(path.file_name().unwrap_or_default());
+            if (is_loose || is_pack) && !dst.exists() {
+                link_or_copy(&path, &dst)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn link_or_copy(src: &Path, dst: &Path) -> std::io::Result<()> {
+    // Hard links fail across filesystems
+    fs::hard_link(src, dst).or_else(|_| fs::copy(src, dst).map(|_| ()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::{fixture_remote, skip_without_git, temp_dir, MASTER, TAG};
+    use crate::transport::client::haves::Haves;
+    use std::os::unix::fs::MetadataExt;
+
+    fn local_protocol(name: &str) -> LocalProtocol {
+        let remote = fixture_remote(name);
+        fs::write(remote.join("refs/tags/test_tag"), format!("{}\n", TAG)).unwrap();
+        LocalProtocol::new(remote.to_str().unwrap().to_owned())
+    }
+
+    fn fetch_master(protocol: &mut LocalProtocol) -> Result<PackFileParser> {
+        let wants = vec![Ref {
+            id: MASTER.to_owned(),
+            name: "refs/heads/master".to_owned(),
+        }];
+        protocol.fetch_packfile(&FetchRequest {
+            wants: &wants,
+            haves: &Haves::none(),
+            shallow: &[],
+            include_tag: false,
+            depth: None,
+            git_dir: None,
+            progress: None,
+            cancel: None,
+        })
+    }
+
+    #[test]
+    fn discovering_refs_peels_annotated_tags() {
+        let mut protocol = local_protocol("local-refs");
+        let (refs, capabilities) = protocol.discover_refs().unwrap();
+        assert_eq!(capabilities.symref("HEAD"), Some("refs/heads/master"));
+        let names: Vec<&str> = refs.iter().map(|r| &r.name[..]).collect();
+        assert_eq!(
+            names,
+            vec![
+                "HEAD",
+                "refs/heads/master",
+                "refs/tags/test_tag",
+                "refs/tags/test_tag^{}"
+            ]
+        );
+        assert_eq!(refs[0].id, MASTER);
+        assert_ne!(refs[3].id, TAG);
+    }
+
+    #[test]
+    fn cancelling_kills_the_upload_pack_of_fetches() {
+        if skip_without_git() {
+            return;
+        }
+        let mut protocol = local_protocol("local-cancel");
+        let token = CancellationToken::new();
+        protocol.set_cancellation(token.clone());
+        token.cancel();
+        assert!(fetch_master(&mut protocol).is_err());
+    }
+
+    #[test]
+    fn fetching_from_a_path_holding_percent_signs() {
+        if skip_without_git() {
+            return;
+        }
+        let remote = fixture_remote("100%25done");
+        let mut protocol = LocalProtocol::new(remote.to_str().unwrap().to_owned());
+        let packfile = fetch_master(&mut protocol).unwrap().parse(None).unwrap();
+        assert!(packfile.find_by_sha(MASTER).unwrap().is_some());
+    }
+
+    #[test]
+    fn copying_objects_links_the_packs() {
+        let mut protocol = local_protocol("local-copy");
+        let git_dir = std::env::temp_dir().join("rs-git-lib-local-copy-clone");
+        let _ = fs::remove_dir_all(&git_dir);
+        fs::create_dir_all(git_dir.join("objects")).unwrap();
+        protocol.copy_objects(git_dir.to_str().unwrap()).unwrap();
+
+        let source = protocol.git_dir().unwrap().join("objects/pack");
+        for entry in fs::read_dir(&source).unwrap() {
+            let path = entry.unwrap().path();
+            let copy = git_dir.join("objects/pack").join(path.file_name().unwrap());
+            let (original, copy) = (fs::metadata(&path).unwrap(), fs::metadata(copy).unwrap());
+            assert_eq!(original.ino(), copy.ino());
+        }
+    }
+
+    #[test]
+    fn copying_objects_resolves_the_alternates() {
+        let remote = fixture_remote("local-alternates");
+        let borrower = temp_dir("local-alternates-borrower.git");
+        fs::create_dir_all(borrower.join("objects/info")).unwrap();
+        fs::create_dir_all(borrower.join("refs/heads")).unwrap();
+        fs::write(borrower.join("HEAD"), "ref: refs/heads/master\n").unwrap();
+        fs::write(borrower.join("refs/heads/master"), format!("{}\n", MASTER)).unwrap();
+        fs::write(
+            borrower.join("objects/info/alternates"),
+            format!("# borrowed\n{}\n", remote.join("objects").display()),
+        )
+        .unwrap();
+
+        let mut protocol = LocalProtocol::new(borrower.to_str().unwrap().to_owned());
+        let git_dir = temp_dir("local-alternates-clone");
+        fs::create_dir_all(git_dir.join("objects")).unwrap();
+        protocol.copy_objects(git_dir.to_str().unwrap()).unwrap();
+        let packs = open_packs(git_dir.to_str().unwrap()).unwrap();
+        assert!(packs
+            .iter()
+            .any(|p| p.find_by_sha(MASTER).unwrap().is_some()));
+    }
+
+    #[test]
+    fn a_missing_repository_is_not_found() {
+        let mut protocol = LocalProtocol::new("/nonexistent/repo".to_owned());
+        let err = protocol.discover_refs().unwrap_err();
+        assert!(matches!(err, Error::RepositoryNotFound(_)));
+    }
+}
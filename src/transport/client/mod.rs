@@ -1,16 +1,304 @@
+pub(crate) mod dumb;
 pub(crate) mod file_client;
 pub(crate) mod git_client;
+pub(crate) mod haves;
 pub(crate) mod http_client;
 pub(crate) mod local_client;
 pub(crate) mod packet;
+pub(crate) mod process;
 pub(crate) mod ssh_client;
+pub(crate) mod v2;
 
+use crate::cancel::CancellationToken;
+use crate::credentials::CredentialCallback;
+use crate::error::{Error, Result};
 use crate::packfile::packfile_parser::PackFileParser;
 use crate::packfile::refs::{Ref, Refs};
-use std::io::Result as IOResult;
+use crate::progress::ProgressCallback;
+use haves::Haves;
+use std::path::Path;
+
+///
+/// What is asked to the remote when fetching a packfile.
+///
+pub struct FetchRequest<'a> {
+    pub wants: &'a [Ref],
+    /// the commits the client already has
+    pub haves: &'a Haves<'a>,
+    /// the commits the client has without their parents, as listed in the
+    /// `shallow` file of a shallow clone
+    pub shallow: &'a [String],
+    /// ask for the annotated tags pointing into the fetched history
+    pub include_tag: bool,
+    /// limit the fetched history to this many commits
+    pub depth: Option<usize>,
+    /// the repository the packfile is written to as it is received
+    pub git_dir: Option<&'a str>,
+    pub progress: Option<ProgressCallback>,
+    pub cancel: Option<CancellationToken>,
+}
+
+/// The id of a missing object in ref updates
+pub(crate) const ZERO_ID: &str = "0000000000000000000000000000000000000000";
+
+///
+/// A ref update sent to the remote when pushing. A missing ref has the
+/// zero id as `old_id`, and a deleted ref has it as `new_id`.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefUpdate {
+    pub name: String,
+    pub old_id: String,
+    pub new_id: String,
+}
+
+///
+/// The outcome of a pushed ref, `error` holds the reason when it was rejected.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct PushStatus {
+    pub name: String,
+    pub error: Option<String>,
+}
+
+impl PushStatus {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+///
+/// The capabilities a server advertises along with its refs, see
+/// https://git-scm.com/docs/protocol-capabilities
+///
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ServerCapabilities {
+    /// the targets of the symbolic refs, like `HEAD` to `refs/heads/master`
+    pub symrefs: Vec<(String, String)>,
+    pub agent: Option<String>,
+    /// the hash algorithm of the object ids, sha1 when not advertised
+    pub object_format: Option<String>,
+    pub side_band: bool,
+    pub side_band_64k: bool,
+    pub ofs_delta: bool,
+    pub shallow: bool,
+    pub filter: bool,
+    capabilities: Vec<String>,
+}
+
+impl ServerCapabilities {
+    ///
+    /// Parses the capabilities as sent after the first advertised ref.
+    ///
+    pub fn parse<S: AsRef<str>>(capabilities: &[S]) -> Self {
+        let mut parsed = ServerCapabilities::default();
+        for capability in capabilities.iter().map(|c| c.as_ref().trim()) {
+            match capability.split_once('=') {
+                Some(("symref", symref)) => {
+                    if let Some((name, target)) = symref.split_once(':') {
+                        parsed.symrefs.push((name.to_owned(), target.to_owned()));
+                    }
+                }
+                Some(("agent", agent)) => parsed.agent = Some(agent.to_owned()),
+                Some(("object-format", format)) => parsed.object_format = Some(format.to_owned()),
+                _ => match capability {
+                    "side-band" => parsed.side_band = true,
+                    "side-band-64k" => parsed.side_band_64k = true,
+                    "ofs-delta" => parsed.ofs_delta = true,
+                    "shallow" => parsed.shallow = true,
+                    "filter" => parsed.filter = true,
+                    "" => continue,
+                    _ => (),
+                },
+            }
+            parsed.capabilities.push(capability.to_owned());
+        }
+        parsed
+    }
+
+    ///
+    /// Fails when the object ids of the server aren't sha1 ones, like with
+    /// `object-format=sha256`.
+    ///
+    pub fn check_object_format(&self) -> Result<()> {
+        match self.object_format.as_deref() {
+            None | Some("sha1") => Ok(()),
+            Some(format) => Err(Error::Transport(format!(
+                "object-format={} is not supported",
+                format
+            ))),
+        }
+    }
+
+    ///
+    /// Whether the capability was advertised, with or without a value.
+    ///
+    pub fn has(&self, name: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|c| c == name || c.split_once('=').is_some_and(|(key, _)| key == name))
+    }
+
+    ///
+    /// The ref a symbolic ref of the server, like `HEAD`, points to.
+    ///
+    pub fn symref(&self, name: &str) -> Option<&str> {
+        self.symrefs
+            .iter()
+            .find(|(symref, _)| symref == name)
+            .map(|(_, target)| &target[..])
+    }
+}
+
+pub(crate) const AGENT: &str = concat!("agent=rs-git-lib/", env!("CARGO_PKG_VERSION"));
+
+///
+/// The error the server reported, through an `ERR` packet line or the error
+/// channel of the sideband.
+///
+pub(crate) fn remote_error(message: &str) -> Error {
+    Error::Remote(message.trim_end().to_owned())
+}
+
+pub(crate) fn not_advertised(capability: &str) -> Error {
+    Error::Protocol(format!("the server doesn't support {}", capability))
+}
+
+impl<'a> FetchRequest<'a> {
+    ///
+    /// Whether the request deepens or comes from a shallow clone, both of
+    /// which need the `shallow` capability.
+    ///
+    pub fn sends_shallow(&self) -> bool {
+        self.depth.is_some() || !self.shallow.is_empty()
+    }
+
+    ///
+    /// A parser for the packfile answering the request, writing it to the
+    /// repository and reporting its progress.
+    ///
+    pub(crate) fn parser(&self) -> PackFileParser {
+        let mut parser = PackFileParser::new();
+        parser.set_git_dir(self.git_dir);
+        parser.set_progress(self.progress.clone());
+        parser.set_cancellation(self.cancel.clone());
+        parser
+    }
+
+    ///
+    /// The upload-pack capabilities needed by this request, among the ones
+    /// advertised by the server.
+    ///
+    pub fn capabilities(&self, server: &ServerCapabilities) -> Result<Vec<&'static str>> {
+        if !server.has("multi_ack_detailed") {
+            return Err(not_advertised("multi_ack_detailed"));
+        }
+        let mut capabilities = vec!["multi_ack_detailed"];
+        if server.side_band_64k {
+            capabilities.push("side-band-64k");
+        } else if server.side_band {
+            capabilities.push("side-band");
+        } else {
+            return Err(not_advertised("side-band"));
+        }
+        if server.ofs_delta {
+            capabilities.push("ofs-delta");
+        }
+        if self.include_tag && server.has("include-tag") {
+            capabilities.push("include-tag");
+        }
+        if self.sends_shallow() {
+            if !server.shallow {
+                return Err(not_advertised("shallow"));
+            }
+            capabilities.push("shallow");
+        }
+        if server.agent.is_some() {
+            capabilities.push(AGENT);
+        }
+        Ok(capabilities)
+    }
+}
 
 pub trait Protocol {
-    fn discover_refs(&mut self) -> IOResult<Refs>;
-    fn fetch_packfile(&mut self, wants: &[Ref]) -> IOResult<PackFileParser>;
+    ///
+    /// Lists the refs of the remote, along with the capabilities it advertises.
+    ///
+    fn discover_refs(&mut self) -> Result<(Refs, ServerCapabilities)>;
+
+    ///
+    /// Lists the refs of the remote starting with one of the prefixes, or
+    /// all of them when there are none.
+    ///
+    fn discover_refs_with_prefixes(
+        &mut self,
+        prefixes: &[&str],
+    ) -> Result<(Refs, ServerCapabilities)> {
+        let (refs, capabilities) = self.discover_refs()?;
+        Ok((filter_refs(refs, prefixes), capabilities))
+    }
+
+    fn fetch_packfile(&mut self, request: &FetchRequest) -> Result<PackFileParser>;
+
+    ///
+    /// Lists the refs of the remote as seen by git-receive-pack.
+    ///
+    fn discover_push_refs(&mut self) -> Result<(Refs, ServerCapabilities)> {
+        Err(unsupported("push", self.protocol()))
+    }
+
+    ///
+    /// Sends the ref updates along with the pack file holding the objects
+    /// they need, none when they only delete refs. Returns the status the
+    /// remote reported for each ref.
+    ///
+    fn send_pack(
+        &mut self,
+        _updates: &[RefUpdate],
+        _pack: Option<&Path>,
+    ) -> Result<Vec<PushStatus>> {
+        Err(unsupported("push", self.protocol()))
+    }
+
+    ///
+    /// Whether the repository is on the local filesystem, so its objects can
+    /// be copied with `copy_objects` instead of fetched in a packfile.
+    ///
+    fn copies_objects(&self) -> bool {
+        false
+    }
+
+    ///
+    /// Copies, or hard-links, every object of the repository into `git_dir`.
+    ///
+    fn copy_objects(&mut self, _git_dir: &str) -> Result<()> {
+        Err(unsupported("copying objects", self.protocol()))
+    }
+
+    ///
+    /// Sets the provider asked for credentials when the remote requires
+    /// authentication. Ignored by protocols that authenticate on their own.
+    ///
+    fn set_credentials(&mut self, _credentials: CredentialCallback) {}
+
+    ///
+    /// Sets the token interrupting the connection to the remote when
+    /// cancelled. Ignored by protocols whose reads are bounded by timeouts.
+    ///
+    fn set_cancellation(&mut self, _cancel: CancellationToken) {}
+
     fn protocol(&self) -> &'static str;
 }
+
+pub(crate) fn filter_refs(refs: Refs, prefixes: &[&str]) -> Refs {
+    if prefixes.is_empty() {
+        return refs;
+    }
+    refs.into_iter()
+        .filter(|r| prefixes.iter().any(|p| r.name.starts_with(p)))
+        .collect()
+}
+
+fn unsupported(operation: &str, protocol: &str) -> Error {
+    Error::Transport(format!("{} is not supported by {}", operation, protocol))
+}
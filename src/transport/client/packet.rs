@@ -1,51 +1,63 @@
+use crate::cancel::check;
+use crate::error::{Error, Result};
 use crate::packfile::packfile_parser::PackFileParser;
 use crate::packfile::refs::{Ref, Refs};
+use crate::transport::client::{
+    remote_error, FetchRequest, PushStatus, RefUpdate, ServerCapabilities,
+};
 use std::collections::HashMap;
-use std::io::{Error, ErrorKind, Read, Result as IOResult};
+use std::io::{Read, Write};
 
 pub(crate) const GIT_UPLOAD_PACK_HEADER: &[u8; 26] = b"# service=git-upload-pack\n";
+pub(crate) const GIT_RECEIVE_PACK_HEADER: &[u8; 27] = b"# service=git-receive-pack\n";
 pub(crate) const GIT_FLUSH_HEADER: &[u8; 4] = b"0000";
 
-pub(crate) fn read_packet_line<R: Read>(reader: &mut R) -> IOResult<Option<Vec<u8>>> {
+pub(crate) fn read_packet_line<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
     let mut header = [0; 4];
     reader.read_exact(&mut header)?;
     let length_str = std::str::from_utf8(&header[..]).unwrap_or("");
-    let length = u64::from_str_radix(length_str, 16).unwrap_or(0);
-    if length <= 4 {
-        Ok(None)
-    } else {
-        let mut pkt = vec![0; (length - 4) as usize];
-        reader.read_exact(&mut pkt)?;
-        Ok(Some(pkt))
+    match u64::from_str_radix(length_str, 16) {
+        Ok(0) | Ok(4) => Ok(None),
+        Ok(length) if length > 4 => {
+            let mut pkt = vec![0; (length - 4) as usize];
+            reader.read_exact(&mut pkt)?;
+            // Servers may abort any conversation with an `ERR <message>` line
+            if let Some(message) = pkt.strip_prefix(b"ERR ") {
+                return Err(remote_error(&String::from_utf8_lossy(message)));
+            }
+            Ok(Some(pkt))
+        }
+        _ => {
+            let err = format!("invalid packet length: {}", length_str);
+            Err(Error::Protocol(err))
+        }
     }
 }
 
-pub(crate) fn read_flush_packet<R: Read>(reader: &mut R) -> IOResult<Option<Vec<u8>>> {
+pub(crate) fn read_flush_packet<R: Read>(reader: &mut R) -> Result<[u8; 4]> {
     let mut flush: [u8; 4] = [0; 4];
     reader.read_exact(&mut flush)?;
-    Ok(Some(flush.to_vec()))
+    Ok(flush)
 }
 
-pub(crate) fn receive_packet<R: Read>(reader: &mut R) -> IOResult<Vec<String>> {
+pub(crate) fn receive_packet<R: Read>(reader: &mut R) -> Result<Vec<String>> {
     let mut lines = vec![];
     loop {
         match read_packet_line(reader) {
-            Ok(Some(line)) => {
-                let s: String = std::str::from_utf8(&line[..]).unwrap().to_owned();
-                lines.push(s)
-            }
+            Ok(Some(line)) => lines.push(String::from_utf8_lossy(&line).into_owned()),
             Ok(None) => return Ok(lines),
             Err(e) => return Err(e),
         }
     }
 }
 
-pub(crate) fn parse_refs_lines(lines: &[String]) -> IOResult<Refs> {
-    if lines.len() <= 1 {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "parse_refs_lines need at least 1 line",
-        ));
+///
+/// Parses the advertised refs, the first line also carries the capabilities
+/// of the server.
+///
+pub(crate) fn parse_refs_lines(lines: &[String]) -> Result<(Refs, ServerCapabilities)> {
+    if lines.is_empty() {
+        return Err(Error::Protocol("no refs advertised".to_owned()));
     }
 
     let mut iter = lines.iter().map(|s| s.trim_end());
@@ -53,39 +65,44 @@ pub(crate) fn parse_refs_lines(lines: &[String]) -> IOResult<Refs> {
     // First line contains capabilities separated by '\0'
     let mut parsed = Vec::new();
     let first = iter.next().unwrap();
-    let (_capabilities, first_ref) = parse_ref_first_line(first);
-    parsed.push(first_ref);
+    let (capabilities, first_ref) = parse_ref_first_line(first)?;
+    // An empty repository only advertises its capabilities
+    if first_ref.name != "capabilities^{}" {
+        parsed.push(first_ref);
+    }
     for line in iter {
-        parsed.push(parse_ref_line(line))
+        parsed.push(parse_ref_line(line)?)
     }
-    Ok(parsed)
+    Ok((parsed, ServerCapabilities::parse(&capabilities)))
 }
 
-fn parse_ref_first_line(line: &str) -> (Vec<String>, Ref) {
+fn parse_ref_first_line(line: &str) -> Result<(Vec<&str>, Ref)> {
     let split = line.split('\0').collect::<Vec<_>>();
-    let the_ref = parse_ref_line(split[0]);
-    let capabilities = split[1]
-        .split(' ')
-        .map(|s| s.to_owned())
-        .collect::<Vec<_>>();
-    (capabilities, the_ref)
+    let the_ref = parse_ref_line(split[0])?;
+    let capabilities = split.get(1).map_or(vec![], |c| c.split(' ').collect());
+    Ok((capabilities, the_ref))
 }
 
-fn parse_ref_line(line: &str) -> Ref {
-    let split = line.split(' ').collect::<Vec<_>>();
-
-    let (obj_id, name) = (split[0], split[1]);
-    Ref {
-        id: obj_id.to_owned(),
-        name: name.to_owned(),
+fn parse_ref_line(line: &str) -> Result<Ref> {
+    match line.split_once(' ') {
+        Some((obj_id, name)) => Ok(Ref {
+            id: obj_id.to_owned(),
+            name: name.to_owned(),
+        }),
+        None => Err(Error::Protocol(format!("unexpected ref line: {}", line))),
     }
 }
 
-pub(crate) fn create_packfile_negotiation_request(capabilities: &[&str], refs: &[Ref]) -> String {
-    let mut lines: Vec<String> = Vec::with_capacity(refs.len());
+///
+/// Builds the wants of an upload-pack request, with the capabilities after
+/// the first one, followed by the shallow commits of the client, the depth
+/// and a flush.
+///
+fn create_wants_request(capabilities: &[&str], request: &FetchRequest) -> String {
+    let mut lines: Vec<String> = Vec::with_capacity(request.wants.len());
     let mut ids: HashMap<String, ()> = HashMap::new();
-    for (i, r) in refs.iter().enumerate() {
-        let &Ref { id: ref o, .. } = r;
+    for (i, r) in request.wants.iter().enumerate() {
+        let Ref { id: o, .. } = r;
         if ids.contains_key(&r.id) {
             continue;
         }
@@ -100,12 +117,256 @@ pub(crate) fn create_packfile_negotiation_request(capabilities: &[&str], refs: &
             lines.push(packet_line(&line[..]));
         }
     }
+    for sha in request.shallow {
+        lines.push(packet_line(&["shallow ", sha, "\n"].concat()));
+    }
+    if let Some(depth) = request.depth {
+        lines.push(packet_line(&format!("deepen {}\n", depth)));
+    }
+    lines.push(flush_packet());
+    lines.concat()
+}
+
+///
+/// Builds the haves of a negotiation round, followed by `done` when `done`
+/// is true, or by a flush to end the round.
+///
+fn create_haves_request(haves: &[String], done: bool) -> String {
+    let mut lines: Vec<String> = Vec::with_capacity(haves.len() + 1);
+    for have in haves {
+        lines.push(packet_line(&["have ", have, "\n"].concat()));
+    }
+    if done {
+        lines.push(packet_line("done\n"));
+    } else {
+        lines.push(flush_packet());
+    }
+    lines.concat()
+}
+
+///
+/// Builds an upload-pack request: the wants, followed by the haves and
+/// `done` when `done` is true, or by a flush to end a negotiation round.
+///
+pub(crate) fn create_packfile_negotiation_request(
+    capabilities: &[&str],
+    request: &FetchRequest,
+    haves: &[String],
+    done: bool,
+) -> String {
+    [
+        create_wants_request(capabilities, request),
+        create_haves_request(haves, done),
+    ]
+    .concat()
+}
+
+///
+/// The commits acknowledged by the server during a negotiation round.
+///
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Acknowledgments {
+    pub common: Vec<String>,
+    pub ready: bool,
+}
+
+///
+/// Reads the `ACK`/`NAK` lines sent with multi_ack_detailed, up to the `NAK`
+/// that ends a round or the final `ACK <sha>` that precedes the packfile.
+///
+pub(crate) fn receive_acknowledgments<R: Read>(reader: &mut R) -> Result<Acknowledgments> {
+    let mut acks = Acknowledgments::default();
+    while let Some(line) = read_packet_line(reader)? {
+        let line = String::from_utf8_lossy(&line);
+        let split = line.trim_end().split(' ').collect::<Vec<_>>();
+        match split[..] {
+            ["NAK"] => break,
+            ["ACK", sha] => {
+                acks.common.push(sha.to_owned());
+                break;
+            }
+            ["ACK", sha, "common"] | ["ACK", sha, "continue"] => acks.common.push(sha.to_owned()),
+            ["ACK", sha, "ready"] => {
+                acks.common.push(sha.to_owned());
+                acks.ready = true;
+            }
+            _ => {
+                let err = format!("unexpected acknowledgment line: {}", line);
+                return Err(Error::Protocol(err));
+            }
+        }
+    }
+    Ok(acks)
+}
+
+pub(crate) const HAVES_PER_ROUND: usize = 32;
+
+///
+/// Runs the multi_ack_detailed negotiation of a stateless connection, sending
+/// the haves in rounds through `send`, until the server is ready to send the
+/// pack or there are no more haves. Returns the commits found in common.
+///
+pub(crate) fn negotiate_common_commits<F, R>(
+    capabilities: &[&str],
+    request: &FetchRequest,
+    mut send: F,
+) -> Result<Vec<String>>
+where
+    F: FnMut(String) -> Result<R>,
+    R: Read,
+{
+    let mut common: Vec<String> = Vec::new();
+    loop {
+        check(&request.cancel)?;
+        let batch = request.haves.next_round(HAVES_PER_ROUND);
+        if batch.is_empty() {
+            break;
+        }
+        // Stateless servers forget everything between rounds
+        let mut haves = common.clone();
+        haves.extend(batch);
+        let body = create_packfile_negotiation_request(capabilities, request, &haves, false);
+        let mut reader = send(body)?;
+        if request.depth.is_some() {
+            receive_shallow_update(&mut reader)?;
+        }
+        let acks = receive_acknowledgments(&mut reader)?;
+        for sha in acks.common {
+            request.haves.ack(&sha);
+            if !common.contains(&sha) {
+                common.push(sha);
+            }
+        }
+        if acks.ready {
+            break;
+        }
+    }
+    Ok(common)
+}
+
+///
+/// Fetches a packfile over a stateful connection, like the ones of ssh or
+/// git://, where the server remembers the negotiation between rounds, so
+/// the wants are sent once and each round only carries new haves.
+///
+pub(crate) fn fetch_packfile_stateful<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    capabilities: &[&str],
+    request: &FetchRequest,
+) -> Result<PackFileParser> {
+    let wants = create_wants_request(capabilities, request);
+    writer.write_all(wants.as_bytes())?;
+    writer.flush()?;
+    let shallow = match request.depth {
+        Some(_) => Some(receive_shallow_update(reader)?),
+        None => None,
+    };
+    loop {
+        check(&request.cancel)?;
+        let batch = request.haves.next_round(HAVES_PER_ROUND);
+        if batch.is_empty() {
+            break;
+        }
+        writer.write_all(create_haves_request(&batch, false).as_bytes())?;
+        writer.flush()?;
+        let acks = receive_acknowledgments(reader)?;
+        for sha in acks.common.iter() {
+            request.haves.ack(sha);
+        }
+        if acks.ready {
+            break;
+        }
+    }
+    writer.write_all(create_haves_request(&[], true).as_bytes())?;
+    writer.flush()?;
+    let mut parser = receive_packet_file_with_sideband(reader, request, false)?;
+    if let Some(shallow) = shallow {
+        parser.set_shallow(shallow);
+    }
+    Ok(parser)
+}
+
+///
+/// Reads the `shallow`/`unshallow` lines the server sends, up to the flush,
+/// when the request had a depth. Returns the commits that are now shallow.
+///
+pub(crate) fn receive_shallow_update<R: Read>(reader: &mut R) -> Result<Vec<String>> {
+    let mut shallow = Vec::new();
+    for line in receive_packet(reader)? {
+        let mut split = line.trim_end().splitn(2, ' ');
+        match (split.next(), split.next()) {
+            (Some("shallow"), Some(sha)) => shallow.push(sha.to_owned()),
+            (Some("unshallow"), Some(sha)) => shallow.retain(|s| s != sha),
+            _ => {
+                let err = format!("unexpected shallow update line: {}", line);
+                return Err(Error::Protocol(err));
+            }
+        }
+    }
+    Ok(shallow)
+}
+
+///
+/// Builds the commands of a receive-pack request, the capabilities go
+/// after the first command. The pack is sent after the flush.
+///
+pub(crate) fn create_receive_pack_request(capabilities: &[&str], updates: &[RefUpdate]) -> String {
+    let mut lines: Vec<String> = Vec::with_capacity(updates.len() + 1);
+    for (i, update) in updates.iter().enumerate() {
+        let command = format!("{} {} {}", update.old_id, update.new_id, update.name);
+        if i == 0 {
+            let caps = capabilities.join(" ");
+            lines.push(packet_line(&[&command[..], "\0", &caps[..], "\n"].concat()));
+        } else {
+            lines.push(packet_line(&[&command[..], "\n"].concat()));
+        }
+    }
     lines.push(flush_packet());
-    lines.push(packet_line("done\n"));
     lines.concat()
 }
 
-fn packet_line(msg: &str) -> String {
+///
+/// Reads the report-status sent by receive-pack: the result of unpacking
+/// the pack followed by an `ok` or `ng` line for each ref.
+///
+pub(crate) fn receive_report_status<R: Read>(reader: &mut R) -> Result<Vec<PushStatus>> {
+    let lines = receive_packet(reader)?;
+    let mut iter = lines.iter().map(|l| l.trim_end());
+    match iter.next() {
+        Some("unpack ok") => (),
+        Some(line) => {
+            let err = format!(
+                "remote failed to unpack: {}",
+                line.trim_start_matches("unpack ")
+            );
+            return Err(Error::Remote(err));
+        }
+        None => return Err(Error::Protocol("report-status not received".to_owned())),
+    }
+    let mut statuses = Vec::new();
+    for line in iter {
+        let split = line.splitn(3, ' ').collect::<Vec<_>>();
+        let status = match split[..] {
+            ["ok", name] => PushStatus {
+                name: name.to_owned(),
+                error: None,
+            },
+            ["ng", name, reason] => PushStatus {
+                name: name.to_owned(),
+                error: Some(reason.to_owned()),
+            },
+            _ => {
+                let err = format!("unexpected report-status line: {}", line);
+                return Err(Error::Protocol(err));
+            }
+        };
+        statuses.push(status);
+    }
+    Ok(statuses)
+}
+
+pub(crate) fn packet_line(msg: &str) -> String {
     format!("{:04x}{}", 4 + msg.len(), msg)
 }
 
@@ -113,15 +374,314 @@ fn flush_packet() -> String {
     format!("{:04x}", 0)
 }
 
+///
+/// Receives the packfile answering the request, after the shallow update
+/// when `shallow_update` is set and the acknowledgments.
+///
 pub(crate) fn receive_packet_file_with_sideband<R: Read>(
     reader: &mut R,
-) -> IOResult<PackFileParser> {
-    let mut parser = PackFileParser::new();
+    request: &FetchRequest,
+    shallow_update: bool,
+) -> Result<PackFileParser> {
+    let mut parser = request.parser();
+    if shallow_update {
+        parser.set_shallow(receive_shallow_update(reader)?);
+    }
+    receive_acknowledgments(reader)?;
+    receive_sideband_packfile(reader, &mut parser)?;
+    Ok(parser)
+}
+
+///
+/// Feeds the sideband packet lines carrying the packfile to the parser,
+/// up to the final flush.
+///
+pub(crate) fn receive_sideband_packfile<R: Read>(
+    reader: &mut R,
+    parser: &mut PackFileParser,
+) -> Result<()> {
     while let Some(line) = read_packet_line(reader)? {
-        if &line[..] != b"NAK\n" {
-            parser.add_line(&line)?;
+        parser.check_cancelled()?;
+        parser.add_line(&line)?;
+    }
+    parser.process_pending_lines()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::client::haves::{Haves, MAX_IN_VAIN};
+    use crate::transport::client::AGENT;
+
+    #[test]
+    fn reading_packet_lines() {
+        let mut reader: &[u8] = b"000ahello\n0000";
+        assert_eq!(read_packet_line(&mut reader).unwrap().unwrap(), b"hello\n");
+        assert!(read_packet_line(&mut reader).unwrap().is_none());
+        for garbage in [&b"zzzz"[..], b"0001", b"0003", b"\xff\xff\xff\xff"] {
+            let mut reader = garbage;
+            let result = read_packet_line(&mut reader);
+            assert!(matches!(result, Err(Error::Protocol(_))));
         }
     }
-    parser.process_pending_lines()?;
-    Ok(parser)
+
+    #[test]
+    fn test_negotiation_request_with_depth() {
+        let refs = vec![Ref {
+            id: "33676d1c63d868803ed110b13be4e616bc8a29b7".to_string(),
+            name: "refs/heads/master".to_string(),
+        }];
+        let shallow = vec!["8f714d94b892dfd51c1ca4694e0cafcf266e57e8".to_string()];
+        let request = FetchRequest {
+            wants: &refs,
+            haves: &Haves::none(),
+            shallow: &shallow,
+            include_tag: false,
+            depth: Some(1),
+            git_dir: None,
+            progress: None,
+            cancel: None,
+        };
+        let body = create_packfile_negotiation_request(&["shallow"], &request, &[], true);
+        assert_eq!(
+            body,
+            "003awant 33676d1c63d868803ed110b13be4e616bc8a29b7 shallow\n\
+             0035shallow 8f714d94b892dfd51c1ca4694e0cafcf266e57e8\n\
+             000ddeepen 1\n\
+             0000\
+             0009done\n"
+        );
+    }
+
+    fn want_master() -> Refs {
+        vec![Ref {
+            id: "33676d1c63d868803ed110b13be4e616bc8a29b7".to_string(),
+            name: "refs/heads/master".to_string(),
+        }]
+    }
+
+    #[test]
+    fn test_negotiation_round() {
+        let haves = vec!["8f714d94b892dfd51c1ca4694e0cafcf266e57e8".to_string()];
+        let wants = want_master();
+        let request = FetchRequest {
+            wants: &wants,
+            haves: &Haves::none(),
+            shallow: &[],
+            include_tag: false,
+            depth: None,
+            git_dir: None,
+            progress: None,
+            cancel: None,
+        };
+        let body = create_packfile_negotiation_request(&[], &request, &haves, false);
+        assert_eq!(
+            body,
+            "0033want 33676d1c63d868803ed110b13be4e616bc8a29b7 \n\
+             0000\
+             0032have 8f714d94b892dfd51c1ca4694e0cafcf266e57e8\n\
+             0000"
+        );
+    }
+
+    #[test]
+    fn test_receive_acknowledgments() {
+        let mut response: &[u8] = b"0038ACK 8f714d94b892dfd51c1ca4694e0cafcf266e57e8 common\n\
+            0037ACK 3c7cfac73a699ef415bc737ce5529ac66c5692a9 ready\n\
+            0008NAK\n";
+        let acks = receive_acknowledgments(&mut response).unwrap();
+        assert!(acks.ready);
+        assert_eq!(acks.common.len(), 2);
+        assert!(response.is_empty());
+
+        let mut response: &[u8] = b"0031ACK 8f714d94b892dfd51c1ca4694e0cafcf266e57e8\n\x01PACK";
+        let acks = receive_acknowledgments(&mut response).unwrap();
+        assert!(!acks.ready);
+        assert_eq!(response, b"\x01PACK");
+    }
+
+    #[test]
+    fn test_negotiate_common_commits() {
+        let wants = want_master();
+        let haves: Vec<String> = (0..40).map(|i| format!("{:040x}", i)).collect();
+        let request = FetchRequest {
+            wants: &wants,
+            haves: &Haves::new(haves.clone(), |_| Some(Vec::new())),
+            shallow: &[],
+            include_tag: false,
+            depth: None,
+            git_dir: None,
+            progress: None,
+            cancel: None,
+        };
+        let mut rounds = 0;
+        let common = negotiate_common_commits(&[], &request, |body| {
+            rounds += 1;
+            let response = if rounds == 1 {
+                assert_eq!(body.matches("have ").count(), 32);
+                format!("0038ACK {} common\n0008NAK\n", haves[3])
+            } else {
+                // The common commit is sent again along the next batch
+                assert_eq!(body.matches("have ").count(), 9);
+                format!("0037ACK {} ready\n0008NAK\n", haves[35])
+            };
+            Ok(std::io::Cursor::new(response.into_bytes()))
+        })
+        .unwrap();
+        assert_eq!(rounds, 2);
+        assert_eq!(common, vec![haves[3].clone(), haves[35].clone()]);
+    }
+
+    #[test]
+    fn negotiating_with_an_unrelated_history_gives_up() {
+        let wants = want_master();
+        // A line of 10000 commits the server never acknowledges
+        let request = FetchRequest {
+            wants: &wants,
+            haves: &Haves::new(vec![format!("{:040x}", 9999)], |sha| {
+                let n = usize::from_str_radix(sha, 16).ok()?;
+                Some(
+                    n.checked_sub(1)
+                        .map(|p| format!("{:040x}", p))
+                        .into_iter()
+                        .collect(),
+                )
+            }),
+            shallow: &[],
+            include_tag: false,
+            depth: None,
+            git_dir: None,
+            progress: None,
+            cancel: None,
+        };
+        let mut rounds = 0;
+        let common = negotiate_common_commits(&[], &request, |_| {
+            rounds += 1;
+            Ok(&b"0008NAK\n"[..])
+        })
+        .unwrap();
+        assert!(common.is_empty());
+        assert_eq!(rounds, MAX_IN_VAIN / HAVES_PER_ROUND);
+    }
+
+    #[test]
+    fn test_receive_shallow_update() {
+        let mut response: &[u8] = b"0035shallow 33676d1c63d868803ed110b13be4e616bc8a29b7\n\
+            0035shallow 59ca2198a5023edc1d154d2abb2478e0cf293bc4\n\
+            0037unshallow 59ca2198a5023edc1d154d2abb2478e0cf293bc4\n\
+            00000008NAK\n";
+        let shallow = receive_shallow_update(&mut response).unwrap();
+        assert_eq!(shallow, vec!["33676d1c63d868803ed110b13be4e616bc8a29b7"]);
+        assert_eq!(response, b"0008NAK\n");
+    }
+
+    #[test]
+    fn test_parse_empty_repo_advertisement() {
+        let lines = vec![
+            "0000000000000000000000000000000000000000 capabilities^{}\0report-status delete-refs\n"
+                .to_string(),
+        ];
+        let (refs, capabilities) = parse_refs_lines(&lines).unwrap();
+        assert!(refs.is_empty());
+        assert!(capabilities.has("delete-refs"));
+    }
+
+    #[test]
+    fn test_err_lines_are_remote_errors() {
+        let mut response: &[u8] = b"0018ERR not our ref abc\n";
+        let err = receive_packet(&mut response).unwrap_err();
+        assert!(matches!(err, Error::Remote(ref message) if message == "not our ref abc"));
+        assert_eq!(err.to_string(), "remote error: not our ref abc");
+
+        let mut response: &[u8] = b"000e\x02counting\n001a\x03repository is locked\n0000";
+        let mut parser = PackFileParser::new();
+        let err = receive_sideband_packfile(&mut response, &mut parser).unwrap_err();
+        assert!(matches!(err, Error::Remote(ref message) if message == "repository is locked"));
+    }
+
+    #[test]
+    fn test_parse_advertised_capabilities() {
+        let lines = vec![
+            "33676d1c63d868803ed110b13be4e616bc8a29b7 HEAD\0multi_ack_detailed side-band-64k \
+             ofs-delta shallow symref=HEAD:refs/heads/main object-format=sha1 agent=git/2.39.5\n"
+                .to_string(),
+            "33676d1c63d868803ed110b13be4e616bc8a29b7 refs/heads/main\n".to_string(),
+        ];
+        let (refs, capabilities) = parse_refs_lines(&lines).unwrap();
+        assert_eq!(refs.len(), 2);
+        assert_eq!(capabilities.symref("HEAD"), Some("refs/heads/main"));
+        assert_eq!(capabilities.agent.as_deref(), Some("git/2.39.5"));
+        assert_eq!(capabilities.object_format.as_deref(), Some("sha1"));
+        assert!(capabilities.side_band_64k && !capabilities.side_band);
+        assert!(capabilities.ofs_delta && capabilities.shallow && !capabilities.filter);
+        assert!(capabilities.has("agent") && !capabilities.has("include-tag"));
+        assert!(capabilities.check_object_format().is_ok());
+        let sha256 = ServerCapabilities::parse(&["object-format=sha256"]);
+        assert!(sha256.check_object_format().is_err());
+
+        let wants = refs[1..].to_vec();
+        let request = FetchRequest {
+            wants: &wants,
+            haves: &Haves::none(),
+            shallow: &[],
+            include_tag: true,
+            depth: Some(1),
+            git_dir: None,
+            progress: None,
+            cancel: None,
+        };
+        assert_eq!(
+            request.capabilities(&capabilities).unwrap(),
+            vec![
+                "multi_ack_detailed",
+                "side-band-64k",
+                "ofs-delta",
+                "shallow",
+                AGENT
+            ]
+        );
+        let no_shallow = ServerCapabilities::parse(&["multi_ack_detailed", "side-band"]);
+        assert!(request.capabilities(&no_shallow).is_err());
+    }
+
+    #[test]
+    fn test_receive_pack_request() {
+        let updates = vec![
+            RefUpdate {
+                name: "refs/heads/master".to_string(),
+                old_id: "8f714d94b892dfd51c1ca4694e0cafcf266e57e8".to_string(),
+                new_id: "33676d1c63d868803ed110b13be4e616bc8a29b7".to_string(),
+            },
+            RefUpdate {
+                name: "refs/tags/v1".to_string(),
+                old_id: "0000000000000000000000000000000000000000".to_string(),
+                new_id: "33676d1c63d868803ed110b13be4e616bc8a29b7".to_string(),
+            },
+        ];
+        let request = create_receive_pack_request(&["report-status"], &updates);
+        assert_eq!(
+            request,
+            "00768f714d94b892dfd51c1ca4694e0cafcf266e57e8 \
+             33676d1c63d868803ed110b13be4e616bc8a29b7 refs/heads/master\0report-status\n\
+             00630000000000000000000000000000000000000000 \
+             33676d1c63d868803ed110b13be4e616bc8a29b7 refs/tags/v1\n\
+             0000"
+        );
+    }
+
+    #[test]
+    fn test_receive_report_status() {
+        let mut response: &[u8] = b"000eunpack ok\n\
+            0019ok refs/heads/master\n\
+            0025ng refs/tags/v1 non-fast-forward\n\
+            0000";
+        let statuses = receive_report_status(&mut response).unwrap();
+        assert_eq!(statuses.len(), 2);
+        assert!(statuses[0].is_ok());
+        assert_eq!(statuses[1].name, "refs/tags/v1");
+        assert_eq!(statuses[1].error.as_deref(), Some("non-fast-forward"));
+
+        let mut response: &[u8] = b"001dunpack index-pack failed\n0000";
+        assert!(receive_report_status(&mut response).is_err());
+    }
 }
@@ -0,0 +1,77 @@
+use std::io::{BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+use super::packet::{fetch_packfile_stateful, parse_refs_lines, receive_packet};
+use crate::cancel::{on_cancel, AbortOnCancel, CancellationToken};
+use crate::error::{Error, Result};
+use crate::packfile::packfile_parser::PackFileParser;
+use crate::packfile::refs::Refs;
+use crate::transport::client::{FetchRequest, ServerCapabilities};
+
+///
+/// A git service running in a child process, like git-upload-pack spawned
+/// locally or through ssh, spoken to over its stdin and stdout.
+///
+#[derive(Debug)]
+pub(crate) struct ProcessConnection {
+    process: Arc<Mutex<Child>>,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    capabilities: ServerCapabilities,
+    /// kills the process when the token is cancelled
+    _abort: Option<AbortOnCancel>,
+}
+
+impl ProcessConnection {
+    ///
+    /// Spawns the command and reads the refs and capabilities advertised by
+    /// the service. The process is killed once `cancel` is cancelled.
+    ///
+    pub fn spawn(
+        command: &mut Command,
+        cancel: &Option<CancellationToken>,
+    ) -> Result<(Self, Refs, ServerCapabilities)> {
+        let mut process = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = process.stdin.take().unwrap();
+        let stdout = BufReader::new(process.stdout.take().unwrap());
+        let process = Arc::new(Mutex::new(process));
+        let killed = process.clone();
+        let mut connection = ProcessConnection {
+            process,
+            stdin,
+            stdout,
+            capabilities: ServerCapabilities::default(),
+            _abort: on_cancel(cancel, move || {
+                let _ = killed.lock().unwrap().kill();
+            }),
+        };
+        let lines = receive_packet(&mut connection.stdout).map_err(|e| match e {
+            Error::Io(e) => {
+                Error::Transport(format!("could not read from remote repository: {}", e))
+            }
+            e => e,
+        })?;
+        let (refs, capabilities) = parse_refs_lines(&lines)?;
+        connection.capabilities = capabilities.clone();
+        Ok((connection, refs, capabilities))
+    }
+
+    pub fn fetch_packfile(&mut self, request: &FetchRequest) -> Result<PackFileParser> {
+        let capabilities = request.capabilities(&self.capabilities)?;
+        fetch_packfile_stateful(&mut self.stdout, &mut self.stdin, &capabilities, request)
+    }
+}
+
+impl Drop for ProcessConnection {
+    fn drop(&mut self) {
+        // A flush ends the conversation when nothing was fetched
+        let _ = self.stdin.write_all(b"0000");
+        let mut process = self.process.lock().unwrap();
+        let _ = process.kill();
+        let _ = process.wait();
+    }
+}
@@ -1,31 +1,271 @@
-use std::io::Result as IOResult;
+use percent_encoding::percent_decode;
+use std::env;
+use std::process::Command;
 use url::Url;
 
+use super::process::ProcessConnection;
+use crate::cancel::CancellationToken;
+use crate::error::Result;
 use crate::packfile::packfile_parser::PackFileParser;
-use crate::packfile::refs::{Ref, Refs};
-use crate::transport::client::Protocol;
+use crate::packfile::refs::Refs;
+use crate::transport::client::{FetchRequest, Protocol, ServerCapabilities};
+use crate::transport::url_parser::UrlError;
 
+///
+/// Speaks to git-upload-pack through an ssh subprocess. The command used is
+/// taken from `GIT_SSH_COMMAND` or `GIT_SSH`, as git does, and defaults to `ssh`.
+///
 #[derive(Debug)]
 pub struct SshProtocol {
     url: Url,
+    command: SshCommand,
+    connection: Option<ProcessConnection>,
+    cancel: Option<CancellationToken>,
+}
+
+#[derive(Debug)]
+enum SshCommand {
+    /// a command line run through the shell, like `GIT_SSH_COMMAND`
+    Shell(String),
+    /// the path of a program spawned without a shell, like `GIT_SSH`
+    Program(String),
 }
 
 impl SshProtocol {
     pub fn new(url: &Url) -> Self {
-        SshProtocol { url: url.clone() }
+        match env::var("GIT_SSH_COMMAND") {
+            Ok(command) => SshProtocol::with_command(url, &command),
+            Err(_) => {
+                let program = env::var("GIT_SSH").unwrap_or_else(|_| "ssh".to_owned());
+                SshProtocol::with_program(url, &program)
+            }
+        }
+    }
+
+    ///
+    /// Runs ssh through the shell, so the command may carry its own
+    /// arguments.
+    ///
+    pub fn with_command(url: &Url, command: &str) -> Self {
+        SshProtocol::with_ssh(url, SshCommand::Shell(command.to_owned()))
+    }
+
+    ///
+    /// Runs the program at `path` as ssh, its path taken as it is.
+    ///
+    pub fn with_program(url: &Url, path: &str) -> Self {
+        SshProtocol::with_ssh(url, SshCommand::Program(path.to_owned()))
+    }
+
+    fn with_ssh(url: &Url, command: SshCommand) -> Self {
+        SshProtocol {
+            url: url.clone(),
+            command,
+            connection: None,
+            cancel: None,
+        }
+    }
+
+    ///
+    /// The path of the repository on the server, `~` paths are relative to
+    /// the home of the user.
+    ///
+    fn remote_path(&self) -> String {
+        let path = percent_decode(self.url.path().as_bytes()).decode_utf8_lossy();
+        match path.strip_prefix("/~") {
+            Some(rest) => ["~", rest].concat(),
+            None => path.into_owned(),
+        }
+    }
+
+    ///
+    /// The `user@host` ssh connects to. A host or user starting with `-`
+    /// would be taken by ssh as an option, like `-oProxyCommand=...`,
+    /// so they are rejected.
+    ///
+    fn destination(&self) -> Result<String> {
+        let host = self.url.host_str().ok_or(UrlError::NoServer)?;
+        let user = percent_decode(self.url.username().as_bytes()).decode_utf8_lossy();
+        if host.starts_with('-') || user.starts_with('-') {
+            return Err(UrlError::InvalidServer.into());
+        }
+        Ok(match user.as_ref() {
+            "" => host.to_owned(),
+            user => format!("{}@{}", user, host),
+        })
+    }
+
+    ///
+    /// Runs the service on the server and reads the refs it advertises.
+    ///
+    fn connect(&mut self, service: &str) -> Result<(Refs, ServerCapabilities)> {
+        let mut args = Vec::new();
+        if let Some(port) = self.url.port() {
+            args.push("-p".to_owned());
+            args.push(port.to_string());
+        }
+        args.push(self.destination()?);
+        let path = self.remote_path().replace('\'', "'\\''");
+        args.push(format!("{} '{}'", service, path));
+
+        let mut command = match self.command {
+            SshCommand::Shell(ref command) => {
+                let mut shell = Command::new("sh");
+                shell
+                    .arg("-c")
+                    .arg(format!("{} \"$@\"", command))
+                    .arg(command);
+                shell
+            }
+            SshCommand::Program(ref path) => Command::new(path),
+        };
+        let (connection, refs, capabilities) =
+            ProcessConnection::spawn(command.args(&args), &self.cancel)?;
+        self.connection = Some(connection);
+        Ok((refs, capabilities))
     }
 }
 
 impl Protocol for SshProtocol {
-    fn discover_refs(&mut self) -> IOResult<Refs> {
-        unimplemented!()
+    fn discover_refs(&mut self) -> Result<(Refs, ServerCapabilities)> {
+        self.connect("git-upload-pack")
+    }
+
+    fn fetch_packfile(&mut self, request: &FetchRequest) -> Result<PackFileParser> {
+        if self.connection.is_none() {
+            self.connect("git-upload-pack")?;
+        }
+        self.connection.take().unwrap().fetch_packfile(request)
     }
 
-    fn fetch_packfile(&mut self, _reference: &[Ref]) -> IOResult<PackFileParser> {
-        unimplemented!()
+    fn set_cancellation(&mut self, cancel: CancellationToken) {
+        self.cancel = Some(cancel);
     }
 
     fn protocol(&self) -> &'static str {
         "ssh-protocol"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::fixtures::{fixture_remote, skip_without_git, MASTER};
+    use crate::packfile::refs::Ref;
+    use crate::transport::client::haves::Haves;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    ///
+    /// A fake ssh that runs the remote command locally, after checking the
+    /// port and the host it was given.
+    ///
+    fn fake_ssh(name: &str) -> String {
+        let path = std::env::temp_dir().join(name);
+        let script = "#!/bin/sh\n\
+            [ \"$1\" = -p ] && [ \"$2\" = 2222 ] && [ \"$3\" = git@localhost ] || exit 1\n\
+            exec sh -c \"$4\"\n";
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn ssh_protocol(name: &str) -> SshProtocol {
+        let remote = fixture_remote(name);
+        let url = Url::parse(&format!("ssh://git@localhost:2222{}", remote.display())).unwrap();
+        SshProtocol::with_command(&url, &fake_ssh("rs-git-lib-fake-ssh"))
+    }
+
+    #[test]
+    fn discovering_refs_through_ssh() {
+        if skip_without_git() {
+            return;
+        }
+        let mut protocol = ssh_protocol("ssh-refs");
+        let (refs, _) = protocol.discover_refs().unwrap();
+        assert_eq!(refs[0].name, "HEAD");
+        assert!(refs
+            .iter()
+            .any(|r| r.name == "refs/heads/master" && r.id == MASTER));
+    }
+
+    #[test]
+    fn fetching_a_packfile_through_ssh() {
+        if skip_without_git() {
+            return;
+        }
+        let mut protocol = ssh_protocol("ssh-fetch");
+        protocol.discover_refs().unwrap();
+        let wants = vec![Ref {
+            id: MASTER.to_owned(),
+            name: "refs/heads/master".to_owned(),
+        }];
+        let request = FetchRequest {
+            wants: &wants,
+            haves: &Haves::none(),
+            shallow: &[],
+            include_tag: false,
+            depth: None,
+            git_dir: None,
+            progress: Some(std::sync::Arc::new(|_| ())),
+            cancel: None,
+        };
+        let mut parser = protocol.fetch_packfile(&request).unwrap();
+        let packfile = parser.parse(None).unwrap();
+        assert!(packfile.find_by_sha(MASTER).unwrap().is_some());
+    }
+
+    #[test]
+    fn programs_are_run_without_a_shell() {
+        if skip_without_git() {
+            return;
+        }
+        let remote = fixture_remote("ssh-program");
+        let url = Url::parse(&format!("ssh://git@localhost:2222{}", remote.display())).unwrap();
+        let program = fake_ssh("rs-git-lib-fake ssh");
+        let mut protocol = SshProtocol::with_program(&url, &program);
+        let (refs, _) = protocol.discover_refs().unwrap();
+        assert!(refs
+            .iter()
+            .any(|r| r.name == "refs/heads/master" && r.id == MASTER));
+    }
+
+    #[test]
+    fn destinations_taken_as_ssh_options_are_rejected() {
+        for url in [
+            "ssh://-oProxyCommand=touch%20pwned/x.git",
+            "ssh://-oProxyCommand=x@localhost/x.git",
+            "ssh://%2DoProxyCommand=x@localhost/x.git",
+        ] {
+            let url = Url::parse(url).unwrap();
+            let mut protocol = SshProtocol::with_command(&url, "false");
+            let result = protocol.discover_refs();
+            assert!(
+                matches!(result, Err(Error::Url(UrlError::InvalidServer))),
+                "{}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn usernames_are_percent_decoded() {
+        let url = Url::parse("ssh://git%3Dme@localhost/repo.git").unwrap();
+        assert_eq!(
+            SshProtocol::with_command(&url, "ssh")
+                .destination()
+                .unwrap(),
+            "git=me@localhost"
+        );
+    }
+
+    #[test]
+    fn remote_paths_relative_to_home() {
+        let url = Url::parse("ssh://git@localhost/~/repo%20name.git").unwrap();
+        assert_eq!(
+            SshProtocol::with_command(&url, "ssh").remote_path(),
+            "~/repo name.git"
+        );
+    }
+}
@@ -0,0 +1,418 @@
+use super::packet::{packet_line, receive_sideband_packfile, Acknowledgments};
+use super::{remote_error, FetchRequest, ServerCapabilities, AGENT};
+use crate::error::{Error, Result};
+use crate::packfile::packfile_parser::PackFileParser;
+use crate::packfile::refs::{Ref, Refs};
+use std::io::Read;
+
+///
+/// The header asking an HTTP server for protocol version 2,
+/// see https://git-scm.com/docs/protocol-v2
+///
+pub(crate) const GIT_PROTOCOL_HEADER: &str = "Git-Protocol";
+pub(crate) const GIT_PROTOCOL_V2: &str = "version=2";
+pub(crate) const VERSION_2_LINE: &[u8; 10] = b"version 2\n";
+
+const DELIM_PACKET: &str = "0001";
+const FLUSH_PACKET: &str = "0000";
+
+///
+/// A packet of protocol v2, where special packets end the sections of
+/// requests and responses.
+///
+#[derive(Debug, PartialEq)]
+pub(crate) enum Packet {
+    Flush,
+    Delim,
+    Data(String),
+}
+
+pub(crate) fn read_packet<R: Read>(reader: &mut R) -> Result<Packet> {
+    let mut header = [0; 4];
+    reader.read_exact(&mut header)?;
+    let length_str = std::str::from_utf8(&header[..]).unwrap_or("");
+    match u64::from_str_radix(length_str, 16) {
+        Ok(0) => Ok(Packet::Flush),
+        Ok(1) => Ok(Packet::Delim),
+        Ok(length) if length > 4 => {
+            let mut pkt = vec![0; (length - 4) as usize];
+            reader.read_exact(&mut pkt)?;
+            let line = String::from_utf8_lossy(&pkt).into_owned();
+            match line.strip_prefix("ERR ") {
+                Some(message) => Err(remote_error(message)),
+                None => Ok(Packet::Data(line)),
+            }
+        }
+        _ => {
+            let err = format!("invalid packet length: {}", length_str);
+            Err(Error::Protocol(err))
+        }
+    }
+}
+
+///
+/// Returns the value of an advertised capability, empty when it has none.
+///
+pub(crate) fn capability<'a>(capabilities: &'a [String], name: &str) -> Option<&'a str> {
+    capabilities.iter().find_map(|c| {
+        let mut split = c.trim_end().splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(key), value) if key == name => Some(value.unwrap_or("")),
+            _ => None,
+        }
+    })
+}
+
+///
+/// Reads the capabilities of a version 2 advertisement in the terms of
+/// version 0, where the features of `fetch`, like `shallow`, are capabilities.
+///
+pub(crate) fn parse_capabilities(lines: &[String]) -> ServerCapabilities {
+    let mut capabilities: Vec<&str> = lines.iter().map(|l| l.trim_end()).collect();
+    if let Some(fetch) = capability(lines, "fetch") {
+        capabilities.extend(fetch.split(' '));
+    }
+    // The packfile always comes multiplexed, and may hold ofs-deltas
+    capabilities.extend(&["side-band-64k", "ofs-delta"]);
+    ServerCapabilities::parse(&capabilities)
+}
+
+///
+/// Builds a command request, the agent is only sent to servers advertising
+/// theirs.
+///
+fn create_command_request(
+    command: &str,
+    server: &ServerCapabilities,
+    arguments: &[String],
+) -> String {
+    let mut lines = vec![packet_line(&format!("command={}\n", command))];
+    if server.agent.is_some() {
+        lines.push(packet_line(&format!("{}\n", AGENT)));
+    }
+    lines.push(DELIM_PACKET.to_owned());
+    lines.extend(arguments.iter().map(|a| packet_line(&format!("{}\n", a))));
+    lines.push(FLUSH_PACKET.to_owned());
+    lines.concat()
+}
+
+///
+/// Builds an `ls-refs` request, only the refs starting with one of the
+/// prefixes are listed, or all of them when there are none.
+///
+pub(crate) fn create_ls_refs_request(server: &ServerCapabilities, prefixes: &[&str]) -> String {
+    let mut arguments = vec!["symrefs".to_owned(), "peel".to_owned()];
+    arguments.extend(prefixes.iter().map(|p| format!("ref-prefix {}", p)));
+    create_command_request("ls-refs", server, &arguments)
+}
+
+///
+/// Reads the refs listed by `ls-refs`, with annotated tags followed by their
+/// peeled ref like in the version 0 advertisement. The targets of symbolic
+/// refs are returned along.
+///
+pub(crate) fn receive_ls_refs<R: Read>(reader: &mut R) -> Result<(Refs, Vec<(String, String)>)> {
+    let mut refs = Vec::new();
+    let mut symrefs = Vec::new();
+    while let Packet::Data(line) = read_packet(reader)? {
+        let mut split = line.trim_end().split(' ');
+        let (id, name) = match (split.next(), split.next()) {
+            (Some(id), Some(name)) => (id, name),
+            _ => {
+                let err = format!("unexpected ls-refs line: {}", line);
+                return Err(Error::Protocol(err));
+            }
+        };
+        refs.push(Ref {
+            id: id.to_owned(),
+            name: name.to_owned(),
+        });
+        for attribute in split {
+            if let Some(target) = attribute.strip_prefix("symref-target:") {
+                symrefs.push((name.to_owned(), target.to_owned()));
+            } else if let Some(peeled) = attribute.strip_prefix("peeled:") {
+                refs.push(Ref {
+                    id: peeled.to_owned(),
+                    name: format!("{}^{{}}", name),
+                });
+            }
+        }
+    }
+    Ok((refs, symrefs))
+}
+
+///
+/// Builds a `fetch` request sending the haves, and `done` when `done` is
+/// true so the server answers with the packfile.
+///
+pub(crate) fn create_fetch_request(
+    request: &FetchRequest,
+    server: &ServerCapabilities,
+    haves: &[String],
+    done: bool,
+) -> String {
+    let mut arguments = vec!["ofs-delta".to_owned()];
+    if request.include_tag {
+        arguments.push("include-tag".to_owned());
+    }
+    for r in request.wants.iter() {
+        let want = format!("want {}", r.id);
+        if !arguments.contains(&want) {
+            arguments.push(want);
+        }
+    }
+    arguments.extend(request.shallow.iter().map(|s| format!("shallow {}", s)));
+    if let Some(depth) = request.depth {
+        arguments.push(format!("deepen {}", depth));
+    }
+    arguments.extend(haves.iter().map(|h| format!("have {}", h)));
+    if done {
+        arguments.push("done".to_owned());
+    }
+    create_command_request("fetch", server, &arguments)
+}
+
+///
+/// Reads the lines of a section up to the delimiter that starts the next
+/// one. Returns false if the response ended with a flush instead.
+///
+fn receive_section<R: Read>(reader: &mut R, lines: &mut Vec<String>) -> Result<bool> {
+    loop {
+        match read_packet(reader)? {
+            Packet::Data(line) => lines.push(line.trim_end().to_owned()),
+            Packet::Delim => return Ok(true),
+            Packet::Flush => return Ok(false),
+        }
+    }
+}
+
+///
+/// Reads the sections of a `fetch` response. Returns the acknowledgments of
+/// the round, and the packfile once the server sends it.
+///
+pub(crate) fn receive_fetch_response<R: Read>(
+    reader: &mut R,
+    request: &FetchRequest,
+) -> Result<(Acknowledgments, Option<PackFileParser>)> {
+    let mut acks = Acknowledgments::default();
+    let mut shallow: Vec<String> = Vec::new();
+    loop {
+        let header = match read_packet(reader)? {
+            Packet::Data(header) => header,
+            _ => return Ok((acks, None)),
+        };
+        let mut lines = Vec::new();
+        match header.trim_end() {
+            "packfile" => {
+                let mut parser = request.parser();
+                if request.depth.is_some() {
+                    parser.set_shallow(shallow);
+                }
+                receive_sideband_packfile(reader, &mut parser)?;
+                return Ok((acks, Some(parser)));
+            }
+            "acknowledgments" => {
+                let more = receive_section(reader, &mut lines)?;
+                for line in lines {
+                    match line.split(' ').collect::<Vec<_>>()[..] {
+                        ["ACK", sha] => acks.common.push(sha.to_owned()),
+                        ["ready"] => acks.ready = true,
+                        _ => (),
+                    }
+                }
+                if !more {
+                    return Ok((acks, None));
+                }
+            }
+            "shallow-info" => {
+                receive_section(reader, &mut lines)?;
+                for line in lines {
+                    match line.split(' ').collect::<Vec<_>>()[..] {
+                        ["shallow", sha] => shallow.push(sha.to_owned()),
+                        ["unshallow", sha] => shallow.retain(|s| s != sha),
+                        _ => (),
+                    }
+                }
+            }
+            "wanted-refs" | "packfile-uris" => {
+                receive_section(reader, &mut lines)?;
+            }
+            section => {
+                let err = format!("unexpected fetch response section: {}", section);
+                return Err(Error::Protocol(err));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packfile::build_pack;
+    use crate::store::object::{GitObject, GitObjectType};
+    use crate::transport::client::haves::Haves;
+
+    fn want_master() -> Refs {
+        vec![Ref {
+            id: "33676d1c63d868803ed110b13be4e616bc8a29b7".to_string(),
+            name: "refs/heads/master".to_string(),
+        }]
+    }
+
+    #[test]
+    fn test_capability() {
+        let capabilities = vec![
+            "agent=git/2.39.5\n".to_string(),
+            "ls-refs=unborn\n".to_string(),
+            "fetch=shallow wait-for-done\n".to_string(),
+            "server-option\n".to_string(),
+        ];
+        assert_eq!(
+            capability(&capabilities, "fetch"),
+            Some("shallow wait-for-done")
+        );
+        assert_eq!(capability(&capabilities, "server-option"), Some(""));
+        assert_eq!(capability(&capabilities, "object-info"), None);
+
+        let parsed = parse_capabilities(&capabilities);
+        assert_eq!(parsed.agent.as_deref(), Some("git/2.39.5"));
+        assert!(parsed.shallow && !parsed.filter && parsed.side_band_64k);
+    }
+
+    #[test]
+    fn test_ls_refs_request() {
+        let server = ServerCapabilities::default();
+        let request = create_ls_refs_request(&server, &["HEAD", "refs/heads/"]);
+        assert_eq!(
+            request,
+            "0014command=ls-refs\n\
+             0001\
+             000csymrefs\n\
+             0009peel\n\
+             0014ref-prefix HEAD\n\
+             001bref-prefix refs/heads/\n\
+             0000"
+        );
+    }
+
+    #[test]
+    fn test_receive_ls_refs() {
+        let mut response: &[u8] = b"0052718e7fc194a0fef1b1067b12689e5d343f533497 HEAD \
+            symref-target:refs/heads/master\n\
+            003f718e7fc194a0fef1b1067b12689e5d343f533497 refs/heads/master\n\
+            006a7a4219fa5df9550fa54636f2783cd7c3cb63b1f3 refs/tags/v1 \
+            peeled:718e7fc194a0fef1b1067b12689e5d343f533497\n\
+            0000";
+        let (refs, symrefs) = receive_ls_refs(&mut response).unwrap();
+        let names: Vec<&str> = refs.iter().map(|r| &r.name[..]).collect();
+        assert_eq!(
+            names,
+            vec![
+                "HEAD",
+                "refs/heads/master",
+                "refs/tags/v1",
+                "refs/tags/v1^{}"
+            ]
+        );
+        assert_eq!(refs[3].id, "718e7fc194a0fef1b1067b12689e5d343f533497");
+        assert_eq!(
+            symrefs,
+            vec![("HEAD".to_string(), "refs/heads/master".to_string())]
+        );
+        assert!(response.is_empty());
+    }
+
+    #[test]
+    fn test_fetch_request() {
+        let wants = want_master();
+        let haves = vec!["8f714d94b892dfd51c1ca4694e0cafcf266e57e8".to_string()];
+        let shallow = vec!["9cd2a2a0f3b1dd6d3a2c4dbe3d5cd4d5e1ae0d24".to_string()];
+        let request = FetchRequest {
+            wants: &wants,
+            haves: &Haves::none(),
+            shallow: &shallow,
+            include_tag: true,
+            depth: Some(1),
+            git_dir: None,
+            progress: None,
+            cancel: None,
+        };
+        let server = ServerCapabilities::parse(&["agent=git/2.39.5"]);
+        let expected = [
+            "0012command=fetch\n",
+            &packet_line(&format!("{}\n", AGENT)),
+            "0001\
+             000eofs-delta\n\
+             0010include-tag\n\
+             0032want 33676d1c63d868803ed110b13be4e616bc8a29b7\n\
+             0035shallow 9cd2a2a0f3b1dd6d3a2c4dbe3d5cd4d5e1ae0d24\n\
+             000ddeepen 1\n\
+             0032have 8f714d94b892dfd51c1ca4694e0cafcf266e57e8\n\
+             0009done\n\
+             0000",
+        ]
+        .concat();
+        assert_eq!(
+            create_fetch_request(&request, &server, &haves, true),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_receive_acknowledgments_round() {
+        let wants = want_master();
+        let request = FetchRequest {
+            wants: &wants,
+            haves: &Haves::none(),
+            shallow: &[],
+            include_tag: false,
+            depth: None,
+            git_dir: None,
+            progress: None,
+            cancel: None,
+        };
+        let mut response: &[u8] = b"0014acknowledgments\n\
+            0031ACK 8f714d94b892dfd51c1ca4694e0cafcf266e57e8\n\
+            0000";
+        let (acks, parser) = receive_fetch_response(&mut response, &request).unwrap();
+        assert!(parser.is_none());
+        assert!(!acks.ready);
+        assert_eq!(
+            acks.common,
+            vec!["8f714d94b892dfd51c1ca4694e0cafcf266e57e8"]
+        );
+    }
+
+    #[test]
+    fn test_receive_fetch_response_sections() {
+        let wants = want_master();
+        let request = FetchRequest {
+            wants: &wants,
+            haves: &Haves::none(),
+            shallow: &[],
+            include_tag: false,
+            depth: Some(1),
+            git_dir: None,
+            progress: None,
+            cancel: None,
+        };
+        let blob = GitObject::new(GitObjectType::Blob, b"hello\n".to_vec());
+        let pack = build_pack(&[blob]).unwrap();
+        let mut response = b"0011shallow-info\n\
+            0035shallow 33676d1c63d868803ed110b13be4e616bc8a29b7\n\
+            0001\
+            000dpackfile\n"
+            .to_vec();
+        response.extend(format!("{:04x}\x01", pack.len() + 5).as_bytes());
+        response.extend(pack);
+        response.extend(b"0000");
+        let mut response = &response[..];
+        let (_, parser) = receive_fetch_response(&mut response, &request).unwrap();
+        let parser = parser.unwrap();
+        assert_eq!(
+            parser.shallow(),
+            &["33676d1c63d868803ed110b13be4e616bc8a29b7".to_string()]
+        );
+        assert!(response.is_empty());
+    }
+}
@@ -1,46 +1,123 @@
 pub mod client;
-mod url_parser;
+pub(crate) mod url_parser;
 
+use crate::cancel::{check, CancellationToken};
+use crate::credentials::CredentialCallback;
+use crate::error::{Error, Result};
+use crate::options::HttpOptions;
 use crate::packfile::packfile_parser::PackFileParser;
-use crate::packfile::refs::{Ref, Refs};
+use crate::packfile::refs::Refs;
 use client::file_client::FileProtocol;
 use client::git_client::GitProtocol;
 use client::http_client::HttpProtocol;
 use client::local_client::LocalProtocol;
 use client::ssh_client::SshProtocol;
-use client::Protocol;
-use std::io::Result as IOResult;
-use url_parser::UrlType::{FILE, GIT, HTTP, LOCAL, SSH};
+use client::{FetchRequest, Protocol, PushStatus, RefUpdate, ServerCapabilities};
+use std::path::Path;
+use url_parser::UrlType;
 
 pub struct Transport {
     client: Box<dyn Protocol>,
     output_dir: String,
+    cancel: Option<CancellationToken>,
 }
 
 impl Transport {
-    pub fn from_url(repo_url: &str, dir: Option<String>) -> IOResult<Self> {
+    pub fn from_url(repo_url: &str, dir: Option<String>, http: &HttpOptions) -> Result<Self> {
         let res = url_parser::parse(repo_url, dir)?;
 
         let (client, output_dir) = match res {
-            LOCAL(path, dir) => (Box::new(LocalProtocol::new(path)) as Box<dyn Protocol>, dir),
-            FILE(url, dir) => (Box::new(FileProtocol::new(url)) as Box<dyn Protocol>, dir),
-            GIT(url, dir) => (Box::new(GitProtocol::new(&url)) as Box<dyn Protocol>, dir),
-            HTTP(url, dir) => (Box::new(HttpProtocol::new(&url)) as Box<dyn Protocol>, dir),
-            SSH(url, dir) => (Box::new(SshProtocol::new(&url)) as Box<dyn Protocol>, dir),
+            UrlType::Local(path, dir) => {
+                (Box::new(LocalProtocol::new(path)) as Box<dyn Protocol>, dir)
+            }
+            UrlType::File(url, dir) => (Box::new(FileProtocol::new(url)) as Box<dyn Protocol>, dir),
+            UrlType::Git(url, dir) => (Box::new(GitProtocol::new(&url)) as Box<dyn Protocol>, dir),
+            UrlType::Http(url, dir) => (
+                Box::new(HttpProtocol::new(&url, http)?) as Box<dyn Protocol>,
+                dir,
+            ),
+            UrlType::Ssh(url, dir) => (Box::new(SshProtocol::new(&url)) as Box<dyn Protocol>, dir),
         };
 
-        Ok(Transport { client, output_dir })
+        Ok(Transport {
+            client,
+            output_dir,
+            cancel: None,
+        })
     }
 
     pub fn dir(&self) -> String {
         self.output_dir.to_string()
     }
 
-    pub fn discover_refs(&mut self) -> IOResult<Refs> {
-        self.client.discover_refs()
+    pub fn discover_refs(&mut self) -> Result<(Refs, ServerCapabilities)> {
+        check(&self.cancel)?;
+        let discovered = self.client.discover_refs();
+        let (refs, capabilities) = self.interrupted(discovered)?;
+        capabilities.check_object_format()?;
+        Ok((refs, capabilities))
     }
 
-    pub fn fetch_packfile(&mut self, wants: &[Ref]) -> IOResult<PackFileParser> {
-        self.client.fetch_packfile(wants)
+    pub fn discover_refs_with_prefixes(
+        &mut self,
+        prefixes: &[&str],
+    ) -> Result<(Refs, ServerCapabilities)> {
+        check(&self.cancel)?;
+        let discovered = self.client.discover_refs_with_prefixes(prefixes);
+        let (refs, capabilities) = self.interrupted(discovered)?;
+        capabilities.check_object_format()?;
+        Ok((refs, capabilities))
+    }
+
+    pub fn fetch_packfile(&mut self, request: &FetchRequest) -> Result<PackFileParser> {
+        check(&self.cancel)?;
+        let fetched = self.client.fetch_packfile(request);
+        self.interrupted(fetched)
+    }
+
+    pub fn copies_objects(&self) -> bool {
+        self.client.copies_objects()
+    }
+
+    pub fn copy_objects(&mut self, git_dir: &str) -> Result<()> {
+        self.client.copy_objects(git_dir)
+    }
+
+    pub fn discover_push_refs(&mut self) -> Result<(Refs, ServerCapabilities)> {
+        let (refs, capabilities) = self.client.discover_push_refs()?;
+        capabilities.check_object_format()?;
+        Ok((refs, capabilities))
+    }
+
+    pub fn set_credentials(&mut self, credentials: CredentialCallback) {
+        self.client.set_credentials(credentials)
+    }
+
+    ///
+    /// Sets the token aborting the discovery of the refs and the fetches
+    /// when cancelled.
+    ///
+    pub fn set_cancellation(&mut self, cancel: CancellationToken) {
+        self.client.set_cancellation(cancel.clone());
+        self.cancel = Some(cancel);
+    }
+
+    ///
+    /// Reports the errors of a connection interrupted by the cancellation
+    /// as such.
+    ///
+    fn interrupted<T>(&self, result: Result<T>) -> Result<T> {
+        match result {
+            Err(_) if check(&self.cancel).is_err() => Err(Error::Cancelled),
+            result => result,
+        }
+    }
+
+    pub fn send_pack(
+        &mut self,
+        updates: &[RefUpdate],
+        pack: Option<&Path>,
+    ) -> Result<Vec<PushStatus>> {
+        self.client.send_pack(updates, pack)
     }
 }
@@ -1,14 +1,14 @@
+use crate::error::Result;
 use std::fmt::Formatter;
-use std::io::{Error, ErrorKind, Result as IOResult};
 use url::{ParseError, Url};
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum UrlType {
-    LOCAL(String, String),
-    FILE(String, String),
-    GIT(Url, String),
-    HTTP(Url, String),
-    SSH(Url, String),
+    Local(String, String),
+    File(String, String),
+    Git(Url, String),
+    Http(Url, String),
+    Ssh(Url, String),
 }
 
 #[derive(Debug, PartialEq)]
@@ -17,6 +17,7 @@ pub enum UrlError {
     BadScheme,
     Empty,
     NoServer,
+    InvalidServer,
     NoPath,
     InvalidPath,
 }
@@ -28,6 +29,7 @@ impl std::error::Error for UrlError {
             UrlError::BadScheme => None,
             UrlError::Empty => None,
             UrlError::NoServer => None,
+            UrlError::InvalidServer => None,
             UrlError::NoPath => None,
             UrlError::InvalidPath => None,
         }
@@ -41,6 +43,7 @@ impl std::fmt::Display for UrlError {
             UrlError::BadScheme => f.write_str("bad scheme")?,
             UrlError::Empty => f.write_str("empty")?,
             UrlError::NoServer => f.write_str("no server")?,
+            UrlError::InvalidServer => f.write_str("invalid server")?,
             UrlError::NoPath => f.write_str("no path")?,
             UrlError::InvalidPath => f.write_str("invalid path")?,
         }
@@ -48,65 +51,65 @@ impl std::fmt::Display for UrlError {
     }
 }
 
-pub(crate) fn parse(url: &str, dir: Option<String>) -> IOResult<UrlType> {
+pub(crate) fn parse(url: &str, dir: Option<String>) -> Result<UrlType> {
     match Url::parse(url) {
         Ok(url) => match url.scheme() {
             "git" => parse_git(&url, dir),
             "http" | "https" => parse_http(&url, dir),
             "ssh" => parse_ssh(&url, dir),
             "file" => parse_file(&url, dir),
-            _ => Err(Error::new(ErrorKind::Other, UrlError::BadScheme)),
+            _ => Err(UrlError::BadScheme.into()),
         },
-        Err(ParseError::EmptyHost) => Err(Error::new(ErrorKind::Other, UrlError::NoServer)),
+        Err(ParseError::EmptyHost) => Err(UrlError::NoServer.into()),
         Err(ParseError::RelativeUrlWithoutBase) => parse_local(url, dir),
-        Err(e) => Err(Error::new(ErrorKind::Other, UrlError::UrlParseError(e))),
+        Err(e) => Err(UrlError::UrlParseError(e).into()),
     }
 }
 
-fn parse_git(url: &Url, dir: Option<String>) -> Result<UrlType, Error> {
+fn parse_git(url: &Url, dir: Option<String>) -> Result<UrlType> {
     if !url.has_host() {
-        Err(Error::new(ErrorKind::Other, UrlError::NoServer))
+        Err(UrlError::NoServer.into())
     } else if url.path().is_empty() {
-        Err(Error::new(ErrorKind::Other, UrlError::NoPath))
+        Err(UrlError::NoPath.into())
     } else {
-        Ok(UrlType::GIT(url.clone(), det_output_dir(url.path(), dir)))
+        Ok(UrlType::Git(url.clone(), det_output_dir(url.path(), dir)))
     }
 }
 
-fn parse_http(url: &Url, dir: Option<String>) -> Result<UrlType, Error> {
+fn parse_http(url: &Url, dir: Option<String>) -> Result<UrlType> {
     if !url.has_host() {
-        Err(Error::new(ErrorKind::Other, UrlError::NoServer))
+        Err(UrlError::NoServer.into())
     } else if url.path().is_empty() || url.path() == "/" {
-        Err(Error::new(ErrorKind::Other, UrlError::NoPath))
+        Err(UrlError::NoPath.into())
     } else {
-        Ok(UrlType::HTTP(url.clone(), det_output_dir(url.path(), dir)))
+        Ok(UrlType::Http(url.clone(), det_output_dir(url.path(), dir)))
     }
 }
 
-fn parse_ssh(url: &Url, dir: Option<String>) -> Result<UrlType, Error> {
+fn parse_ssh(url: &Url, dir: Option<String>) -> Result<UrlType> {
     if !url.has_host() {
-        Err(Error::new(ErrorKind::Other, UrlError::NoServer))
+        Err(UrlError::NoServer.into())
     } else if url.path().is_empty() || url.path() == "/" {
-        Err(Error::new(ErrorKind::Other, UrlError::NoPath))
+        Err(UrlError::NoPath.into())
     } else {
-        Ok(UrlType::SSH(url.clone(), det_output_dir(url.path(), dir)))
+        Ok(UrlType::Ssh(url.clone(), det_output_dir(url.path(), dir)))
     }
 }
 
-fn parse_file(url: &Url, dir: Option<String>) -> Result<UrlType, Error> {
+fn parse_file(url: &Url, dir: Option<String>) -> Result<UrlType> {
     if url.path().is_empty() || url.path() == "/" {
-        Err(Error::new(ErrorKind::Other, UrlError::InvalidPath))
+        Err(UrlError::InvalidPath.into())
     } else {
-        Ok(UrlType::FILE(
+        Ok(UrlType::File(
             url.path().to_string(),
             det_output_dir(url.path(), dir),
         ))
     }
 }
 
-fn parse_local(url: &str, dir: Option<String>) -> Result<UrlType, Error> {
+fn parse_local(url: &str, dir: Option<String>) -> Result<UrlType> {
     if url.is_empty() {
-        return Err(Error::new(ErrorKind::Other, UrlError::Empty));
+        return Err(UrlError::Empty.into());
     }
     if url.contains('@') {
         // alternate SSH
@@ -123,7 +126,7 @@ fn parse_local(url: &str, dir: Option<String>) -> Result<UrlType, Error> {
         }
         return parse(&format!("ssh://{}", url), dir);
     }
-    Ok(UrlType::LOCAL(url.to_string(), det_output_dir(url, dir)))
+    Ok(UrlType::Local(url.to_string(), det_output_dir(url, dir)))
 }
 
 fn det_output_dir(remote_path: &str, dir: Option<String>) -> String {
@@ -176,7 +179,7 @@ mod tests {
         assert!(parse("git://server", None).is_err());
         let res = parse("git://server/path", None);
         assert!(res.is_ok());
-        if let Some(UrlType::GIT(_url, path)) = res.ok() {
+        if let Ok(UrlType::Git(_url, path)) = res {
             assert_eq!(path, "path");
         } else {
             panic!("failed git parse");
@@ -196,7 +199,7 @@ mod tests {
         assert!(parse("http://domain.tld", None).is_err());
         assert!(parse("https://domain.tld", None).is_err());
         let res = parse("https://server/path", None);
-        if let Some(UrlType::HTTP(_url, path)) = res.ok() {
+        if let Ok(UrlType::Http(_url, path)) = res {
             assert_eq!(path, "path");
         } else {
             panic!("failed http parse");
@@ -212,17 +215,17 @@ mod tests {
         assert!(parse("user@host:/", None).is_err());
         assert!(parse("login@server.com:12345/~/repository.git", None).is_ok());
         let res = parse("ssh://login@server.com:12345/~/repository.git", None);
-        if let Some(UrlType::SSH(_url, path)) = res.ok() {
+        if let Ok(UrlType::Ssh(_url, path)) = res {
             assert_eq!(path, "repository");
         } else {
             panic!("failed ssh parse");
         }
 
         let res = parse("git@server.com:user/repository.git", None);
-        if let Some(UrlType::SSH(_url, path)) = res.ok() {
+        if let Ok(UrlType::Ssh(_url, path)) = res {
             assert_eq!(path, "repository");
         } else {
-            panic!("failed ssh parse {:?}");
+            panic!("failed ssh parse");
         }
     }
 
@@ -232,7 +235,7 @@ mod tests {
         assert!(parse("file://path", None).is_err());
         assert!(parse("file://domain", None).is_err());
         let res = parse("file:///user/work/repository.git", None);
-        if let Some(UrlType::FILE(_url, path)) = res.ok() {
+        if let Ok(UrlType::File(_url, path)) = res {
             assert_eq!(path, "repository");
         } else {
             panic!("failed ssh parse");
@@ -1,3 +1,7 @@
+use crate::error::{Error, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
 pub fn sha1_hash_hex(input: &[u8]) -> String {
     use crypto::digest::Digest;
     use crypto::sha1::Sha1;
@@ -20,5 +24,62 @@ pub fn sha1_hash(input: &[u8]) -> Vec<u8> {
 }
 
 pub fn is_sha(id: &str) -> bool {
-    id.len() == 40 && id.chars().all(|c| c.is_digit(16))
+    id.len() == 40 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+///
+/// Creates a new file in `dir` named after the prefix, creating the
+/// directory if needed.
+///
+pub(crate) fn create_temp_file(dir: &Path, prefix: &str) -> std::io::Result<(PathBuf, fs::File)> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    fs::create_dir_all(dir)?;
+    loop {
+        let name = format!(
+            "{}{}_{}",
+            prefix,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        );
+        let path = dir.join(name);
+        match fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(file) => return Ok((path, file)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+///
+/// Returns the git dir of the repository rooted at `path`, following
+/// `gitdir:` files, or `path` itself when it is a bare repository.
+///
+pub(crate) fn find_git_dir(path: &Path) -> Result<Option<PathBuf>> {
+    let dot_git = path.join(".git");
+    if dot_git.is_file() {
+        let contents = fs::read_to_string(&dot_git)?;
+        let git_dir = contents
+            .strip_prefix("gitdir: ")
+            .map(|p| path.join(p.trim()))
+            .filter(|p| is_git_dir(p))
+            .ok_or_else(|| Error::RepositoryNotFound(dot_git.display().to_string()))?;
+        Ok(Some(git_dir))
+    } else if is_git_dir(&dot_git) {
+        Ok(Some(dot_git))
+    } else if is_git_dir(path) {
+        Ok(Some(path.to_path_buf()))
+    } else {
+        Ok(None)
+    }
+}
+
+pub(crate) fn is_git_dir(path: &Path) -> bool {
+    path.join("HEAD").is_file() && path.join("objects").is_dir() && path.join("refs").is_dir()
 }